@@ -22,6 +22,14 @@ fn sample_settings() -> MicroEngineTradingGroupSettings {
             digits: 5,
             max_leverage: None,
             markup_settings: None,
+            margin_price_mode: Default::default(),
+            kind: Default::default(),
+            commission_model: Default::default(),
+            lot_step: None,
+            min_lots: None,
+            max_lots: None,
+            trading_hours: None,
+            default_contract_size: None,
         },
     );
 
@@ -29,6 +37,17 @@ fn sample_settings() -> MicroEngineTradingGroupSettings {
         id: "G1".to_string(),
         hedge_coef: None,
         instruments,
+        negative_balance_protection: false,
+        equity_components: Default::default(),
+        settle_on_close: false,
+        max_leverage: None,
+        account_leverage_policy: Default::default(),
+        missing_conversion_policy: Default::default(),
+        round_account_figures: false,
+        min_margin_level_to_open: None,
+        swaps_reduce_free_margin: false,
+        reserve_swaps_in_margin: false,
+        max_account_notional: None,
     }
 }
 
@@ -38,11 +57,16 @@ fn sample_account() -> MicroEngineAccount {
         trader_id: "TR1".to_string(),
         trading_group: "G1".to_string(),
         balance: 1000.0,
+        credit: 0.0,
         leverage: 100.0,
         margin: 0.0,
         equity: 0.0,
         free_margin: 0.0,
         margin_level: 0.0,
+        realized_pl: 0.0,
+        deposit_currency: None,
+        last_calculated: None,
+        raw_spread: false,
     }
 }
 
@@ -61,6 +85,7 @@ fn sample_bidask() -> MicroEngineBidask {
         ask: 1.1,
         base: "EUR".to_string(),
         quote: "USD".to_string(),
+        date: None,
     }
 }
 
@@ -87,15 +112,21 @@ fn sample_position() -> MicroEnginePosition {
         active_bidask: price.clone(),
         margin_bidask: price.clone(),
         profit_bidask: MicroEngineBidask::create_blank(),
-        profit_price_assets_subscriptions: Vec::new(),
+        profit_price_assets_subscriptions: HashSet::new(),
         swaps_sum: 0.0,
+        realized_pl: 0.0,
+        leverage: None,
+        open_time: None,
+        last_swap_accrual: None,
+        is_pending: false,
+        metadata: None,
     }
 }
 
 fn build_engine() -> Arc<MicroEngine> {
     let rt = Builder::new_current_thread().enable_all().build().unwrap();
 
-    let (engine, _errors) = rt.block_on(MicroEngine::initialize(
+    let (engine, _errors, _) = rt.block_on(MicroEngine::initialize(
         vec![sample_account()],
         Vec::<MicroEnginePosition>::new(),
         vec![sample_settings()],
@@ -114,6 +145,7 @@ fn gen_prices_unique(n: usize) -> Vec<MicroEngineBidask> {
             ask: 1.1 + (i as f64) * 1e-6,
             base: "EUR".into(),
             quote: "USD".into(),
+            date: None,
         })
         .collect()
 }
@@ -138,8 +170,14 @@ fn gen_positions(n: usize) -> Vec<MicroEnginePosition> {
             active_bidask: px.clone(),
             margin_bidask: px.clone(),
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: Vec::new(),
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         })
         .collect()
 }
@@ -148,7 +186,7 @@ fn bench_initialize(c: &mut Criterion) {
     let rt = Builder::new_current_thread().enable_all().build().unwrap();
     c.bench_function("initialize", |b| {
         b.iter(|| {
-            let (engine, errors) = rt.block_on(MicroEngine::initialize(
+            let (engine, errors, _) = rt.block_on(MicroEngine::initialize(
                 vec![sample_account()],
                 Vec::<MicroEnginePosition>::new(),
                 vec![sample_settings()],
@@ -1,8 +1,15 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
+
 use crate::{
+    bidask::MicroEngineBidAskCache,
     positions::position::MicroEnginePosition,
-    settings::{MicroEngineTradingGroupSettings, TradingGroupInstrumentSettings},
+    round_float_to_digits,
+    settings::{
+        EquityComponents, MicroEngineTradingGroupSettings, MissingConversionPolicy,
+        TradingGroupInstrumentSettings,
+    },
 };
 
 #[derive(Debug, Clone)]
@@ -14,6 +21,35 @@ pub struct MicroEngineAccountCalculationUpdate {
     pub margin_level: f64,
     pub total_gross: f64,
     pub balance: f64,
+    /// `balance + total_gross` floored at zero, i.e. equity without credit.
+    pub withdrawable: f64,
+    /// Figures the account had before this recalculation
+    pub previous_equity: f64,
+    pub previous_margin: f64,
+    pub previous_margin_level: f64,
+    /// Part of `margin` coming from offsetting buy/sell volume. The rest is `directional_margin`.
+    pub hedged_margin: f64,
+    pub directional_margin: f64,
+    /// Accrued negative swaps held in `margin` when the group reserves them. Kept out of
+    /// `hedged_margin` and `directional_margin`.
+    pub swaps_reserve: f64,
+    /// Margin used, in percent of equity
+    pub margin_utilization: f64,
+    /// Open positions left out of or mispriced in these figures: no instrument settings, no
+    /// instrument or conversion price. Figures are partial when this is not zero. When a
+    /// collateral can't be converted into the deposit currency the account keeps its last
+    /// figures.
+    pub unpriceable_positions: usize,
+    pub calculated_at: DateTime<Utc>,
+}
+
+impl MicroEngineAccountCalculationUpdate {
+    /// Whether equity, margin or margin level moved by more than `epsilon`.
+    pub fn is_changed(&self, epsilon: f64) -> bool {
+        (self.equity - self.previous_equity).abs() > epsilon
+            || (self.margin - self.previous_margin).abs() > epsilon
+            || (self.margin_level - self.previous_margin_level).abs() > epsilon
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,30 +58,123 @@ pub struct MicroEngineAccount {
     pub trader_id: String,
     pub trading_group: String,
     pub balance: f64,
+    /// Non-withdrawable bonus. Counts toward equity and margin level, but not `withdrawable`.
+    pub credit: f64,
     pub leverage: f64,
     pub margin: f64,
     pub equity: f64,
     pub free_margin: f64,
     pub margin_level: f64,
+    /// Gross P&L accumulated from positions closed through the engine.
+    pub realized_pl: f64,
+    /// Currency `balance`, `equity` and `margin` are kept in. `None` means the positions'
+    /// collateral.
+    pub deposit_currency: Option<String>,
+    /// When the figures above were last recomputed. `None` until the first recalculation.
+    pub last_calculated: Option<DateTime<Utc>>,
+    /// Trades on raw prices: instrument markup and spread limits are not applied.
+    pub raw_spread: bool,
 }
 
 impl MicroEngineAccount {
+    /// `margin / equity * 100`, the inverse of margin level. Zero when equity is not positive.
+    pub fn margin_utilization(&self) -> f64 {
+        match self.equity <= 0.0 {
+            true => 0.0,
+            false => self.margin / self.equity * 100.0,
+        }
+    }
+
+    /// Drops whatever the positions contributed: no margin and equity back to balance.
+    pub(crate) fn clear_position_figures(&mut self) {
+        self.margin = 0.0;
+        self.equity = self.balance + self.credit;
+        self.free_margin = self.equity;
+        self.margin_level = 0.0;
+    }
+
+    pub fn realize_pl(&mut self, gross_pl: f64, settle_on_close: bool) {
+        self.realized_pl += gross_pl;
+
+        if settle_on_close {
+            self.balance += gross_pl;
+        }
+    }
+
     pub fn recalculate_account_data(
         &mut self,
         account_positions: &[&MicroEnginePosition],
         settings: &MicroEngineTradingGroupSettings,
+        bidask_cache: &MicroEngineBidAskCache,
     ) -> MicroEngineAccountCalculationUpdate {
-        let (margin, gross_pl) =
-            self.calculate_margin_and_gross_pl(account_positions, settings.hedge_coef, settings);
+        let (figures, unpriceable_positions) = self.calculate_margin_and_gross_pl(
+            account_positions,
+            settings.hedge_coef,
+            settings,
+            bidask_cache,
+        );
+
+        // Dropping the unconvertible P&L would shrink equity, the last figures stand instead
+        let Some((hedged_margin, directional_margin, swaps_reserve, gross_pl)) = figures else {
+            return self.stale_update(unpriceable_positions);
+        };
+
+        let (previous_equity, previous_margin, previous_margin_level) =
+            (self.equity, self.margin, self.margin_level);
+
+        let margin = hedged_margin + directional_margin + swaps_reserve;
+        let mut hedged_margin = hedged_margin;
+        let mut swaps_reserve = swaps_reserve;
+        let mut gross_pl = gross_pl;
+
+        let round_digits = settings
+            .round_account_figures
+            .then(|| self.get_deposit_currency_digits(account_positions, settings));
+
+        // Equity is built from the rounded gross, so `balance + credit + total_gross` reconciles
+        // with it at the reported precision
+        if let Some(digits) = round_digits {
+            gross_pl = round_float_to_digits(gross_pl, digits);
+        }
 
         self.margin = margin;
-        self.equity = self.balance + gross_pl;
+        self.equity = self.balance + self.credit + gross_pl;
+        let mut withdrawable = (self.balance + gross_pl).max(0.0);
+
+        if settings.negative_balance_protection && self.equity < 0.0 {
+            self.equity = 0.0;
+        }
+
+        if let Some(digits) = round_digits {
+            self.margin = round_float_to_digits(self.margin, digits);
+            self.equity = round_float_to_digits(self.equity, digits);
+            withdrawable = round_float_to_digits(withdrawable, digits);
+            hedged_margin = round_float_to_digits(hedged_margin, digits);
+            swaps_reserve = round_float_to_digits(swaps_reserve, digits);
+        }
+
         self.free_margin = self.equity - self.margin;
-        self.margin_level = match margin < 0.00001 {
+
+        // Swaps already taken from equity or held in the margin reserve aren't taken again
+        if settings.swaps_reduce_free_margin
+            && !settings.equity_components.swaps
+            && !settings.reserve_swaps_in_margin
+        {
+            self.free_margin += self.negative_swaps(account_positions, bidask_cache);
+        }
+
+        if let Some(digits) = round_digits {
+            self.free_margin = round_float_to_digits(self.free_margin, digits);
+        }
+        // From the reported figures, so margin level agrees with them when they're rounded
+        self.margin_level = match self.margin < 0.00001 {
             true => 0.0,
-            false => self.equity / margin * 100.0,
+            false => self.equity / self.margin * 100.0,
         };
 
+        let calculated_at = Utc::now();
+        self.last_calculated = Some(calculated_at);
+
         MicroEngineAccountCalculationUpdate {
             account_id: self.id.clone(),
             margin: self.margin,
@@ -54,17 +183,60 @@ impl MicroEngineAccount {
             margin_level: self.margin_level,
             total_gross: gross_pl,
             balance: self.balance,
+            withdrawable,
+            hedged_margin,
+            directional_margin: self.margin - hedged_margin - swaps_reserve,
+            swaps_reserve,
+            margin_utilization: self.margin_utilization(),
+            unpriceable_positions,
+            previous_equity,
+            previous_margin,
+            previous_margin_level,
+            calculated_at,
+        }
+    }
+
+    /// The account's last figures, unchanged, reported with `unpriceable_positions`.
+    fn stale_update(&self, unpriceable_positions: usize) -> MicroEngineAccountCalculationUpdate {
+        let total_gross = self.equity - self.balance - self.credit;
+
+        MicroEngineAccountCalculationUpdate {
+            account_id: self.id.clone(),
+            margin: self.margin,
+            equity: self.equity,
+            free_margin: self.free_margin,
+            margin_level: self.margin_level,
+            total_gross,
+            balance: self.balance,
+            withdrawable: (self.balance + total_gross).max(0.0),
+            hedged_margin: 0.0,
+            directional_margin: self.margin,
+            swaps_reserve: 0.0,
+            margin_utilization: self.margin_utilization(),
+            unpriceable_positions,
+            previous_equity: self.equity,
+            previous_margin: self.margin,
+            previous_margin_level: self.margin_level,
+            calculated_at: Utc::now(),
         }
     }
 
+    /// Hedged margin, directional margin, swaps reserve and gross P&L in the deposit currency,
+    /// or `None` when some collateral can't be converted into it, along with the count of
+    /// unpriceable open positions.
     fn calculate_margin_and_gross_pl(
         &self,
         account_positions: &[&MicroEnginePosition],
         hedge_coef: Option<f64>,
         settings: &MicroEngineTradingGroupSettings,
-    ) -> (f64, f64) {
-        let mut total_margin = 0.0;
+        bidask_cache: &MicroEngineBidAskCache,
+    ) -> (Option<(f64, f64, f64, f64)>, usize) {
+        let mut total_hedged_margin = 0.0;
+        let mut total_directional_margin = 0.0;
+        let mut total_swaps_reserve = 0.0;
         let mut total_gross_pl = 0.0;
+        let mut unpriceable_positions = 0;
+        let mut converted = true;
         let mut grouped_positions = HashMap::new();
 
         for position in account_positions.into_iter() {
@@ -75,33 +247,176 @@ impl MicroEngineAccount {
         }
 
         for (asset, positions) in grouped_positions.into_iter() {
-            if let Some(target_settings) = settings.instruments.get(&asset) {
-                let (margin, gross) = calculate_specific_instrument_margin_and_gross_pl(
-                    positions.as_slice(),
-                    self,
-                    hedge_coef,
-                    target_settings,
-                );
-                total_margin += margin;
-                total_gross_pl += gross;
+            let open_positions = positions.iter().filter(|x| !x.is_pending).count();
+
+            let Some(target_settings) = settings.instruments.get(&asset) else {
+                unpriceable_positions += open_positions;
+                continue;
+            };
+
+            // Positions of an instrument share its quote and collateral, the conversion is
+            // resolved once for all of them
+            let conversion_rate = quote_conversion_rate(positions[0], bidask_cache);
+            let priceable = conversion_rate.is_some() && bidask_cache.get_by_id(&asset).is_some();
+            let margin_rate = conversion_rate.or_else(|| {
+                last_known_conversion_rate(positions[0], settings.missing_conversion_policy)
+            });
+            let (margin, swaps, gross) = calculate_specific_instrument_margin_parts(
+                positions.as_slice(),
+                self,
+                hedge_coef,
+                target_settings,
+                &settings.equity_components,
+                settings.reserve_swaps_in_margin,
+                margin_rate,
+            );
+
+            // Without a margin rate the positions still carry their P&L, which is in the
+            // collateral already. Only the margin is left out.
+            let margin_priced = margin.is_some();
+            let (hedged_margin, directional_margin) = margin.unwrap_or((0.0, 0.0));
+
+            let collateral = &positions[0].collateral;
+            let (Some(hedged_margin), Some(directional_margin), Some(swaps), Some(gross)) = (
+                self.convert_to_deposit_currency(hedged_margin, collateral, bidask_cache),
+                self.convert_to_deposit_currency(directional_margin, collateral, bidask_cache),
+                self.convert_to_deposit_currency(swaps, collateral, bidask_cache),
+                self.convert_to_deposit_currency(gross, collateral, bidask_cache),
+            ) else {
+                unpriceable_positions += open_positions;
+                converted = false;
+                continue;
+            };
+
+            if !margin_priced || !priceable {
+                unpriceable_positions += open_positions;
             }
+
+            total_hedged_margin += hedged_margin;
+            total_directional_margin += directional_margin;
+            total_swaps_reserve += swaps;
+            total_gross_pl += gross;
+        }
+
+        let figures = converted.then_some((
+            total_hedged_margin,
+            total_directional_margin,
+            total_swaps_reserve,
+            total_gross_pl,
+        ));
+
+        (figures, unpriceable_positions)
+    }
+
+    /// Sum of the negative `swaps_sum` of open positions, in the deposit currency. Positions
+    /// whose collateral can't be converted are skipped.
+    fn negative_swaps(
+        &self,
+        account_positions: &[&MicroEnginePosition],
+        bidask_cache: &MicroEngineBidAskCache,
+    ) -> f64 {
+        account_positions
+            .iter()
+            .filter(|x| !x.is_pending && x.swaps_sum < 0.0)
+            .filter_map(|x| {
+                self.convert_to_deposit_currency(x.swaps_sum, &x.collateral, bidask_cache)
+            })
+            .sum()
+    }
+
+    /// Digits of the deposit currency, falling back to the positions' collateral and then to 2.
+    fn get_deposit_currency_digits(
+        &self,
+        account_positions: &[&MicroEnginePosition],
+        settings: &MicroEngineTradingGroupSettings,
+    ) -> i32 {
+        let currency = match &self.deposit_currency {
+            Some(deposit_currency) => Some(deposit_currency),
+            None => account_positions.first().map(|x| &x.collateral),
+        };
+
+        currency
+            .and_then(|x| settings.collaterals.get(x))
+            .map(|x| x.digits as i32)
+            .unwrap_or(2)
+    }
+
+    /// Converts an amount in `collateral` into the deposit currency. Returns `None` if the
+    /// conversion price is not available.
+    pub(crate) fn convert_to_deposit_currency(
+        &self,
+        amount: f64,
+        collateral: &str,
+        bidask_cache: &MicroEngineBidAskCache,
+    ) -> Option<f64> {
+        let Some(deposit_currency) = &self.deposit_currency else {
+            return Some(amount);
+        };
+
+        if deposit_currency == collateral {
+            return Some(amount);
         }
 
-        (total_margin, total_gross_pl)
+        let price = bidask_cache.get_price(collateral, deposit_currency)?;
+
+        let rate = match amount >= 0.0 {
+            true => price.bid,
+            false => price.ask,
+        };
+
+        Some(amount * rate)
     }
 }
 
-fn calculate_specific_instrument_margin_and_gross_pl(
+pub(crate) fn calculate_specific_instrument_margin_and_gross_pl(
     positions: &[&MicroEnginePosition],
     account: &MicroEngineAccount,
     hedge_coef: Option<f64>,
     settings: &TradingGroupInstrumentSettings,
-) -> (f64, f64) {
+    equity_components: &EquityComponents,
+    reserve_swaps: bool,
+    bidask_cache: &MicroEngineBidAskCache,
+) -> Option<(f64, f64)> {
+    let Some(position) = positions.first() else {
+        return Some((0.0, 0.0));
+    };
+
+    // The cached conversion, or the last one the positions were priced at
+    let margin_rate = quote_conversion_rate(position, bidask_cache)
+        .or_else(|| last_known_conversion_rate(position, MissingConversionPolicy::UseLastKnown));
+    let (margin, swaps_reserve, gross_pl) = calculate_specific_instrument_margin_parts(
+        positions,
+        account,
+        hedge_coef,
+        settings,
+        equity_components,
+        reserve_swaps,
+        margin_rate,
+    );
+    let (hedged_margin, directional_margin) = margin?;
+
+    Some((hedged_margin + directional_margin + swaps_reserve, gross_pl))
+}
+
+/// Hedged and directional margin, swaps reserve and gross P&L of one instrument's positions, all
+/// in the positions' collateral. The margin is `None` without a `margin_rate` from the quote
+/// currency into the collateral. The swaps reserve holds accrued negative swaps and stays zero
+/// without `reserve_swaps`.
+fn calculate_specific_instrument_margin_parts(
+    positions: &[&MicroEnginePosition],
+    account: &MicroEngineAccount,
+    hedge_coef: Option<f64>,
+    settings: &TradingGroupInstrumentSettings,
+    equity_components: &EquityComponents,
+    reserve_swaps: bool,
+    margin_rate: Option<f64>,
+) -> (Option<(f64, f64)>, f64, f64) {
     if positions.is_empty() {
-        return (0.0, 0.0);
+        return (Some((0.0, 0.0)), 0.0, 0.0);
     }
 
     let mut total_gross_pl = 0.0;
+    let mut swaps_reserve = 0.0;
 
     let leverage = match settings.max_leverage {
         Some(x) => x.min(account.leverage),
@@ -114,10 +429,37 @@ fn calculate_specific_instrument_margin_and_gross_pl(
     let mut buy_volume = 0.0;
     let mut sell_volume = 0.0;
     let mut contract_size_sum = 0.0;
+    let mut volume_positions_count = 0;
+
+    // With any position-level leverage, each position's own contract size and leverage are
+    // folded into its margin price so mixed leverages sum correctly
+    let per_position_margin = positions.iter().any(|x| x.leverage.is_some());
 
     for position in positions {
-        total_gross_pl += position.get_gross_pl();
-        let margin_price = position.margin_bidask.get_open_price(position.is_buy);
+        if position.is_pending {
+            continue;
+        }
+
+        total_gross_pl += equity_components.get_gross_pl(position);
+
+        if reserve_swaps {
+            swaps_reserve += (-position.swaps_sum).max(0.0);
+        }
+
+        // Zero-volume positions carry P&L but no margin and must not skew the averages
+        if position.lots_amount == 0.0 {
+            continue;
+        }
+
+        volume_positions_count += 1;
+        let margin_price = settings
+            .margin_price_mode
+            .get_margin_price(&position.margin_bidask, position.is_buy);
+        let margin_price = match per_position_margin {
+            true => margin_price * position.contract_size / position.leverage.unwrap_or(leverage),
+            false => margin_price,
+        };
+
         match position.is_buy {
             true => {
                 buy_margin_price_sum += margin_price * position.lots_amount;
@@ -132,8 +474,14 @@ fn calculate_specific_instrument_margin_and_gross_pl(
         }
     }
 
-    let positions_len = positions.len() as f64;
-    let contract_size = contract_size_sum / positions_len;
+    if volume_positions_count == 0 {
+        return (Some((0.0, 0.0)), swaps_reserve, total_gross_pl);
+    }
+
+    let (contract_size, leverage) = match per_position_margin {
+        true => (1.0, 1.0),
+        false => (contract_size_sum / volume_positions_count as f64, leverage),
+    };
     let hedged_volume = buy_volume.min(sell_volume);
 
     let hedged_margin = {
@@ -149,28 +497,176 @@ fn calculate_specific_instrument_margin_and_gross_pl(
         }
     };
 
-    let not_hedged_margin_price = match buy_volume > sell_volume {
-        true => buy_margin_price_sum / buy_volume,
-        false => sell_margin_price_sum / sell_volume,
+    let not_hedged_volume = (buy_volume - sell_volume).abs();
+
+    let not_hedge_margin = match not_hedged_volume > 0.0 {
+        true => {
+            let not_hedged_margin_price = match buy_volume > sell_volume {
+                true => buy_margin_price_sum / buy_volume,
+                false => sell_margin_price_sum / sell_volume,
+            };
+
+            not_hedged_volume * contract_size * not_hedged_margin_price / leverage
+        }
+        false => 0.0,
     };
 
-    let not_hedged_volume = (buy_volume - sell_volume).abs();
+    // Swaps are booked in the collateral already, only the margin needs the quote conversion
+    (
+        margin_rate.map(|rate| (hedged_margin * rate, not_hedge_margin * rate)),
+        swaps_reserve,
+        total_gross_pl,
+    )
+}
+
+/// Rate converting margin from the instrument's quote currency into the collateral, or `None`
+/// while no conversion price is available.
+fn quote_conversion_rate(
+    position: &MicroEnginePosition,
+    bidask_cache: &MicroEngineBidAskCache,
+) -> Option<f64> {
+    if position.quote == position.collateral {
+        return Some(1.0);
+    }
 
-    let not_hedge_margin = not_hedged_volume * contract_size * not_hedged_margin_price / leverage;
-    (hedged_margin + not_hedge_margin, total_gross_pl)
+    bidask_cache
+        .get_price(&position.quote, &position.collateral)
+        .map(|x| x.bid)
+}
+
+/// Under `UseLastKnown`, the rate of the position's last profit price, if it has one.
+fn last_known_conversion_rate(
+    position: &MicroEnginePosition,
+    policy: MissingConversionPolicy,
+) -> Option<f64> {
+    let last_known = &position.profit_bidask;
+
+    (policy == MissingConversionPolicy::UseLastKnown
+        && !last_known.id.is_empty()
+        && last_known.bid > 0.0)
+        .then_some(last_known.bid)
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
+    use std::collections::HashSet;
+
     use crate::{
         accounts::account::{
             MicroEngineAccount, calculate_specific_instrument_margin_and_gross_pl,
         },
-        bidask::dto::MicroEngineBidask,
+        bidask::{MicroEngineBidAskCache, MicroEngineInstrument, dto::MicroEngineBidask},
         positions::position::MicroEnginePosition,
-        settings::{TradingGroupInstrumentMarkupSettings, TradingGroupInstrumentSettings},
+        round_float_to_digits,
+        settings::{
+            CollateralSettings, MarginPriceMode, MicroEngineTradingGroupSettings,
+            TradingGroupInstrumentMarkupSettings, TradingGroupInstrumentSettings,
+        },
     };
 
+    fn eurusd_bidask(bid: f64, ask: f64) -> MicroEngineBidask {
+        MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid,
+            ask,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        }
+    }
+
+    fn sample_position(id: &str, is_buy: bool, lots_amount: f64, pl: f64) -> MicroEnginePosition {
+        MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount,
+            contract_size: 100000.0,
+            is_buy,
+            pl,
+            commission: 0.0,
+            open_bidask: eurusd_bidask(1.25540, 1.25542),
+            active_bidask: eurusd_bidask(1.25540, 1.25542),
+            margin_bidask: eurusd_bidask(1.25540, 1.25542),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        }
+    }
+
+    fn empty_bidask_cache() -> MicroEngineBidAskCache {
+        MicroEngineBidAskCache::new(HashSet::new(), vec![], vec![]).0
+    }
+
+    fn sample_account(balance: f64) -> MicroEngineAccount {
+        MicroEngineAccount {
+            id: "ACC1".to_string(),
+            trader_id: "TR1".to_string(),
+            trading_group: "tg1".to_string(),
+            balance,
+            credit: 0.0,
+            leverage: 100.0,
+            margin: 0.0,
+            equity: 0.0,
+            free_margin: 0.0,
+            margin_level: 0.0,
+            realized_pl: 0.0,
+            deposit_currency: None,
+            last_calculated: None,
+            raw_spread: false,
+        }
+    }
+
+    fn sample_settings() -> MicroEngineTradingGroupSettings {
+        MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            hedge_coef: None,
+            instruments: HashMap::from_iter(vec![(
+                "EURUSD".to_string(),
+                TradingGroupInstrumentSettings {
+                    digits: 5,
+                    max_leverage: None,
+                    markup_settings: None,
+                    margin_price_mode: Default::default(),
+                    kind: Default::default(),
+                    commission_model: Default::default(),
+                    lot_step: None,
+                    min_lots: None,
+                    max_lots: None,
+                    trading_hours: None,
+                    default_contract_size: None,
+                },
+            )]),
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        }
+    }
+
     #[tokio::test]
     pub async fn test_account_margin_calculation() {
         let position = vec![MicroEnginePosition {
@@ -192,6 +688,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -199,6 +696,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -206,10 +704,17 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         }];
 
         let account = MicroEngineAccount {
@@ -217,11 +722,16 @@ mod test {
             trader_id: "TR1".to_string(),
             trading_group: "tg1".to_string(),
             balance: 99488.14,
+            credit: 0.0,
             leverage: 100.0,
             margin: 0.0,
             equity: 0.0,
             free_margin: 0.0,
             margin_level: 0.0,
+            realized_pl: 0.0,
+            deposit_currency: None,
+            last_calculated: None,
+            raw_spread: false,
         };
 
         let group = TradingGroupInstrumentSettings {
@@ -232,7 +742,21 @@ mod test {
                 markup_ask: 0.0,
                 min_spread: Some(0.00020),
                 max_spread: None,
+                markup_order: Default::default(),
+                spread_clamp_order: Default::default(),
+                spread_rounding: Default::default(),
+                volume_markup: None,
+                clamp_preserves_mid: false,
+                on_inverted: Default::default(),
             }),
+            margin_price_mode: Default::default(),
+            kind: Default::default(),
+            commission_model: Default::default(),
+            lot_step: None,
+            min_lots: None,
+            max_lots: None,
+            trading_hours: None,
+            default_contract_size: None,
         };
 
         let (margin, gross) = calculate_specific_instrument_margin_and_gross_pl(
@@ -240,8 +764,665 @@ mod test {
             &account,
             None,
             &group,
-        );
+            &Default::default(),
+            false,
+            &empty_bidask_cache(),
+        )
+        .unwrap();
 
         assert_eq!(format!("{:.5}", margin), "62.77000");
     }
+
+    #[tokio::test]
+    pub async fn test_negative_balance_protection() {
+        let position = sample_position("id", true, 1.0, -1500.0);
+        let mut settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "-500.00");
+
+        settings.negative_balance_protection = true;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "0.00");
+        assert_eq!(format!("{:.2}", update.total_gross), "-1500.00");
+        assert_eq!(format!("{:.2}", update.free_margin), "-1255.42");
+        assert_eq!(format!("{:.2}", update.margin_level), "0.00");
+        assert_eq!(format!("{:.2}", account.equity), "0.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_margin_price_mode_mid() {
+        let position = sample_position("id", false, 0.05, 0.0);
+        let account = sample_account(1000.0);
+
+        let mut instrument_settings = TradingGroupInstrumentSettings {
+            digits: 5,
+            max_leverage: None,
+            markup_settings: None,
+            margin_price_mode: MarginPriceMode::OpenSide,
+            kind: Default::default(),
+            commission_model: Default::default(),
+            lot_step: None,
+            min_lots: None,
+            max_lots: None,
+            trading_hours: None,
+            default_contract_size: None,
+        };
+
+        let (open_side_margin, _) = calculate_specific_instrument_margin_and_gross_pl(
+            &[&position],
+            &account,
+            None,
+            &instrument_settings,
+            &Default::default(),
+            false,
+            &empty_bidask_cache(),
+        )
+        .unwrap();
+
+        instrument_settings.margin_price_mode = MarginPriceMode::Mid;
+
+        let (mid_margin, _) = calculate_specific_instrument_margin_and_gross_pl(
+            &[&position],
+            &account,
+            None,
+            &instrument_settings,
+            &Default::default(),
+            false,
+            &empty_bidask_cache(),
+        )
+        .unwrap();
+
+        // 0.05 * 100000 * 1.25540 (bid) / 100
+        assert_eq!(format!("{:.5}", open_side_margin), "62.77000");
+        // 0.05 * 100000 * 1.25541 (mid) / 100
+        assert_eq!(format!("{:.5}", mid_margin), "62.77050");
+    }
+
+    #[tokio::test]
+    pub async fn test_equity_components_exclude_commission() {
+        let mut position = sample_position("id", true, 1.0, 100.0);
+        position.commission = 7.0;
+        position.swaps_sum = -2.0;
+        let mut settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "1091.00");
+        assert_eq!(format!("{:.2}", update.total_gross), "91.00");
+
+        settings.equity_components.commission = false;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "1098.00");
+        assert_eq!(format!("{:.2}", update.total_gross), "98.00");
+        assert_eq!(format!("{:.2}", position.get_gross_pl()), "91.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_signed_commission_convention() {
+        let mut position = sample_position("id", true, 1.0, 100.0);
+        position.commission = -7.0;
+        let mut settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        // Read as a cost, a negative commission turns into a rebate
+        assert_eq!(format!("{:.2}", update.total_gross), "107.00");
+
+        settings.equity_components.commission_is_cost = false;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.total_gross), "93.00");
+        assert_eq!(format!("{:.2}", update.equity), "1093.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_zero_volume_position_does_not_affect_margin() {
+        let normal = sample_position("normal", true, 0.05, 0.0);
+        let empty = sample_position("empty", false, 0.0, 0.0);
+        let settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let expected =
+            account.recalculate_account_data(&[&normal], &settings, &empty_bidask_cache());
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&normal, &empty], &settings, &empty_bidask_cache());
+
+        assert!(update.margin.is_finite());
+        assert_eq!(
+            format!("{:.5}", update.margin),
+            format!("{:.5}", expected.margin)
+        );
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(&[&empty], &settings, &empty_bidask_cache());
+
+        assert_eq!(update.margin, 0.0);
+        assert_eq!(update.margin_level, 0.0);
+    }
+
+    #[tokio::test]
+    pub async fn test_deposit_currency_differs_from_collateral() {
+        let position = sample_position("id", true, 0.05, 100.0);
+        let settings = sample_settings();
+
+        let (bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            vec![MicroEngineInstrument {
+                id: "EURUSD".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![eurusd_bidask(1.25, 1.25)],
+        );
+
+        let mut account = sample_account(1000.0);
+        account.deposit_currency = Some("EUR".to_string());
+        let update = account.recalculate_account_data(&[&position], &settings, &bidask_cache);
+
+        // 100 USD / 1.25 = 80 EUR
+        assert_eq!(format!("{:.2}", update.total_gross), "80.00");
+        assert_eq!(format!("{:.2}", update.equity), "1080.00");
+        // 0.05 * 100000 * 1.25542 / 100 = 62.771 USD
+        assert_eq!(format!("{:.3}", update.margin), "50.217");
+
+        let mut account = sample_account(1000.0);
+        account.deposit_currency = Some("USD".to_string());
+        let update = account.recalculate_account_data(&[&position], &settings, &bidask_cache);
+
+        assert_eq!(format!("{:.2}", update.equity), "1100.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_unconvertible_collateral_keeps_last_figures() {
+        let mut position = sample_position("id", true, 0.05, 100.0);
+        let settings = sample_settings();
+
+        let (bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            vec![MicroEngineInstrument {
+                id: "EURUSD".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![eurusd_bidask(1.25, 1.25)],
+        );
+
+        let mut account = sample_account(1000.0);
+        account.deposit_currency = Some("EUR".to_string());
+        account.recalculate_account_data(&[&position], &settings, &bidask_cache);
+
+        // The USD loss can't be taken as EUR, equity isn't shrunk by leaving it out
+        position.pl = -500.0;
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "1080.00");
+        assert_eq!(format!("{:.3}", update.margin), "50.217");
+        assert_eq!(format!("{:.2}", update.total_gross), "80.00");
+        assert_eq!(update.unpriceable_positions, 1);
+        assert!(!update.is_changed(0.0));
+        assert_eq!(format!("{:.2}", account.equity), "1080.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_margin_converted_from_quote_to_collateral() {
+        let eurgbp = MicroEngineBidask {
+            id: "EURGBP".to_string(),
+            bid: 0.85000,
+            ask: 0.85020,
+            base: "EUR".to_string(),
+            quote: "GBP".to_string(),
+            date: None,
+        };
+        let mut position = sample_position("id", true, 0.1, 0.0);
+        position.quote = "GBP".to_string();
+        position.asset_pair = "EURGBP".to_string();
+        position.open_bidask = eurgbp.clone();
+        position.active_bidask = eurgbp.clone();
+        position.margin_bidask = eurgbp;
+
+        let mut settings = sample_settings();
+        let instrument_settings = settings.instruments["EURUSD"].clone();
+        settings
+            .instruments
+            .insert("EURGBP".to_string(), instrument_settings);
+
+        let (bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            vec![MicroEngineInstrument {
+                id: "GBPUSD".to_string(),
+                base: "GBP".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![MicroEngineBidask {
+                id: "GBPUSD".to_string(),
+                bid: 1.25000,
+                ask: 1.25020,
+                base: "GBP".to_string(),
+                quote: "USD".to_string(),
+                date: None,
+            }],
+        );
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(&[&position], &settings, &bidask_cache);
+
+        // 0.1 * 100000 * 0.85020 / 100 = 85.02 GBP, * 1.25 = 106.275 USD
+        assert_eq!(format!("{:.3}", update.margin), "106.275");
+
+        // One conversion for all the instrument's positions
+        let before = bidask_cache.resolutions();
+        let mut account = sample_account(1000.0);
+        let positions = [&position, &position, &position];
+        account.recalculate_account_data(&positions, &settings, &bidask_cache);
+        assert_eq!(bidask_cache.resolutions() - before, 1);
+
+        // Without the conversion price the GBP margin can't be taken as USD
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(update.margin, 0.0);
+        assert_eq!(update.unpriceable_positions, 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_unconverted_margin_keeps_the_loss_in_equity() {
+        let eurgbp = MicroEngineBidask {
+            id: "EURGBP".to_string(),
+            bid: 0.85000,
+            ask: 0.85020,
+            base: "EUR".to_string(),
+            quote: "GBP".to_string(),
+            date: None,
+        };
+        let mut position = sample_position("id", true, 0.1, -300.0);
+        position.quote = "GBP".to_string();
+        position.asset_pair = "EURGBP".to_string();
+        position.open_bidask = eurgbp.clone();
+        position.active_bidask = eurgbp.clone();
+        position.margin_bidask = eurgbp;
+
+        let mut settings = sample_settings();
+        let instrument_settings = settings.instruments["EURUSD"].clone();
+        settings
+            .instruments
+            .insert("EURGBP".to_string(), instrument_settings);
+
+        // No GBPUSD price and no last known one: the margin is left out, the loss is not
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(update.margin, 0.0);
+        assert_eq!(format!("{:.2}", update.total_gross), "-300.00");
+        assert_eq!(format!("{:.2}", update.equity), "700.00");
+        assert_eq!(update.unpriceable_positions, 1);
+
+        // With a last known GBPUSD price the margin is priced at it
+        position.profit_bidask = MicroEngineBidask {
+            id: "GBPUSD".to_string(),
+            bid: 1.25000,
+            ask: 1.25020,
+            base: "GBP".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.3}", update.margin), "106.275");
+        assert_eq!(format!("{:.2}", update.equity), "700.00");
+        assert_eq!(update.unpriceable_positions, 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_negative_swaps_reduce_free_margin() {
+        let mut position = sample_position("id", true, 0.05, 0.0);
+        position.swaps_sum = -400.0;
+        let mut settings = sample_settings();
+        settings.equity_components.swaps = false;
+
+        let mut account = sample_account(1000.0);
+        let plain =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        settings.swaps_reduce_free_margin = true;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(
+            format!("{:.2}", plain.free_margin - update.free_margin),
+            "400.00"
+        );
+        assert_eq!(update.equity, plain.equity);
+        assert_eq!(update.margin, plain.margin);
+        assert_eq!(update.margin_level, plain.margin_level);
+
+        // Swaps counted in equity already reduce free margin through it
+        settings.equity_components.swaps = true;
+        settings.swaps_reduce_free_margin = false;
+        let mut account = sample_account(1000.0);
+        let plain =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        settings.swaps_reduce_free_margin = true;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(update.free_margin, plain.free_margin);
+    }
+
+    #[tokio::test]
+    pub async fn test_swaps_reduce_free_margin_with_reserved_swaps() {
+        let mut position = sample_position("id", true, 0.05, 0.0);
+        position.swaps_sum = -400.0;
+        let mut settings = sample_settings();
+        settings.equity_components.swaps = false;
+
+        let mut account = sample_account(1000.0);
+        let plain =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        settings.swaps_reduce_free_margin = true;
+        settings.reserve_swaps_in_margin = true;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        // The reserve takes the swaps off free margin once, the flag doesn't take them again
+        assert_eq!(
+            format!("{:.2}", plain.free_margin - update.free_margin),
+            "400.00"
+        );
+        assert_eq!(format!("{:.2}", update.swaps_reserve), "400.00");
+        assert_eq!(update.equity, plain.equity);
+    }
+
+    #[tokio::test]
+    pub async fn test_calculated_at_advances() {
+        let position = sample_position("id", true, 0.05, 0.0);
+        let settings = sample_settings();
+        let mut account = sample_account(1000.0);
+        assert!(account.last_calculated.is_none());
+
+        let first =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+        assert_eq!(account.last_calculated, Some(first.calculated_at));
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let second =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+        assert!(second.calculated_at > first.calculated_at);
+        assert_eq!(account.last_calculated, Some(second.calculated_at));
+    }
+
+    #[tokio::test]
+    pub async fn test_round_account_figures() {
+        let position = sample_position("id", true, 0.05, 10.123456);
+        let mut settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.6}", update.equity), "1010.123456");
+        assert_eq!(format!("{:.3}", update.margin), "62.771");
+
+        settings.round_account_figures = true;
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        // USD collateral has 2 digits
+        assert_eq!(update.equity, 1010.12);
+        assert_eq!(update.margin, 62.77);
+        assert_eq!(update.free_margin, 947.35);
+        assert_eq!(account.free_margin, 947.35);
+        assert_eq!(update.margin_level, 1010.12 / 62.77 * 100.0);
+    }
+
+    #[tokio::test]
+    pub async fn test_hedged_and_directional_margin() {
+        let buy = sample_position("buy", true, 0.1, 0.0);
+        let sell = sample_position("sell", false, 0.05, 0.0);
+        let mut settings = sample_settings();
+        settings.hedge_coef = Some(0.5);
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&buy, &sell], &settings, &empty_bidask_cache());
+
+        assert!(update.hedged_margin > 0.0);
+        assert!(update.directional_margin > 0.0);
+        assert_eq!(
+            format!("{:.10}", update.hedged_margin + update.directional_margin),
+            format!("{:.10}", update.margin)
+        );
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(&[&buy], &settings, &empty_bidask_cache());
+
+        assert_eq!(update.hedged_margin, 0.0);
+        assert_eq!(update.directional_margin, update.margin);
+    }
+
+    #[tokio::test]
+    pub async fn test_position_level_leverage() {
+        let account_leverage = sample_position("account", true, 0.1, 0.0);
+        let mut own_leverage = sample_position("own", true, 0.1, 0.0);
+        own_leverage.leverage = Some(50.0);
+        let settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(
+            &[&account_leverage, &own_leverage],
+            &settings,
+            &empty_bidask_cache(),
+        );
+
+        // 0.1 * 100000 * 1.25542 / 100 + 0.1 * 100000 * 1.25542 / 50
+        assert_eq!(format!("{:.3}", update.margin), "376.626");
+
+        let mut hedging = sample_position("hedging", false, 0.1, 0.0);
+        hedging.leverage = Some(50.0);
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(
+            &[&account_leverage, &own_leverage, &hedging],
+            &settings,
+            &empty_bidask_cache(),
+        );
+
+        assert!(update.hedged_margin > 0.0);
+        assert_eq!(
+            format!("{:.10}", update.hedged_margin + update.directional_margin),
+            format!("{:.10}", update.margin)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_margin_utilization() {
+        let position = sample_position("id", true, 0.05, 25.0);
+        let settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert!(update.margin_utilization > 0.0);
+        assert_eq!(update.margin_utilization, account.margin_utilization());
+        assert_eq!(
+            format!("{:.6}", update.margin_utilization * update.margin_level),
+            "10000.000000"
+        );
+
+        account.equity = 0.0;
+        assert_eq!(account.margin_utilization(), 0.0);
+    }
+
+    #[tokio::test]
+    pub async fn test_pending_positions_are_excluded() {
+        let active = sample_position("active", true, 0.05, 10.0);
+        let mut pending = sample_position("pending", true, 0.05, 10.0);
+        pending.is_pending = true;
+        let settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let expected =
+            account.recalculate_account_data(&[&active], &settings, &empty_bidask_cache());
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(
+            &[&active, &pending],
+            &settings,
+            &empty_bidask_cache(),
+        );
+
+        assert_eq!(update.margin, expected.margin);
+        assert_eq!(update.total_gross, expected.total_gross);
+    }
+
+    #[tokio::test]
+    pub async fn test_rounded_total_gross_reconciles_with_equity() {
+        let positions = (0..3)
+            .map(|i| sample_position(&format!("P{i}"), true, 0.01, 0.004))
+            .collect::<Vec<_>>();
+        let positions = positions.iter().collect::<Vec<_>>();
+
+        let mut settings = sample_settings();
+        settings.round_account_figures = true;
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(&positions, &settings, &empty_bidask_cache());
+
+        // 3 * 0.004 = 0.012 rounds to 0.01 before it enters equity
+        assert_eq!(update.total_gross, 0.01);
+        assert_eq!(update.equity, 1000.01);
+        assert_eq!(
+            update.equity,
+            round_float_to_digits(update.balance + update.total_gross, 2)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_credit_counts_toward_equity_but_not_withdrawable() {
+        let position = sample_position("id", true, 0.1, -1100.0);
+        let settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        account.credit = 500.0;
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.margin), "125.54");
+        assert_eq!(format!("{:.2}", update.equity), "400.00");
+        assert_eq!(format!("{:.2}", update.free_margin), "274.46");
+        assert_eq!(
+            format!("{:.2}", update.margin_level),
+            format!("{:.2}", 400.0 / 125.54 * 100.0)
+        );
+        // The losses exceed the balance, only the bonus keeps equity positive
+        assert_eq!(update.withdrawable, 0.0);
+
+        let position = sample_position("id", true, 0.1, 200.0);
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.2}", update.equity), "1700.00");
+        assert_eq!(format!("{:.2}", update.withdrawable), "1200.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_unpriceable_positions_are_counted() {
+        let mut settings = sample_settings();
+        let eurusd_settings = settings.instruments["EURUSD"].clone();
+        settings
+            .instruments
+            .insert("GBPUSD".to_string(), eurusd_settings);
+
+        let (bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            vec![MicroEngineInstrument {
+                id: "EURUSD".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![eurusd_bidask(1.25, 1.25)],
+        );
+
+        let priced = sample_position("priced", true, 0.05, 100.0);
+        let mut unpriced = sample_position("unpriced", true, 0.05, 100.0);
+        unpriced.asset_pair = "GBPUSD".to_string();
+        unpriced.base = "GBP".to_string();
+        let mut pending = unpriced.clone();
+        pending.id = "pending".to_string();
+        pending.is_pending = true;
+
+        let mut account = sample_account(1000.0);
+        let update = account.recalculate_account_data(&[&priced], &settings, &bidask_cache);
+        assert_eq!(update.unpriceable_positions, 0);
+
+        let update = account.recalculate_account_data(
+            &[&priced, &unpriced, &pending],
+            &settings,
+            &bidask_cache,
+        );
+        assert_eq!(update.unpriceable_positions, 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_reserve_swaps_in_margin() {
+        let mut position = sample_position("id", true, 0.05, 0.0);
+        position.swaps_sum = -400.0;
+        let mut settings = sample_settings();
+
+        let mut account = sample_account(1000.0);
+        let plain =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        settings.reserve_swaps_in_margin = true;
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+
+        assert_eq!(format!("{:.3}", update.margin - plain.margin), "400.000");
+        assert_eq!(
+            format!("{:.5}", update.directional_margin),
+            format!("{:.5}", plain.directional_margin)
+        );
+        assert_eq!(format!("{:.3}", update.swaps_reserve), "400.000");
+        assert_eq!(plain.swaps_reserve, 0.0);
+        assert_eq!(update.equity, plain.equity);
+
+        // Positive swaps don't release margin
+        position.swaps_sum = 400.0;
+        let update =
+            account.recalculate_account_data(&[&position], &settings, &empty_bidask_cache());
+        assert_eq!(
+            format!("{:.3}", update.margin),
+            format!("{:.3}", plain.margin)
+        );
+    }
 }
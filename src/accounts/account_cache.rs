@@ -3,13 +3,18 @@ use std::collections::{HashMap, HashSet};
 use crate::{
     MicroEngineError,
     accounts::account::{MicroEngineAccount, MicroEngineAccountCalculationUpdate},
-    positions::positions_cache::MicroEnginePositionCache,
-    settings::TradingSettingsCache,
+    bidask::MicroEngineBidAskCache,
+    positions::{position::MicroEnginePosition, positions_cache::MicroEnginePositionCache},
+    settings::{
+        AccountLeveragePolicy, MicroEngineTradingGroupSettings, TradingSettingsCache,
+        UnknownGroupPolicy,
+    },
 };
 
 pub struct MicroEngineAccountCache {
     trader_index: HashMap<String, HashSet<String>>,
     accounts: HashMap<String, MicroEngineAccount>,
+    unknown_group_policy: UnknownGroupPolicy,
 }
 
 impl MicroEngineAccountCache {
@@ -32,6 +37,32 @@ impl MicroEngineAccountCache {
         Self {
             trader_index,
             accounts: accounts_cache,
+            unknown_group_policy: UnknownGroupPolicy::default(),
+        }
+    }
+
+    pub(crate) fn set_unknown_group_policy(&mut self, policy: UnknownGroupPolicy) {
+        self.unknown_group_policy = policy;
+    }
+
+    /// Ids of accounts whose trading group has no settings, sorted.
+    pub fn accounts_without_settings(&self, settings: &TradingSettingsCache) -> Vec<String> {
+        let mut ids = self
+            .accounts
+            .keys()
+            .filter(|x| settings.resolve_by_account(x).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids
+    }
+
+    fn apply_unknown_group_policy(&mut self, account_id: &str) {
+        if self.unknown_group_policy == UnknownGroupPolicy::ZeroOut
+            && let Some(account) = self.accounts.get_mut(account_id)
+        {
+            account.clear_position_figures();
         }
     }
 
@@ -46,6 +77,39 @@ impl MicroEngineAccountCache {
         )
     }
 
+    /// Aggregate margin level of all the trader's accounts. Zero when they hold no margin, like
+    /// the per-account margin level.
+    pub fn trader_margin_level(&self, trader_id: &str) -> Option<f64> {
+        let accounts = self.get_trader_accounts(trader_id)?;
+
+        let (equity, margin) = accounts.iter().fold((0.0, 0.0), |(equity, margin), x| {
+            (equity + x.equity, margin + x.margin)
+        });
+
+        Some(match margin < 0.00001 {
+            true => 0.0,
+            false => equity / margin * 100.0,
+        })
+    }
+
+    /// Counts accounts by margin level against ascending `buckets` boundaries. Slot `i` holds
+    /// levels in `[buckets[i - 1], buckets[i])`, with open-ended first and last ranges, so there
+    /// are `buckets.len() + 1` ranges. The final extra slot counts accounts without margin.
+    pub fn margin_level_histogram(&self, buckets: &[f64]) -> Vec<usize> {
+        let mut result = vec![0; buckets.len() + 2];
+
+        for account in self.accounts.values() {
+            let slot = match account.margin < 0.00001 {
+                true => buckets.len() + 1,
+                false => buckets.partition_point(|x| *x <= account.margin_level),
+            };
+
+            result[slot] += 1;
+        }
+
+        result
+    }
+
     pub fn get_account(&self, account_id: &str) -> Option<&MicroEngineAccount> {
         self.accounts.get(account_id)
     }
@@ -54,13 +118,48 @@ impl MicroEngineAccountCache {
         self.accounts.values().collect()
     }
 
+    /// Accounts matching `pred`, in no particular order.
+    pub fn filter(&self, pred: impl Fn(&MicroEngineAccount) -> bool) -> Vec<&MicroEngineAccount> {
+        self.accounts.values().filter(|x| pred(x)).collect()
+    }
+
+    pub(crate) fn remove_account(&mut self, account_id: &str) -> Option<MicroEngineAccount> {
+        let account = self.accounts.remove(account_id)?;
+
+        if let Some(trader_accounts) = self.trader_index.get_mut(&account.trader_id) {
+            trader_accounts.remove(account_id);
+
+            if trader_accounts.is_empty() {
+                self.trader_index.remove(&account.trader_id);
+            }
+        }
+
+        Some(account)
+    }
+
+    pub fn accounts_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Page through accounts ordered by id, so consecutive pages never overlap or skip entries.
+    pub fn list_accounts(&self, offset: usize, limit: usize) -> Vec<&MicroEngineAccount> {
+        let mut accounts = self.accounts.values().collect::<Vec<_>>();
+        accounts.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+        accounts.into_iter().skip(offset).take(limit).collect()
+    }
+
     pub(crate) fn recalculate_account_data(
         &mut self,
         settings: &TradingSettingsCache,
         positions_cache: &MicroEnginePositionCache,
+        bidask_cache: &MicroEngineBidAskCache,
         account_id: &str,
     ) -> Option<MicroEngineAccountCalculationUpdate> {
-        let account_settings = settings.resolve_by_account(account_id)?;
+        let Some(account_settings) = settings.resolve_by_account(account_id) else {
+            self.apply_unknown_group_policy(account_id);
+            return None;
+        };
 
         let account_positions = positions_cache
             .get_account_positions(&account_id)
@@ -68,19 +167,42 @@ impl MicroEngineAccountCache {
 
         let account = self.accounts.get_mut(account_id)?;
 
-        Some(account.recalculate_account_data(account_positions.as_slice(), account_settings))
+        Some(account.recalculate_account_data(
+            account_positions.as_slice(),
+            account_settings,
+            bidask_cache,
+        ))
+    }
+
+    pub(crate) fn realize_position_pl(
+        &mut self,
+        settings: &TradingSettingsCache,
+        position: &MicroEnginePosition,
+    ) {
+        let Some(account) = self.accounts.get_mut(&position.account_id) else {
+            return;
+        };
+
+        let settle_on_close = settings
+            .resolve_by_account(&position.account_id)
+            .map(|x| x.settle_on_close)
+            .unwrap_or(false);
+
+        account.realize_pl(position.get_gross_pl(), settle_on_close);
     }
 
     pub(crate) fn recalculate_accounts_data(
         &mut self,
         settings: &TradingSettingsCache,
         positions_cache: &MicroEnginePositionCache,
+        bidask_cache: &MicroEngineBidAskCache,
         updated_accounts: &[&str],
     ) -> Vec<MicroEngineAccountCalculationUpdate> {
         let mut updated_accounts_data = vec![];
 
         for account_id in updated_accounts {
             let Some(account_settings) = settings.resolve_by_account(&account_id) else {
+                self.apply_unknown_group_policy(account_id);
                 continue;
             };
 
@@ -89,22 +211,48 @@ impl MicroEngineAccountCache {
                 .unwrap_or_default();
 
             if let Some(account) = self.accounts.get_mut(*account_id) {
-                updated_accounts_data.push(
-                    account
-                        .recalculate_account_data(account_positions.as_slice(), account_settings),
-                );
+                updated_accounts_data.push(account.recalculate_account_data(
+                    account_positions.as_slice(),
+                    account_settings,
+                    bidask_cache,
+                ));
             }
         }
         updated_accounts_data
     }
 
+    /// Same as `recalculate_accounts_data`, but drops updates whose figures didn't move by more
+    /// than `epsilon`.
+    pub(crate) fn recalculate_accounts_data_changed_only(
+        &mut self,
+        settings: &TradingSettingsCache,
+        positions_cache: &MicroEnginePositionCache,
+        bidask_cache: &MicroEngineBidAskCache,
+        updated_accounts: &[&str],
+        epsilon: f64,
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        let mut result = self.recalculate_accounts_data(
+            settings,
+            positions_cache,
+            bidask_cache,
+            updated_accounts,
+        );
+        result.retain(|x| x.is_changed(epsilon));
+
+        result
+    }
+
     pub(crate) fn recalculate_all_accounts(
         &mut self,
         settings: &TradingSettingsCache,
         positions_cache: &MicroEnginePositionCache,
+        bidask_cache: &MicroEngineBidAskCache,
     ) {
         for (id, account) in self.accounts.iter_mut() {
             let Some(account_settings) = settings.resolve_by_account(id) else {
+                if self.unknown_group_policy == UnknownGroupPolicy::ZeroOut {
+                    account.clear_position_figures();
+                }
                 continue;
             };
 
@@ -112,7 +260,7 @@ impl MicroEngineAccountCache {
                 .get_account_positions(id)
                 .unwrap_or_default();
 
-            account.recalculate_account_data(&account_positions, account_settings);
+            account.recalculate_account_data(&account_positions, account_settings, bidask_cache);
         }
     }
 
@@ -121,9 +269,14 @@ impl MicroEngineAccountCache {
         account: MicroEngineAccount,
         settings: &mut TradingSettingsCache,
         positions_cache: &MicroEnginePositionCache,
+        bidask_cache: &MicroEngineBidAskCache,
     ) -> Result<MicroEngineAccountCalculationUpdate, MicroEngineError> {
         let mut account = account;
 
+        if let Some(group) = settings.groups.get(&account.trading_group) {
+            apply_leverage_policy(&mut account, group)?;
+        }
+
         settings.account_updated(&account);
 
         let settings = settings.resolve_by_account(&account.id).ok_or(
@@ -135,7 +288,7 @@ impl MicroEngineAccountCache {
             .unwrap_or_default();
 
         let calculation_result =
-            account.recalculate_account_data(account_positions.as_slice(), settings);
+            account.recalculate_account_data(account_positions.as_slice(), settings, bidask_cache);
 
         self.trader_index
             .entry(account.trader_id.clone())
@@ -146,4 +299,141 @@ impl MicroEngineAccountCache {
 
         Ok(calculation_result)
     }
-}
\ No newline at end of file
+}
+
+/// Clamps the account's leverage to the group's `max_leverage` or rejects it, depending on the
+/// group's `account_leverage_policy`.
+pub(crate) fn apply_leverage_policy(
+    account: &mut MicroEngineAccount,
+    group: &MicroEngineTradingGroupSettings,
+) -> Result<(), MicroEngineError> {
+    if let Some(max_leverage) = group.max_leverage
+        && account.leverage > max_leverage
+    {
+        match group.account_leverage_policy {
+            AccountLeveragePolicy::Clamp => account.leverage = max_leverage,
+            AccountLeveragePolicy::Reject => {
+                return Err(MicroEngineError::LeverageExceedsGroupMax {
+                    account_id: account.id.clone(),
+                    leverage: account.leverage,
+                    max_leverage,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::accounts::{account::MicroEngineAccount, account_cache::MicroEngineAccountCache};
+
+    fn account(id: &str) -> MicroEngineAccount {
+        MicroEngineAccount {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            trading_group: "tg1".to_string(),
+            balance: 1000.0,
+            credit: 0.0,
+            leverage: 100.0,
+            margin: 0.0,
+            equity: 0.0,
+            free_margin: 0.0,
+            margin_level: 0.0,
+            realized_pl: 0.0,
+            deposit_currency: None,
+            last_calculated: None,
+            raw_spread: false,
+        }
+    }
+
+    #[test]
+    fn test_list_accounts_pages_are_ordered_and_disjoint() {
+        let cache = MicroEngineAccountCache::new(
+            ["ACC5", "ACC2", "ACC4", "ACC1", "ACC3"]
+                .into_iter()
+                .map(account)
+                .collect::<Vec<_>>(),
+        );
+
+        let first_page = cache
+            .list_accounts(0, 3)
+            .into_iter()
+            .map(|x| x.id.as_str())
+            .collect::<Vec<_>>();
+        let second_page = cache
+            .list_accounts(3, 3)
+            .into_iter()
+            .map(|x| x.id.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(first_page, vec!["ACC1", "ACC2", "ACC3"]);
+        assert_eq!(second_page, vec!["ACC4", "ACC5"]);
+        assert!(cache.list_accounts(5, 3).is_empty());
+    }
+
+    #[test]
+    fn test_trader_margin_level() {
+        let mut healthy = account("ACC1");
+        healthy.equity = 5000.0;
+        healthy.margin = 500.0;
+
+        let mut stressed = account("ACC2");
+        stressed.equity = 550.0;
+        stressed.margin = 500.0;
+
+        let mut other_trader = account("ACC3");
+        other_trader.trader_id = "TR2".to_string();
+
+        let cache = MicroEngineAccountCache::new(vec![healthy, stressed, other_trader]);
+
+        // (5000 + 550) / (500 + 500) * 100
+        assert_eq!(cache.trader_margin_level("TR1"), Some(555.0));
+        assert_eq!(cache.trader_margin_level("TR2"), Some(0.0));
+        assert_eq!(cache.trader_margin_level("TR3"), None);
+    }
+
+    #[test]
+    fn test_margin_level_histogram() {
+        let accounts = [
+            ("ACC1", 100.0, 30.0),
+            ("ACC2", 100.0, 50.0),
+            ("ACC3", 100.0, 75.0),
+            ("ACC4", 100.0, 150.0),
+            ("ACC5", 100.0, 250.0),
+            ("ACC6", 100.0, 900.0),
+            ("ACC7", 0.0, 0.0),
+        ]
+        .into_iter()
+        .map(|(id, margin, margin_level)| {
+            let mut account = account(id);
+            account.margin = margin;
+            account.margin_level = margin_level;
+            account
+        })
+        .collect::<Vec<_>>();
+
+        let cache = MicroEngineAccountCache::new(accounts);
+
+        assert_eq!(
+            cache.margin_level_histogram(&[50.0, 100.0, 200.0]),
+            vec![1, 2, 1, 2, 1]
+        );
+        assert_eq!(cache.margin_level_histogram(&[]), vec![6, 1]);
+    }
+
+    #[test]
+    fn test_filter_accounts() {
+        let mut negative = account("ACC2");
+        negative.free_margin = -50.0;
+
+        let cache = MicroEngineAccountCache::new(vec![account("ACC1"), negative, account("ACC3")]);
+
+        let result = cache.filter(|x| x.free_margin < 0.0);
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].id, "ACC2");
+        assert!(cache.filter(|x| x.balance > 1000.0).is_empty());
+    }
+}
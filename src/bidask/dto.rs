@@ -1,6 +1,8 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use cross_calculations::core::{CrossCalculationsBidAsk, CrossCalculationsCrossRate};
 
+use crate::round_float_to_digits;
+
 #[derive(Default, Clone, Debug)]
 pub struct MicroEngineBidask {
     pub id: String,
@@ -8,6 +10,8 @@ pub struct MicroEngineBidask {
     pub ask: f64,
     pub base: String,
     pub quote: String,
+    /// When the price was quoted. `None` for prices without a source timestamp.
+    pub date: Option<DateTime<Utc>>,
 }
 
 impl CrossCalculationsBidAsk for MicroEngineBidask {
@@ -24,11 +28,28 @@ impl CrossCalculationsBidAsk for MicroEngineBidask {
     }
 
     fn get_date(&self) -> chrono::DateTime<chrono::Utc> {
-        Utc::now()
+        self.date.unwrap_or_else(Utc::now)
     }
 }
 
 impl MicroEngineBidask {
+    pub fn new(
+        id: impl Into<String>,
+        bid: f64,
+        ask: f64,
+        base: impl Into<String>,
+        quote: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            bid,
+            ask,
+            base: base.into(),
+            quote: quote.into(),
+            date: None,
+        }
+    }
+
     #[inline(always)]
     pub fn get_bid_ask_with_markup(&self, markup_bid: f64, markup_ask: f64) -> (f64, f64) {
         let bid = self.bid + markup_bid;
@@ -43,6 +64,22 @@ impl MicroEngineBidask {
         self.ask = ask;
     }
 
+    /// Whether the price was quoted more than `max_age` before `now`. Prices without a
+    /// timestamp are never stale.
+    pub fn is_stale(&self, now: DateTime<Utc>, max_age: chrono::Duration) -> bool {
+        self.date.is_some_and(|date| now - date > max_age)
+    }
+
+    /// Finite, positive and not crossed (`bid <= ask`).
+    #[inline(always)]
+    pub fn is_valid(&self) -> bool {
+        self.bid.is_finite()
+            && self.ask.is_finite()
+            && self.bid > 0.0
+            && self.ask > 0.0
+            && self.bid <= self.ask
+    }
+
     #[inline(always)]
     pub fn get_open_price(&self, is_buy: bool) -> f64 {
         match is_buy {
@@ -75,15 +112,52 @@ impl MicroEngineBidask {
         };
     }
 
+    /// Moves bid and ask by a number of points, a point being `10^-digits`.
+    #[inline(always)]
+    pub fn shift_points(&mut self, bid_points: f64, ask_points: f64, digits: u32) {
+        let point = 10_f64.powi(-(digits as i32));
+
+        self.bid += bid_points * point;
+        self.ask += ask_points * point;
+    }
+
+    /// Falls back to a blank (1.0) price when the quote can't be reversed, see `try_reverse`.
     #[inline(always)]
     pub fn reverse(&self) -> Self {
-        Self {
+        self.try_reverse().unwrap_or_else(|| Self {
+            id: format!("REVERSE-{}", self.id),
+            base: self.quote.clone(),
+            quote: self.base.clone(),
+            ..Self::create_blank()
+        })
+    }
+
+    /// `reverse` with bid and ask rounded to `digits`, for display and reconciliation. Keep
+    /// using `reverse` for calculations.
+    #[inline(always)]
+    pub fn reverse_rounded(&self, digits: u32) -> Self {
+        let mut result = self.reverse();
+        result.bid = round_float_to_digits(result.bid, digits as i32);
+        result.ask = round_float_to_digits(result.ask, digits as i32);
+
+        result
+    }
+
+    /// `None` on a non-positive bid or ask, which would otherwise reverse into infinity.
+    #[inline(always)]
+    pub fn try_reverse(&self) -> Option<Self> {
+        if self.bid <= 0.0 || self.ask <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
             id: format!("REVERSE-{}", self.id.clone()),
             bid: 1.0 / self.ask,
             ask: 1.0 / self.bid,
             base: self.quote.clone(),
             quote: self.base.clone(),
-        }
+            date: self.date,
+        })
     }
 
     #[inline(always)]
@@ -94,6 +168,7 @@ impl MicroEngineBidask {
             ask: 1.0,
             base: String::default(),
             quote: String::default(),
+            date: None,
         }
     }
 }
@@ -108,6 +183,77 @@ impl From<CrossCalculationsCrossRate> for MicroEngineBidask {
             ask: value.ask,
             base: value.base,
             quote: value.quote,
+            date: None,
+        }
+    }
+}
+
+/// `(id, bid, ask, base, quote)`
+impl From<(&str, f64, f64, &str, &str)> for MicroEngineBidask {
+    fn from((id, bid, ask, base, quote): (&str, f64, f64, &str, &str)) -> Self {
+        Self::new(id, bid, ask, base, quote)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MicroEngineBidask;
+
+    #[test]
+    fn test_constructors() {
+        let from_new = MicroEngineBidask::new("EURUSD", 1.08000, 1.08020, "EUR", "USD".to_string());
+        let from_tuple = MicroEngineBidask::from(("EURUSD", 1.08000, 1.08020, "EUR", "USD"));
+
+        for bidask in [from_new, from_tuple] {
+            assert_eq!(bidask.id, "EURUSD");
+            assert_eq!(bidask.bid, 1.08000);
+            assert_eq!(bidask.ask, 1.08020);
+            assert_eq!(bidask.base, "EUR");
+            assert_eq!(bidask.quote, "USD");
         }
     }
+
+    #[test]
+    fn test_reverse_zero_bid() {
+        let bidask = MicroEngineBidask::new("USDCAD", 0.0, 1.35020, "USD", "CAD");
+
+        assert!(bidask.try_reverse().is_none());
+
+        let reversed = bidask.reverse();
+        assert!(reversed.bid.is_finite() && reversed.ask.is_finite());
+        assert_eq!(reversed.base, "CAD");
+        assert_eq!(reversed.quote, "USD");
+
+        let valid = MicroEngineBidask::new("USDCAD", 1.25, 1.6, "USD", "CAD");
+        let reversed = valid.try_reverse().unwrap();
+        assert_eq!(reversed.bid, 0.625);
+        assert_eq!(reversed.ask, 0.8);
+    }
+
+    #[test]
+    fn test_reverse_rounded() {
+        let bidask = MicroEngineBidask::new("USDCAD", 1.35000, 1.35020, "USD", "CAD");
+
+        let raw = bidask.reverse();
+        let rounded = bidask.reverse_rounded(5);
+
+        assert_ne!(raw.bid, 0.74063);
+        assert_eq!(rounded.bid, 0.74063);
+        assert_eq!(rounded.ask, 0.74074);
+        assert_eq!(rounded.base, "CAD");
+        assert_eq!(rounded.quote, "USD");
+    }
+
+    #[test]
+    fn test_shift_points() {
+        let mut bidask = MicroEngineBidask::new("EURUSD", 1.08000, 1.08020, "EUR", "USD");
+
+        bidask.shift_points(10.0, -10.0, 5);
+        assert_eq!(format!("{:.5}", bidask.bid), "1.08010");
+        assert_eq!(format!("{:.5}", bidask.ask), "1.08010");
+
+        bidask.shift_points(-10.0, 10.0, 5);
+        assert_eq!(format!("{:.5}", bidask.bid), "1.08000");
+        assert_eq!(format!("{:.5}", bidask.ask), "1.08020");
+    }
 }
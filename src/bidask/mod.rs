@@ -6,16 +6,70 @@ use cross_calculations::core::{
     CrossCalculationsSourceInstrument,
 };
 
-use crate::bidask::dto::MicroEngineBidask;
+use crate::{bidask::dto::MicroEngineBidask, round_float_to_digits};
 
 pub mod dto;
 
+/// Currency -> currency -> instrument id.
+type PairIndex = AHashMap<String, AHashMap<String, String>>;
+
 #[derive(Debug)]
 pub struct MicroEngineBidAskCache {
     prices: AHashMap<String, MicroEngineBidask>,
-    base_quote_index: AHashMap<String, AHashMap<String, String>>,
-    quote_base_index: AHashMap<String, AHashMap<String, String>>,
+    base_quote_index: PairIndex,
+    quote_base_index: PairIndex,
     cross_matrix: CrossCalculationsCrossPairsMatrix,
+    /// Base -> quote -> id of every known instrument, whether it has a price yet or not.
+    instrument_index: PairIndex,
+    /// Instrument id -> crosses it is a leg of.
+    cross_legs: AHashMap<String, Vec<(String, String)>>,
+    required_crosses: Vec<(String, String)>,
+    spread_stats: Option<AHashMap<String, SpreadStats>>,
+    price_provider: Option<Box<dyn PriceProvider>>,
+    /// Currency -> ids of instruments quoting it. Only filled when a price provider is set.
+    currency_instruments: AHashMap<String, Vec<String>>,
+    /// Pinned base -> quote rates, consulted before any cached price.
+    conversion_overrides: AHashMap<String, AHashMap<String, f64>>,
+    cross_rate_digits: Option<u32>,
+    conversion_fallback: ConversionFallback,
+    /// Base -> quote rates used under `ConversionFallback::StaticRate`.
+    fallback_rates: AHashMap<String, AHashMap<String, f64>>,
+    /// Base -> quote prices last resolved, recorded on price updates under
+    /// `ConversionFallback::LastResolved`.
+    last_resolved: AHashMap<String, AHashMap<String, MicroEngineBidask>>,
+    #[cfg(test)]
+    resolutions: std::sync::atomic::AtomicUsize,
+}
+
+/// Pull-based source consulted when a price is missing from the cache.
+pub trait PriceProvider: std::fmt::Debug + Send + Sync {
+    fn fetch(&self, id: &str) -> Option<MicroEngineBidask>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpreadStats {
+    pub min: f64,
+    pub max: f64,
+    pub last: f64,
+    pub sample_count: u64,
+}
+
+impl SpreadStats {
+    fn new(spread: f64) -> Self {
+        Self {
+            min: spread,
+            max: spread,
+            last: spread,
+            sample_count: 1,
+        }
+    }
+
+    fn add_sample(&mut self, spread: f64) {
+        self.min = self.min.min(spread);
+        self.max = self.max.max(spread);
+        self.last = spread;
+        self.sample_count += 1;
+    }
 }
 
 impl CrossCalculationsPriceSource for MicroEngineBidAskCache {
@@ -27,15 +81,98 @@ impl CrossCalculationsPriceSource for MicroEngineBidAskCache {
     }
 }
 
+/// What `get_price` answers when a base -> quote price can't be resolved from cached prices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConversionFallback {
+    /// No price, the position is left to its group's `MissingConversionPolicy`
+    #[default]
+    Unpriceable,
+    /// The rate configured with `set_fallback_rate`, if any
+    StaticRate,
+    /// The last price successfully resolved for the pair, if any
+    LastResolved,
+}
+
+/// How a base -> quote price is resolved from the cached instruments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriceResolution {
+    /// `base == quote`, the price is 1.0
+    Identity,
+    Direct {
+        id: String,
+    },
+    Reverse {
+        id: String,
+    },
+    /// Two legs through a shared currency. A leg is reversed when its instrument is quoted the
+    /// other way around relative to the conversion direction.
+    Cross {
+        left_id: String,
+        right_id: String,
+        left_reversed: bool,
+        right_reversed: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct MicroEngineBidAskCacheOptions {
+    /// Subset of collaterals that are actually in use by accounts/groups. When set, crosses are
+    /// generated only against these collaterals instead of every known one.
+    pub used_collaterals: Option<HashSet<String>>,
+    /// Track min/max/last spread per instrument in `handle_new`. Off by default to keep the
+    /// tick path free of extra bookkeeping.
+    pub collect_spread_stats: bool,
+    /// Consulted by the `*_or_fetch` lookups on a cache miss. Without it the cache is push-only.
+    pub price_provider: Option<Box<dyn PriceProvider>>,
+    /// Round cross-derived bid/ask to this many digits. Direct and reverse prices keep their
+    /// source precision.
+    pub cross_rate_digits: Option<u32>,
+}
+
 impl MicroEngineBidAskCache {
     pub fn new(
         collaterals: HashSet<String>,
         instruments: Vec<MicroEngineInstrument>,
         cached_prices: Vec<MicroEngineBidask>,
     ) -> (MicroEngineBidAskCache, Vec<CrossCalculationsError>) {
+        Self::new_with_options(
+            collaterals,
+            instruments,
+            cached_prices,
+            MicroEngineBidAskCacheOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        collaterals: HashSet<String>,
+        instruments: Vec<MicroEngineInstrument>,
+        cached_prices: Vec<MicroEngineBidask>,
+        options: MicroEngineBidAskCacheOptions,
+    ) -> (MicroEngineBidAskCache, Vec<CrossCalculationsError>) {
+        let collaterals = match &options.used_collaterals {
+            Some(used_collaterals) => collaterals
+                .intersection(used_collaterals)
+                .cloned()
+                .collect(),
+            None => collaterals,
+        };
+
         let required_crosses =
             generate_required_crosses(&instruments.iter().collect::<Vec<_>>(), collaterals);
 
+        let mut currency_instruments: AHashMap<String, Vec<String>> = AHashMap::new();
+
+        if options.price_provider.is_some() {
+            for instrument in &instruments {
+                for currency in [&instrument.base, &instrument.quote] {
+                    currency_instruments
+                        .entry(currency.clone())
+                        .or_default()
+                        .push(instrument.id.clone());
+                }
+            }
+        }
+
         let (crosses, cross_errors) = CrossCalculationsCrossPairsMatrix::new(
             &required_crosses
                 .iter()
@@ -44,6 +181,14 @@ impl MicroEngineBidAskCache {
             &instruments.iter().collect::<Vec<_>>(),
         );
 
+        let mut instrument_index: PairIndex = AHashMap::new();
+        for instrument in &instruments {
+            instrument_index
+                .entry(instrument.base.clone())
+                .or_default()
+                .insert(instrument.quote.clone(), instrument.id.clone());
+        }
+
         let mut prices = AHashMap::with_capacity(instruments.len());
         let mut base_quote_index = AHashMap::new();
         let mut quote_base_index = AHashMap::new();
@@ -67,16 +212,249 @@ impl MicroEngineBidAskCache {
                 prices,
                 base_quote_index,
                 quote_base_index,
+                cross_legs: index_cross_legs(&crosses),
                 cross_matrix: crosses,
+                instrument_index,
+                required_crosses,
+                spread_stats: options.collect_spread_stats.then(AHashMap::new),
+                price_provider: options.price_provider,
+                currency_instruments,
+                conversion_overrides: AHashMap::new(),
+                cross_rate_digits: options.cross_rate_digits,
+                conversion_fallback: ConversionFallback::default(),
+                fallback_rates: AHashMap::new(),
+                last_resolved: AHashMap::new(),
+                #[cfg(test)]
+                resolutions: Default::default(),
             },
             cross_errors,
         )
     }
 
+    /// Pins `base -> quote` (and its reverse) to `rate`, whatever the live prices are.
+    pub fn set_conversion_override(&mut self, base: &str, quote: &str, rate: f64) {
+        self.conversion_overrides
+            .entry(base.to_string())
+            .or_default()
+            .insert(quote.to_string(), rate);
+    }
+
+    pub fn clear_conversion_override(&mut self, base: &str, quote: &str) {
+        remove_from_override_index(&mut self.conversion_overrides, base, quote);
+    }
+
+    pub fn get_conversion_override(&self, base: &str, quote: &str) -> Option<MicroEngineBidask> {
+        if let Some(rate) = self
+            .conversion_overrides
+            .get(base)
+            .and_then(|x| x.get(quote))
+        {
+            return Some(override_bidask(base, quote, *rate));
+        }
+
+        let rate = self
+            .conversion_overrides
+            .get(quote)
+            .and_then(|x| x.get(base))?;
+
+        override_bidask(quote, base, *rate).try_reverse()
+    }
+
+    /// Switching to `ConversionFallback::LastResolved` records the rates the cached prices
+    /// resolve right away, later ones are recorded as prices update.
+    pub fn set_conversion_fallback(&mut self, conversion_fallback: ConversionFallback) {
+        self.conversion_fallback = conversion_fallback;
+        self.last_resolved.clear();
+
+        if conversion_fallback == ConversionFallback::LastResolved {
+            let ids = self.prices.keys().cloned().collect::<Vec<_>>();
+
+            for id in ids {
+                self.remember_resolved(&id);
+            }
+        }
+    }
+
+    /// Rate used for `base -> quote` (and its reverse) under `ConversionFallback::StaticRate`
+    /// when the price can't be resolved.
+    pub fn set_fallback_rate(&mut self, base: &str, quote: &str, rate: f64) {
+        self.fallback_rates
+            .entry(base.to_string())
+            .or_default()
+            .insert(quote.to_string(), rate);
+    }
+
+    fn get_fallback_price(&self, base: &str, quote: &str) -> Option<MicroEngineBidask> {
+        match self.conversion_fallback {
+            ConversionFallback::Unpriceable => None,
+            ConversionFallback::StaticRate => {
+                if let Some(rate) = self.fallback_rates.get(base).and_then(|x| x.get(quote)) {
+                    return Some(fallback_bidask(base, quote, *rate));
+                }
+
+                let rate = self.fallback_rates.get(quote).and_then(|x| x.get(base))?;
+
+                fallback_bidask(quote, base, *rate).try_reverse()
+            }
+            ConversionFallback::LastResolved => self
+                .last_resolved
+                .get(base)
+                .and_then(|x| x.get(quote))
+                .cloned(),
+        }
+    }
+
+    /// Records both directions of every conversion the price `id` takes part in, directly or
+    /// as a cross leg, as they resolve now.
+    fn remember_resolved(&mut self, id: &str) {
+        let Some(price) = self.prices.get(id) else {
+            return;
+        };
+
+        let mut pairs = vec![(price.base.clone(), price.quote.clone())];
+        pairs.extend(self.cross_legs.get(id).into_iter().flatten().cloned());
+
+        for (base, quote) in pairs {
+            for (base, quote) in [(&base, &quote), (&quote, &base)] {
+                if let Some((bidask, _)) = self.resolve_price_with_source(base, quote) {
+                    self.last_resolved
+                        .entry(base.clone())
+                        .or_default()
+                        .insert(quote.clone(), bidask);
+                }
+            }
+        }
+    }
+
+    /// Ids of the instruments a base -> quote price is resolved from, looked up among all known
+    /// instruments rather than the cached prices. `None` when no instrument path exists.
+    pub(crate) fn conversion_legs(&self, base: &str, quote: &str) -> Option<Vec<String>> {
+        let direct = self.instrument_index.get(base).and_then(|x| x.get(quote));
+        let reverse = self.instrument_index.get(quote).and_then(|x| x.get(base));
+
+        if let Some(id) = direct.or(reverse) {
+            return Some(vec![id.clone()]);
+        }
+
+        let rates = &self.cross_matrix.rates;
+        let (left, right) = rates
+            .get(&(base.to_string(), quote.to_string()))
+            .or_else(|| rates.get(&(quote.to_string(), base.to_string())))?;
+
+        Some(vec![left.0.clone(), right.0.clone()])
+    }
+
+    /// Reassembles a cache around a prebuilt cross matrix, skipping the matrix rebuild. The
+    /// result has no options set and no required crosses, so `prune_unreferenced` only keeps
+    /// the referenced prices. Known instruments are the ones with a price.
+    #[cfg(feature = "snapshot")]
+    pub fn from_parts(
+        prices: AHashMap<String, MicroEngineBidask>,
+        base_quote_index: PairIndex,
+        quote_base_index: PairIndex,
+        cross_matrix: CrossCalculationsCrossPairsMatrix,
+    ) -> Self {
+        Self {
+            prices,
+            instrument_index: base_quote_index.clone(),
+            base_quote_index,
+            quote_base_index,
+            cross_legs: index_cross_legs(&cross_matrix),
+            cross_matrix,
+            required_crosses: vec![],
+            spread_stats: None,
+            price_provider: None,
+            currency_instruments: AHashMap::new(),
+            conversion_overrides: AHashMap::new(),
+            cross_rate_digits: None,
+            conversion_fallback: ConversionFallback::default(),
+            fallback_rates: AHashMap::new(),
+            last_resolved: AHashMap::new(),
+            #[cfg(test)]
+            resolutions: Default::default(),
+        }
+    }
+
+    /// Splits the cache into the parts `from_parts` takes back, for snapshot/restore.
+    #[cfg(feature = "snapshot")]
+    pub fn into_parts(
+        self,
+    ) -> (
+        AHashMap<String, MicroEngineBidask>,
+        PairIndex,
+        PairIndex,
+        CrossCalculationsCrossPairsMatrix,
+    ) {
+        (
+            self.prices,
+            self.base_quote_index,
+            self.quote_base_index,
+            self.cross_matrix,
+        )
+    }
+
+    /// Cached price only. Lookups borrow the cache immutably, so they can run from the tick
+    /// loop and the parallel recalculation, and can't store what a price provider returns; use
+    /// `get_by_id_or_fetch` to pull on a miss.
     pub fn get_by_id(&self, id: &str) -> Option<&MicroEngineBidask> {
         self.prices.get(id)
     }
 
+    /// Like `get_by_id`, but asks the price provider on a miss and caches its answer.
+    pub fn get_by_id_or_fetch(&mut self, id: &str) -> Option<&MicroEngineBidask> {
+        if !self.prices.contains_key(id) {
+            self.fetch(id);
+        }
+
+        self.prices.get(id)
+    }
+
+    /// Like `get_price`, but on a miss fetches every instrument quoting `base` or `quote` that
+    /// isn't cached yet, which covers direct, reverse and cross resolution.
+    pub fn get_price_or_fetch(&mut self, base: &str, quote: &str) -> Option<MicroEngineBidask> {
+        self.fetch_missing(base, quote);
+        self.get_price(base, quote)
+    }
+
+    /// Fetches the uncached instruments needed to resolve base -> quote, if a price provider is
+    /// set and the price can't already be resolved. Returns the ids of the prices it cached.
+    pub fn fetch_missing(&mut self, base: &str, quote: &str) -> Vec<String> {
+        if self.price_provider.is_none() || self.get_price(base, quote).is_some() {
+            return Vec::new();
+        }
+
+        let ids = [base, quote]
+            .into_iter()
+            .filter_map(|x| self.currency_instruments.get(x))
+            .flatten()
+            .filter(|x| !self.prices.contains_key(*x))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // An instrument quoting both currencies is listed twice, it's fetched once
+        ids.into_iter()
+            .filter(|x| !self.prices.contains_key(x) && self.fetch(x))
+            .collect()
+    }
+
+    pub(crate) fn has_price_provider(&self) -> bool {
+        self.price_provider.is_some()
+    }
+
+    /// Number of `get_price_with_source` resolutions so far.
+    #[cfg(test)]
+    pub(crate) fn resolutions(&self) -> usize {
+        self.resolutions.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn fetch(&mut self, id: &str) -> bool {
+        let Some(price) = self.price_provider.as_ref().and_then(|x| x.fetch(id)) else {
+            return false;
+        };
+
+        self.handle_new(&price)
+    }
+
     pub fn get_base_quote(&self, base: &str, quote: &str) -> Option<&MicroEngineBidask> {
         let id = self.base_quote_index.get(base).and_then(|x| x.get(quote))?;
 
@@ -89,35 +467,48 @@ impl MicroEngineBidAskCache {
         self.prices.get(id)
     }
 
+    /// Price of 1 unit of `base` in `quote`, whether it's quoted directly, reversed or crossed.
+    /// Like `get_by_id` it never consults the price provider, see `get_price_or_fetch`.
     pub fn get_price(&self, base: &str, quote: &str) -> Option<MicroEngineBidask> {
-        if base == quote {
-            return Some(MicroEngineBidask::create_blank());
-        }
-        let result = self
-            .get_base_quote(base, quote)
-            .cloned()
-            .or_else(|| self.get_quote_base(base, quote).map(|x| x.reverse()));
-
-        if result.is_none() {
-            let cross = cross_calculations::core::get_cross_rate(
-                base,
-                quote,
-                &self.cross_matrix,
-                self,
-                false,
-            );
+        self.get_price_with_source(base, quote)
+            .map(|(bidask, _)| bidask)
+    }
 
-            if let Ok(cross) = cross {
-                return Some(MicroEngineBidask::from(cross));
-            }
-        }
+    /// Resolves many `(base, quote)` pairs, walking each unique pair only once.
+    pub fn get_prices_batch(&self, pairs: &[(&str, &str)]) -> Vec<Option<MicroEngineBidask>> {
+        let mut resolved: AHashMap<(&str, &str), Option<MicroEngineBidask>> = AHashMap::new();
 
-        result
+        pairs
+            .iter()
+            .map(|(base, quote)| {
+                resolved
+                    .entry((*base, *quote))
+                    .or_insert_with(|| self.get_price(base, quote))
+                    .clone()
+            })
+            .collect()
     }
+    /// Stores the quote. Invalid quotes (see [`MicroEngineBidask::is_valid`]) are skipped and
+    /// `false` is returned.
     #[inline(always)]
-    pub fn handle_new(&mut self, bid_ask: &MicroEngineBidask) {
+    pub fn handle_new(&mut self, bid_ask: &MicroEngineBidask) -> bool {
         use std::collections::hash_map::Entry;
 
+        if !bid_ask.is_valid() {
+            return false;
+        }
+
+        if let Some(spread_stats) = self.spread_stats.as_mut() {
+            let spread = bid_ask.ask - bid_ask.bid;
+
+            match spread_stats.get_mut(&bid_ask.id) {
+                Some(stats) => stats.add_sample(spread),
+                None => {
+                    spread_stats.insert(bid_ask.id.clone(), SpreadStats::new(spread));
+                }
+            }
+        }
+
         match self.prices.entry(bid_ask.id.clone()) {
             Entry::Occupied(mut occ) => {
                 let existed_price = occ.get_mut();
@@ -128,6 +519,7 @@ impl MicroEngineBidAskCache {
                 if existed_price.ask != bid_ask.ask {
                     existed_price.ask = bid_ask.ask;
                 }
+                existed_price.date = bid_ask.date;
             }
             Entry::Vacant(vac) => {
                 vac.insert(bid_ask.clone());
@@ -145,39 +537,249 @@ impl MicroEngineBidAskCache {
                 quote_base.insert(bid_ask.base.clone(), bid_ask.id.clone());
             }
         }
+
+        if self.conversion_fallback == ConversionFallback::LastResolved {
+            self.remember_resolved(&bid_ask.id);
+        }
+
+        true
+    }
+
+    pub fn spread_stats(&self, id: &str) -> Option<SpreadStats> {
+        self.spread_stats.as_ref()?.get(id).copied()
     }
 
     pub fn get_all(&self) -> AHashMap<String, MicroEngineBidask> {
         self.prices.clone()
     }
 
+    /// Borrows every cached price, unlike `get_all`. Order is unspecified.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MicroEngineBidask)> {
+        self.prices.iter().map(|(id, price)| (id.as_str(), price))
+    }
+
+    pub fn prices_count(&self) -> usize {
+        self.prices.len()
+    }
+
     pub fn get_price_with_source(
         &self,
         base: &str,
         quote: &str,
     ) -> Option<(MicroEngineBidask, Option<Vec<String>>)> {
+        #[cfg(test)]
+        self.resolutions
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
         if base == quote {
             return Some((MicroEngineBidask::create_blank(), None));
         }
 
+        // A fallback price reports the instruments the real price will come from, so positions
+        // subscribe to them and reprice once they arrive
+        match self.resolve_price_with_source(base, quote) {
+            Some(resolved) => Some(resolved),
+            None => self
+                .get_fallback_price(base, quote)
+                .map(|x| (x, self.conversion_legs(base, quote))),
+        }
+    }
+
+    fn resolve_price_with_source(
+        &self,
+        base: &str,
+        quote: &str,
+    ) -> Option<(MicroEngineBidask, Option<Vec<String>>)> {
+        if let Some(pinned) = self.get_conversion_override(base, quote) {
+            return Some((pinned, None));
+        }
+
         if let Some(direct) = self.get_base_quote(base, quote) {
             return Some((direct.clone(), None));
         }
 
         if let Some(reverse) = self.get_quote_base(base, quote) {
-            return Some((reverse.reverse().clone(), Some(vec![reverse.id.clone()])));
+            return Some((reverse.try_reverse()?, Some(vec![reverse.id.clone()])));
         }
 
-        let cross =
-            cross_calculations::core::get_cross_rate(base, quote, &self.cross_matrix, self, true);
+        if let Some((cross, sources)) = self.get_cross(base, quote) {
+            return Some((self.round_cross_rate(cross), Some(sources)));
+        }
 
-        if let Ok(cross) = cross {
-            let (left, right) = cross.clone().source.unwrap();
-            return Some((MicroEngineBidask::from(cross), Some(vec![left.0, right.0])));
+        // Crosses are only built towards the collaterals, so the opposite direction is
+        // resolved through the reversed cross
+        if let Some((cross, sources)) = self.get_cross(quote, base) {
+            return Some((self.round_cross_rate(cross.try_reverse()?), Some(sources)));
         }
 
         return None;
     }
+
+    fn round_cross_rate(&self, mut cross: MicroEngineBidask) -> MicroEngineBidask {
+        if let Some(digits) = self.cross_rate_digits {
+            cross.bid = round_float_to_digits(cross.bid, digits as i32);
+            cross.ask = round_float_to_digits(cross.ask, digits as i32);
+        }
+
+        cross
+    }
+
+    /// Cross rate oriented as `base -> quote`, whatever direction the legs were resolved in.
+    fn get_cross(&self, base: &str, quote: &str) -> Option<(MicroEngineBidask, Vec<String>)> {
+        let cross =
+            cross_calculations::core::get_cross_rate(base, quote, &self.cross_matrix, self, true)
+                .ok()?;
+
+        let (left, right) = cross.source.clone()?;
+        let cross = MicroEngineBidask::from(cross);
+
+        let cross = match cross.base == quote && cross.quote == base {
+            true => cross.try_reverse()?,
+            false => cross,
+        };
+
+        Some((cross, vec![left.0, right.0]))
+    }
+
+    /// Removes prices that are neither in `referenced` nor a leg of one of the cross rates
+    /// built at construction. Returns the number of removed prices.
+    pub fn prune_unreferenced(&mut self, referenced: &HashSet<String>) -> usize {
+        let mut keep = referenced.clone();
+
+        for (base, quote) in &self.required_crosses {
+            if let Some((_, Some(sources))) = self.get_price_with_source(base, quote) {
+                keep.extend(sources);
+            }
+        }
+
+        let removed = self
+            .prices
+            .keys()
+            .filter(|id| !keep.contains(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in &removed {
+            let Some(bid_ask) = self.prices.remove(id) else {
+                continue;
+            };
+
+            remove_from_pair_index(
+                &mut self.base_quote_index,
+                &bid_ask.base,
+                &bid_ask.quote,
+                id,
+            );
+            remove_from_pair_index(
+                &mut self.quote_base_index,
+                &bid_ask.quote,
+                &bid_ask.base,
+                id,
+            );
+
+            if let Some(spread_stats) = self.spread_stats.as_mut() {
+                spread_stats.remove(id);
+            }
+        }
+
+        removed.len()
+    }
+
+    /// Describes which cached instruments are used to price base -> quote.
+    pub fn get_price_path(&self, base: &str, quote: &str) -> Option<PriceResolution> {
+        if base == quote {
+            return Some(PriceResolution::Identity);
+        }
+
+        let (bidask, sources) = self.get_price_with_source(base, quote)?;
+
+        match sources.as_deref() {
+            None => Some(PriceResolution::Direct { id: bidask.id }),
+            Some([id]) => Some(PriceResolution::Reverse { id: id.clone() }),
+            Some([left_id, right_id]) => {
+                let left = self.get_by_id(left_id)?;
+                let right = self.get_by_id(right_id)?;
+
+                Some(PriceResolution::Cross {
+                    left_id: left_id.clone(),
+                    right_id: right_id.clone(),
+                    left_reversed: left.base != base,
+                    right_reversed: right.quote != quote,
+                })
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+fn remove_from_pair_index(
+    index: &mut AHashMap<String, AHashMap<String, String>>,
+    key: &str,
+    inner_key: &str,
+    id: &str,
+) {
+    if let Some(inner) = index.get_mut(key) {
+        if inner.get(inner_key).map(|x| x.as_str()) == Some(id) {
+            inner.remove(inner_key);
+        }
+
+        if inner.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+fn index_cross_legs(
+    cross_matrix: &CrossCalculationsCrossPairsMatrix,
+) -> AHashMap<String, Vec<(String, String)>> {
+    let mut cross_legs: AHashMap<String, Vec<(String, String)>> = AHashMap::new();
+
+    for ((base, quote), (left, right)) in &cross_matrix.rates {
+        for leg in [left, right] {
+            cross_legs
+                .entry(leg.0.clone())
+                .or_default()
+                .push((base.clone(), quote.clone()));
+        }
+    }
+
+    cross_legs
+}
+
+fn remove_from_override_index(
+    index: &mut AHashMap<String, AHashMap<String, f64>>,
+    base: &str,
+    quote: &str,
+) {
+    if let Some(inner) = index.get_mut(base) {
+        inner.remove(quote);
+
+        if inner.is_empty() {
+            index.remove(base);
+        }
+    }
+}
+
+fn override_bidask(base: &str, quote: &str, rate: f64) -> MicroEngineBidask {
+    MicroEngineBidask {
+        id: format!("OVERRIDE-{}{}", base, quote),
+        bid: rate,
+        ask: rate,
+        base: base.to_string(),
+        quote: quote.to_string(),
+        date: None,
+    }
+}
+
+fn fallback_bidask(base: &str, quote: &str, rate: f64) -> MicroEngineBidask {
+    MicroEngineBidask {
+        id: format!("FALLBACK-{}{}", base, quote),
+        bid: rate,
+        ask: rate,
+        base: base.to_string(),
+        quote: quote.to_string(),
+        date: None,
+    }
 }
 
 fn generate_required_crosses(
@@ -233,3 +835,451 @@ impl CrossCalculationsSourceInstrument for MicroEngineInstrument {
         &self.quote
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::bidask::{
+        ConversionFallback, MicroEngineBidAskCache, MicroEngineBidAskCacheOptions,
+        MicroEngineInstrument, PriceProvider, PriceResolution, dto::MicroEngineBidask,
+    };
+
+    fn instrument(id: &str, base: &str, quote: &str) -> MicroEngineInstrument {
+        MicroEngineInstrument {
+            id: id.to_string(),
+            base: base.to_string(),
+            quote: quote.to_string(),
+        }
+    }
+
+    fn bidask(id: &str, bid: f64, ask: f64, base: &str, quote: &str) -> MicroEngineBidask {
+        MicroEngineBidask {
+            id: id.to_string(),
+            bid,
+            ask,
+            base: base.to_string(),
+            quote: quote.to_string(),
+            date: None,
+        }
+    }
+
+    fn instruments() -> Vec<MicroEngineInstrument> {
+        vec![
+            instrument("EURUSD", "EUR", "USD"),
+            instrument("USDCAD", "USD", "CAD"),
+        ]
+    }
+
+    fn prices() -> Vec<MicroEngineBidask> {
+        vec![
+            bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD"),
+            bidask("USDCAD", 1.35000, 1.35020, "USD", "CAD"),
+        ]
+    }
+
+    #[test]
+    fn test_restricted_collaterals_skip_unused_crosses() {
+        let collaterals = HashSet::from(["USD".to_string(), "EUR".to_string()]);
+
+        let (full_cache, _) =
+            MicroEngineBidAskCache::new(collaterals.clone(), instruments(), prices());
+
+        let (restricted_cache, _) = MicroEngineBidAskCache::new_with_options(
+            collaterals,
+            instruments(),
+            prices(),
+            MicroEngineBidAskCacheOptions {
+                used_collaterals: Some(HashSet::from(["USD".to_string()])),
+                ..Default::default()
+            },
+        );
+
+        // CAD -> USD is available through USDCAD in both caches
+        assert!(full_cache.get_price("CAD", "USD").is_some());
+        assert!(restricted_cache.get_price("CAD", "USD").is_some());
+
+        // CAD -> EUR is a cross only required for the EUR collateral
+        assert!(full_cache.get_price("CAD", "EUR").is_some());
+        assert!(restricted_cache.get_price("CAD", "EUR").is_none());
+    }
+
+    #[test]
+    fn test_spread_stats_track_min_max_last() {
+        let (mut cache, _) = MicroEngineBidAskCache::new_with_options(
+            HashSet::from(["USD".to_string()]),
+            instruments(),
+            prices(),
+            MicroEngineBidAskCacheOptions {
+                collect_spread_stats: true,
+                ..Default::default()
+            },
+        );
+
+        cache.handle_new(&bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD"));
+        cache.handle_new(&bidask("EURUSD", 1.08000, 1.08050, "EUR", "USD"));
+        cache.handle_new(&bidask("EURUSD", 1.08000, 1.08010, "EUR", "USD"));
+
+        let stats = cache.spread_stats("EURUSD").unwrap();
+
+        assert_eq!(format!("{:.5}", stats.min), "0.00010");
+        assert_eq!(format!("{:.5}", stats.max), "0.00050");
+        assert_eq!(format!("{:.5}", stats.last), "0.00010");
+        assert_eq!(stats.sample_count, 3);
+        assert!(cache.spread_stats("USDCAD").is_none());
+    }
+
+    #[test]
+    fn test_spread_stats_disabled_by_default() {
+        let collaterals = HashSet::from(["USD".to_string()]);
+        let (mut cache, _) = MicroEngineBidAskCache::new(collaterals, instruments(), prices());
+
+        cache.handle_new(&bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD"));
+
+        assert!(cache.spread_stats("EURUSD").is_none());
+    }
+
+    #[test]
+    fn test_get_price_matches_get_price_with_source() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string(), "CAD".to_string()]),
+            instruments(),
+            prices(),
+        );
+
+        // CADUSD is reverse only, EURCAD is cross only
+        for (base, quote) in [("CAD", "USD"), ("EUR", "CAD")] {
+            let price = cache.get_price(base, quote).unwrap();
+            let (price_with_source, source) = cache.get_price_with_source(base, quote).unwrap();
+
+            assert!(source.is_some());
+            assert_eq!(price.bid, price_with_source.bid);
+            assert_eq!(price.ask, price_with_source.ask);
+        }
+    }
+
+    #[test]
+    fn test_invalid_quotes_are_rejected() {
+        let collaterals = HashSet::from(["USD".to_string()]);
+        let (mut cache, _) = MicroEngineBidAskCache::new(collaterals, instruments(), prices());
+
+        assert!(MicroEngineBidask::create_blank().is_valid());
+
+        let crossed = bidask("EURUSD", 1.08030, 1.08020, "EUR", "USD");
+        let zero = bidask("EURUSD", 0.0, 1.08020, "EUR", "USD");
+        let nan = bidask("EURUSD", f64::NAN, 1.08020, "EUR", "USD");
+        let normal = bidask("EURUSD", 1.09000, 1.09020, "EUR", "USD");
+
+        assert!(!cache.handle_new(&crossed));
+        assert!(!cache.handle_new(&zero));
+        assert!(!cache.handle_new(&nan));
+        assert_eq!(cache.get_by_id("EURUSD").unwrap().bid, 1.08000);
+
+        assert!(cache.handle_new(&normal));
+        assert_eq!(cache.get_by_id("EURUSD").unwrap().bid, 1.09000);
+    }
+
+    #[test]
+    fn test_get_price_path() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string(), "CAD".to_string()]),
+            instruments(),
+            prices(),
+        );
+
+        assert_eq!(
+            cache.get_price_path("EUR", "USD"),
+            Some(PriceResolution::Direct {
+                id: "EURUSD".to_string()
+            })
+        );
+        assert_eq!(
+            cache.get_price_path("USD", "EUR"),
+            Some(PriceResolution::Reverse {
+                id: "EURUSD".to_string()
+            })
+        );
+        assert_eq!(
+            cache.get_price_path("EUR", "CAD"),
+            Some(PriceResolution::Cross {
+                left_id: "EURUSD".to_string(),
+                right_id: "USDCAD".to_string(),
+                left_reversed: false,
+                right_reversed: false,
+            })
+        );
+        assert_eq!(
+            cache.get_price_path("USD", "USD"),
+            Some(PriceResolution::Identity)
+        );
+    }
+
+    #[test]
+    fn test_prune_unreferenced_keeps_referenced_and_cross_legs() {
+        let mut cached = prices();
+        cached.push(bidask("XAUUSD", 2350.10, 2350.40, "XAU", "USD"));
+
+        let (mut cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string(), "CAD".to_string()]),
+            instruments(),
+            cached,
+        );
+
+        let removed = cache.prune_unreferenced(&HashSet::from(["EURUSD".to_string()]));
+
+        assert_eq!(removed, 1);
+        assert!(cache.get_by_id("XAUUSD").is_none());
+        assert!(cache.get_price("XAU", "USD").is_none());
+
+        // USDCAD is only kept as a leg of the EUR -> CAD cross
+        assert!(cache.get_by_id("EURUSD").is_some());
+        assert!(cache.get_by_id("USDCAD").is_some());
+        assert!(cache.get_price("EUR", "CAD").is_some());
+    }
+
+    #[derive(Debug)]
+    struct StubPriceProvider;
+
+    impl PriceProvider for StubPriceProvider {
+        fn fetch(&self, id: &str) -> Option<MicroEngineBidask> {
+            match id {
+                "USDCAD" => Some(bidask("USDCAD", 1.35000, 1.35020, "USD", "CAD")),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_price_provider_resolves_missing_price() {
+        let (mut cache, _) = MicroEngineBidAskCache::new_with_options(
+            HashSet::from(["USD".to_string()]),
+            instruments(),
+            vec![bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD")],
+            MicroEngineBidAskCacheOptions {
+                price_provider: Some(Box::new(StubPriceProvider)),
+                ..Default::default()
+            },
+        );
+
+        assert!(cache.get_by_id("USDCAD").is_none());
+        assert_eq!(cache.get_by_id_or_fetch("USDCAD").unwrap().bid, 1.35000);
+
+        // cached after the first fetch
+        assert!(cache.get_by_id("USDCAD").is_some());
+        assert!(cache.get_price_or_fetch("CAD", "USD").is_some());
+        assert!(cache.get_by_id_or_fetch("GBPUSD").is_none());
+    }
+
+    #[test]
+    fn test_without_price_provider_cache_is_push_only() {
+        let (mut cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            instruments(),
+            vec![bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD")],
+        );
+
+        assert!(cache.get_by_id_or_fetch("USDCAD").is_none());
+        assert!(cache.get_price_or_fetch("CAD", "USD").is_none());
+    }
+
+    #[test]
+    fn test_get_prices_batch_resolves_unique_pairs_once() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string(), "CAD".to_string()]),
+            instruments(),
+            prices(),
+        );
+
+        let result = cache.get_prices_batch(&[
+            ("CAD", "USD"),
+            ("EUR", "CAD"),
+            ("CAD", "USD"),
+            ("EUR", "CAD"),
+            ("CAD", "USD"),
+        ]);
+
+        assert_eq!(result.len(), 5);
+        assert!(result.iter().all(|x| x.is_some()));
+        assert_eq!(
+            result[0].as_ref().unwrap().bid,
+            result[2].as_ref().unwrap().bid
+        );
+        assert_eq!(
+            cache.resolutions.load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[test]
+    fn test_cross_resolved_in_reverse_direction() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["CAD".to_string()]),
+            instruments(),
+            prices(),
+        );
+
+        // EUR -> CAD is the cross built for the CAD collateral
+        let naive = cache.get_price("EUR", "CAD").unwrap();
+        assert_eq!(naive.base, "EUR");
+        assert_eq!(naive.quote, "CAD");
+
+        // CAD -> EUR has the quote on the wrong side of that cross
+        let reversed = cache.get_price("CAD", "EUR").unwrap();
+        assert_eq!(reversed.base, "CAD");
+        assert_eq!(reversed.quote, "EUR");
+        assert_eq!(
+            format!("{:.10}", reversed.bid),
+            format!("{:.10}", 1.0 / naive.ask)
+        );
+        assert_eq!(
+            format!("{:.10}", reversed.ask),
+            format!("{:.10}", 1.0 / naive.bid)
+        );
+        assert!(reversed.bid <= reversed.ask);
+    }
+
+    #[test]
+    fn test_cross_rate_digits() {
+        let (raw_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["CAD".to_string()]),
+            instruments(),
+            vec![
+                bidask("EURUSD", 1.081234, 1.081456, "EUR", "USD"),
+                bidask("USDCAD", 1.35000, 1.35020, "USD", "CAD"),
+            ],
+        );
+        let (cache, _) = MicroEngineBidAskCache::new_with_options(
+            HashSet::from(["CAD".to_string()]),
+            instruments(),
+            vec![
+                bidask("EURUSD", 1.081234, 1.081456, "EUR", "USD"),
+                bidask("USDCAD", 1.35000, 1.35020, "USD", "CAD"),
+            ],
+            MicroEngineBidAskCacheOptions {
+                cross_rate_digits: Some(5),
+                ..Default::default()
+            },
+        );
+
+        let raw = raw_cache.get_price("EUR", "CAD").unwrap();
+        let cross = cache.get_price("EUR", "CAD").unwrap();
+
+        assert_eq!(cross.bid, (raw.bid * 100000.0).round() / 100000.0);
+        assert_eq!(cross.ask, (raw.ask * 100000.0).round() / 100000.0);
+        assert_ne!(cross.bid, raw.bid);
+
+        // Direct prices keep the source precision
+        assert_eq!(cache.get_price("EUR", "USD").unwrap().bid, 1.081234);
+    }
+
+    #[test]
+    fn test_zero_price_gives_no_reverse_conversion() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            instruments(),
+            vec![
+                bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD"),
+                bidask("USDCAD", 0.0, 1.35020, "USD", "CAD"),
+            ],
+        );
+
+        assert!(cache.get_price("CAD", "USD").is_none());
+        assert!(cache.get_price("USD", "CAD").is_some());
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_from_parts_resolves_crosses() {
+        let (cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["CAD".to_string()]),
+            instruments(),
+            prices(),
+        );
+        let expected = cache.get_price("EUR", "CAD").unwrap();
+
+        let (prices, base_quote_index, quote_base_index, cross_matrix) = cache.into_parts();
+        let restored = MicroEngineBidAskCache::from_parts(
+            prices,
+            base_quote_index,
+            quote_base_index,
+            cross_matrix,
+        );
+
+        let cross = restored.get_price("EUR", "CAD").unwrap();
+        assert_eq!(cross.bid, expected.bid);
+        assert_eq!(cross.ask, expected.ask);
+        assert_eq!(restored.get_price("EUR", "USD").unwrap().bid, 1.08000);
+    }
+
+    #[test]
+    fn test_iter_yields_every_price_once() {
+        let collaterals = HashSet::from(["USD".to_string()]);
+        let (mut cache, _) = MicroEngineBidAskCache::new(collaterals, instruments(), prices());
+        cache.handle_new(&bidask("GBPUSD", 1.27000, 1.27020, "GBP", "USD"));
+
+        let mut ids = cache.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        assert_eq!(ids, vec!["EURUSD", "GBPUSD", "USDCAD"]);
+        assert!(cache.iter().all(|(id, price)| price.id == id));
+    }
+
+    #[test]
+    fn test_conversion_fallback() {
+        let collaterals = HashSet::from(["USD".to_string()]);
+        let (mut cache, _) = MicroEngineBidAskCache::new(collaterals, instruments(), prices());
+
+        // No CHF instrument, so there's nothing to cross through
+        assert!(cache.get_price("CHF", "USD").is_none());
+
+        cache.set_fallback_rate("CHF", "USD", 1.1);
+        assert!(cache.get_price("CHF", "USD").is_none());
+
+        cache.set_conversion_fallback(ConversionFallback::StaticRate);
+        let fallback = cache.get_price("CHF", "USD").unwrap();
+        assert_eq!((fallback.bid, fallback.ask), (1.1, 1.1));
+        let reversed = cache.get_price("USD", "CHF").unwrap();
+        assert_eq!(format!("{:.5}", reversed.bid), "0.90909");
+
+        cache.set_conversion_fallback(ConversionFallback::LastResolved);
+        let resolved = cache.get_price("CAD", "USD").unwrap();
+        cache.prune_unreferenced(&HashSet::new());
+        assert_eq!(cache.prices_count(), 0);
+
+        let fallback = cache.get_price("CAD", "USD").unwrap();
+        assert_eq!((fallback.bid, fallback.ask), (resolved.bid, resolved.ask));
+        assert!(cache.get_price("CHF", "USD").is_none());
+
+        // Rates are recorded as prices update
+        cache.handle_new(&bidask("USDCAD", 1.40000, 1.40000, "USD", "CAD"));
+        cache.prune_unreferenced(&HashSet::new());
+
+        let fallback = cache.get_price("CAD", "USD").unwrap();
+        assert_eq!(format!("{:.5}", fallback.bid), "0.71429");
+    }
+
+    #[test]
+    fn test_fallback_price_reports_conversion_legs() {
+        let collaterals = HashSet::from(["CAD".to_string()]);
+        let (mut cache, _) = MicroEngineBidAskCache::new(
+            collaterals,
+            instruments(),
+            vec![bidask("EURUSD", 1.08000, 1.08020, "EUR", "USD")],
+        );
+        cache.set_fallback_rate("EUR", "CAD", 1.46);
+        cache.set_conversion_fallback(ConversionFallback::StaticRate);
+
+        let (fallback, sources) = cache.get_price_with_source("EUR", "CAD").unwrap();
+        assert_eq!(fallback.bid, 1.46);
+        assert_eq!(
+            sources,
+            Some(vec!["EURUSD".to_string(), "USDCAD".to_string()])
+        );
+
+        cache.handle_new(&bidask("USDCAD", 1.35000, 1.35020, "USD", "CAD"));
+        assert_eq!(
+            format!("{:.5}", cache.get_price("EUR", "CAD").unwrap().bid),
+            "1.45800"
+        );
+    }
+}
@@ -1,19 +1,29 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use ahash::AHashSet;
+use ahash::{AHashMap, AHashSet};
+use chrono::{DateTime, Utc, Weekday};
 use cross_calculations::core::CrossCalculationsError;
 
 use crate::{
     accounts::{
-        account::{MicroEngineAccount, MicroEngineAccountCalculationUpdate},
-        account_cache::MicroEngineAccountCache,
+        account::{
+            MicroEngineAccount, MicroEngineAccountCalculationUpdate,
+            calculate_specific_instrument_margin_and_gross_pl,
+        },
+        account_cache::{MicroEngineAccountCache, apply_leverage_policy},
+    },
+    bidask::{
+        MicroEngineBidAskCache, MicroEngineBidAskCacheOptions, MicroEngineInstrument,
+        dto::MicroEngineBidask,
     },
-    bidask::{MicroEngineBidAskCache, MicroEngineInstrument, dto::MicroEngineBidask},
     positions::{
         position::MicroEnginePosition,
-        positions_cache::{MicroEnginePositionCache, MicroEnginePositionCalculationUpdate},
+        positions_cache::{
+            MicroEnginePositionCache, MicroEnginePositionCalculationUpdate,
+            apply_price_with_settings, recalculate_position_from_cache,
+        },
     },
-    settings::{MicroEngineTradingGroupSettings, TradingSettingsCache},
+    settings::{MicroEngineTradingGroupSettings, TradingSettingsCache, UnknownGroupPolicy},
 };
 
 pub mod accounts;
@@ -34,6 +44,10 @@ pub struct MicroEngine {
     pub settings_cache: TradingSettingsCache,
     pub bidask_cache: MicroEngineBidAskCache,
     updated_assets: AHashSet<String>,
+    changed_only_epsilon: Option<f64>,
+    deterministic_order: bool,
+    triple_swap_weekday: Weekday,
+    frozen: bool,
 }
 impl MicroEngine {
     pub async fn initialize(
@@ -43,32 +57,301 @@ impl MicroEngine {
         collaterals: HashSet<String>,
         instruments: Vec<MicroEngineInstrument>,
         cached_prices: Vec<MicroEngineBidask>,
-    ) -> (Self, Vec<CrossCalculationsError>) {
+    ) -> (Self, Vec<CrossCalculationsError>, Vec<MicroEngineError>) {
+        Self::initialize_with_options(
+            accounts,
+            positions,
+            settings,
+            collaterals,
+            instruments,
+            cached_prices,
+            MicroEngineBidAskCacheOptions::default(),
+        )
+        .await
+    }
+
+    pub async fn initialize_with_options(
+        accounts: Vec<impl Into<MicroEngineAccount>>,
+        positions: Vec<impl Into<MicroEnginePosition>>,
+        settings: Vec<impl Into<MicroEngineTradingGroupSettings>>,
+        collaterals: HashSet<String>,
+        instruments: Vec<MicroEngineInstrument>,
+        cached_prices: Vec<MicroEngineBidask>,
+        bidask_options: MicroEngineBidAskCacheOptions,
+    ) -> (Self, Vec<CrossCalculationsError>, Vec<MicroEngineError>) {
         let accounts_cache = MicroEngineAccountCache::new(accounts);
-        let (bidask_cache, bidask_errors) =
-            MicroEngineBidAskCache::new(collaterals, instruments, cached_prices);
+        let (bidask_cache, bidask_errors) = MicroEngineBidAskCache::new_with_options(
+            collaterals,
+            instruments,
+            cached_prices,
+            bidask_options,
+        );
+        let settings_cache = TradingSettingsCache::new(settings, &accounts_cache);
+        let (positions_cache, positions_errors) =
+            MicroEnginePositionCache::new(&bidask_cache, &settings_cache, positions);
 
         let mut cache = Self {
-            positions_cache: MicroEnginePositionCache::new(&bidask_cache, positions),
-            settings_cache: TradingSettingsCache::new(settings, &accounts_cache),
+            positions_cache,
+            settings_cache,
             accounts: accounts_cache,
             bidask_cache: bidask_cache,
             updated_assets: AHashSet::new(),
+            changed_only_epsilon: None,
+            deterministic_order: false,
+            triple_swap_weekday: Weekday::Wed,
+            frozen: false,
         };
 
         cache.recalculate_all().await;
 
-        (cache, bidask_errors)
+        (cache, bidask_errors, positions_errors)
+    }
+
+    /// Same as [`MicroEngine::initialize`], additionally running [`MicroEngine::validate`]
+    /// on the freshly built engine.
+    pub async fn initialize_and_validate(
+        accounts: Vec<impl Into<MicroEngineAccount>>,
+        positions: Vec<impl Into<MicroEnginePosition>>,
+        settings: Vec<impl Into<MicroEngineTradingGroupSettings>>,
+        collaterals: HashSet<String>,
+        instruments: Vec<MicroEngineInstrument>,
+        cached_prices: Vec<MicroEngineBidask>,
+    ) -> (
+        Self,
+        Vec<CrossCalculationsError>,
+        Vec<MicroEngineError>,
+        Vec<ConfigIssue>,
+    ) {
+        let (engine, bidask_errors, positions_errors) = Self::initialize(
+            accounts,
+            positions,
+            settings,
+            collaterals,
+            instruments,
+            cached_prices,
+        )
+        .await;
+
+        let issues = engine.validate().await;
+
+        (engine, bidask_errors, positions_errors, issues)
+    }
+
+    /// Reports configuration gaps that would otherwise silently drop accounts or positions
+    /// from calculations.
+    pub async fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = vec![];
+
+        let mut accounts = self.accounts.get_all_accounts();
+        accounts.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+        for account in accounts {
+            if !self
+                .settings_cache
+                .groups
+                .contains_key(&account.trading_group)
+            {
+                issues.push(ConfigIssue::MissingTradingGroup {
+                    account_id: account.id.clone(),
+                    trading_group: account.trading_group.clone(),
+                });
+            }
+        }
+
+        let mut positions = self.positions_cache.get_all_positions();
+        positions.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+        for position in positions {
+            let Some(settings) = self.settings_cache.resolve_by_account(&position.account_id)
+            else {
+                continue;
+            };
+
+            if !settings.instruments.contains_key(&position.asset_pair) {
+                issues.push(ConfigIssue::MissingInstrumentSettings {
+                    position_id: position.id.clone(),
+                    trading_group: settings.id.clone(),
+                    asset_pair: position.asset_pair.clone(),
+                });
+            }
+        }
+
+        issues
+    }
+
+    /// Ids of positions whose collateral is missing from their group's `collaterals`, sorted.
+    /// Their P&L is rounded to a default 2 digits. Positions without resolvable settings are
+    /// left out.
+    pub async fn validate_collaterals(&self) -> Vec<String> {
+        let mut ids = self
+            .positions_cache
+            .get_all_positions()
+            .into_iter()
+            .filter(|x| {
+                self.settings_cache
+                    .resolve_by_account(&x.account_id)
+                    .is_some_and(|settings| !settings.collaterals.contains_key(&x.collateral))
+            })
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids
+    }
+
+    /// When set, `recalculate_accordint_to_updates` only returns account updates whose equity,
+    /// margin or margin level moved by more than the given epsilon.
+    pub async fn set_changed_only_epsilon(&mut self, epsilon: Option<f64>) {
+        self.changed_only_epsilon = epsilon;
+    }
+
+    /// Walk positions sorted by id on full recalculations, for reproducible runs. Off by default
+    /// as it costs a sort.
+    pub async fn set_deterministic_order(&mut self, deterministic_order: bool) {
+        self.deterministic_order = deterministic_order;
+    }
+
+    /// Kill-switch for feed outages: while frozen incoming prices are dropped and
+    /// `recalculate_accordint_to_updates` reports nothing, so P&L stays at the last known values.
+    pub async fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub async fn unfreeze(&mut self) {
+        self.frozen = false;
+    }
+
+    pub async fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// What recalculation does with accounts whose trading group has no settings.
+    pub async fn set_unknown_group_policy(&mut self, policy: UnknownGroupPolicy) {
+        self.accounts.set_unknown_group_policy(policy);
+    }
+
+    /// Weekday whose rollover is charged three times by `accrue_swaps`. Wednesday by default.
+    pub async fn set_triple_swap_weekday(&mut self, weekday: Weekday) {
+        self.triple_swap_weekday = weekday;
+    }
+
+    /// Accrues swaps for the rollovers since each position's last accrual (or open time) and
+    /// recalculates the affected accounts. Positions without a rate for their instrument are
+    /// left untouched.
+    pub async fn accrue_swaps(
+        &mut self,
+        now: DateTime<Utc>,
+        swap_rate_per_lot: &HashMap<String, f64>,
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        let affected_accounts =
+            self.positions_cache
+                .accrue_swaps(now, swap_rate_per_lot, self.triple_swap_weekday);
+
+        let affected_accounts = affected_accounts
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+
+        self.accounts.recalculate_accounts_data(
+            &self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+            affected_accounts.as_slice(),
+        )
     }
 
-    pub async fn handle_new_price(&mut self, new_bidask: Vec<MicroEngineBidask>) {
-        for bidask in new_bidask {
+    /// Returns the number of rejected (invalid) quotes. While frozen quotes are dropped without
+    /// being validated, so none is counted as rejected.
+    /// When an id repeats within the batch only its newest valid quote by `date` is applied,
+    /// the later one in the batch on equal dates.
+    pub async fn handle_new_price(&mut self, new_bidask: Vec<MicroEngineBidask>) -> usize {
+        if self.frozen {
+            return 0;
+        }
+
+        let mut newest: AHashMap<&str, usize> = AHashMap::with_capacity(new_bidask.len());
+        for (index, bidask) in new_bidask.iter().enumerate() {
+            if !bidask.is_valid() {
+                continue;
+            }
+
+            newest
+                .entry(bidask.id.as_str())
+                .and_modify(|x| {
+                    if bidask.date >= new_bidask[*x].date {
+                        *x = index;
+                    }
+                })
+                .or_insert(index);
+        }
+        let newest = newest.into_values().collect::<AHashSet<_>>();
+
+        let mut rejected = 0;
+
+        for (index, bidask) in new_bidask.iter().enumerate() {
+            if bidask.is_valid() && !newest.contains(&index) {
+                continue;
+            }
+
+            if !self.bidask_cache.handle_new(bidask) {
+                rejected += 1;
+                continue;
+            }
+
             if !self.updated_assets.contains(&bidask.id) {
                 self.updated_assets.insert(bidask.id.clone());
             }
+        }
+
+        rejected
+    }
+
+    /// Latency fast path: applies the price and recalculates only the given account. The price
+    /// is still marked as updated, so the next `recalculate_accordint_to_updates` catches up the
+    /// other accounts on the instrument. Returns `None` and drops the price while frozen.
+    pub async fn handle_price_and_recalc_account(
+        &mut self,
+        bidask: MicroEngineBidask,
+        account_id: &str,
+    ) -> Option<MicroEngineAccountCalculationUpdate> {
+        if self.frozen || !self.bidask_cache.handle_new(&bidask) {
+            return None;
+        }
 
-            self.bidask_cache.handle_new(&bidask);
+        if !self.updated_assets.contains(&bidask.id) {
+            self.updated_assets.insert(bidask.id.clone());
         }
+
+        self.positions_cache.recalculate_account_positions_pl(
+            account_id,
+            &bidask.id,
+            &self.bidask_cache,
+            &self.settings_cache,
+        );
+
+        self.accounts.recalculate_account_data(
+            &self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+            account_id,
+        )
+    }
+
+    /// Simulation only: multiplies every cached bid/ask by `factor` and marks the instruments
+    /// as updated, so the next `recalculate_accordint_to_updates` reflects the shock.
+    pub async fn apply_price_shock(&mut self, factor: f64) {
+        let shocked = self
+            .bidask_cache
+            .get_all()
+            .into_values()
+            .map(|mut bidask| {
+                bidask.bid *= factor;
+                bidask.ask *= factor;
+                bidask
+            })
+            .collect();
+
+        self.handle_new_price(shocked).await;
     }
 
     pub async fn trading_settings_changed(
@@ -81,6 +364,146 @@ impl MicroEngine {
             .insert_or_replace_settings(settings.clone());
     }
 
+    /// Reprices the positions of every account in `group_id` with the current settings and
+    /// returns the accounts' recalculated figures.
+    pub async fn recalculate_group(
+        &mut self,
+        group_id: &str,
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        let account_ids = self.settings_cache.get_group_accounts(group_id);
+
+        self.reprice_accounts(account_ids)
+    }
+
+    /// Replaces the settings of many groups, e.g. on a config reload, and reprices every account
+    /// of those groups once. Returns the accounts' recalculated figures.
+    pub async fn apply_settings_batch(
+        &mut self,
+        settings: Vec<MicroEngineTradingGroupSettings>,
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        let mut account_ids = vec![];
+
+        for settings in settings {
+            account_ids.extend(self.settings_cache.insert_or_replace_settings(settings));
+        }
+
+        self.reprice_accounts(account_ids)
+    }
+
+    /// Reprices the accounts' positions with the current settings and recalculates each account
+    /// once, in id order.
+    fn reprice_accounts(
+        &mut self,
+        mut account_ids: Vec<String>,
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        account_ids.sort_unstable();
+        account_ids.dedup();
+
+        for account_id in &account_ids {
+            self.positions_cache.recalculate_account_positions(
+                account_id,
+                &mut self.bidask_cache,
+                &self.settings_cache,
+            );
+        }
+
+        let account_ids = account_ids.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+        self.accounts.recalculate_accounts_data(
+            &self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+            &account_ids,
+        )
+    }
+
+    /// Moves the account to `group_id` and reprices its positions under the new group.
+    pub async fn move_account_to_group(
+        &mut self,
+        account_id: &str,
+        group_id: &str,
+    ) -> Result<MicroEngineAccountCalculationUpdate, MicroEngineError> {
+        let group = self.settings_cache.get_group(group_id).ok_or(
+            MicroEngineError::AccountSettingsNotFound(group_id.to_string()),
+        )?;
+
+        let mut account = self
+            .accounts
+            .get_account(account_id)
+            .ok_or(MicroEngineError::AccountNotFound)?
+            .clone();
+        account.trading_group = group_id.to_string();
+
+        // Checked before anything is moved, so a rejected account stays in its old group
+        apply_leverage_policy(&mut account, group)?;
+
+        self.settings_cache.account_updated(&account);
+        self.positions_cache.recalculate_account_positions(
+            account_id,
+            &mut self.bidask_cache,
+            &self.settings_cache,
+        );
+
+        self.accounts.insert_or_update_account(
+            account,
+            &mut self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+        )
+    }
+
+    /// What-if figures for the account under `settings`. Copies of its positions are repriced
+    /// against the cached prices, so neither the account nor its positions are changed.
+    pub async fn preview_account_with_settings(
+        &self,
+        account_id: &str,
+        settings: &MicroEngineTradingGroupSettings,
+    ) -> Option<MicroEngineAccountCalculationUpdate> {
+        let mut account = self.accounts.get_account(account_id)?.clone();
+        let raw_spread = self.settings_cache.is_raw_spread(account_id);
+
+        let positions = self
+            .positions_cache
+            .get_account_positions(account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                let mut position = x.clone();
+
+                // Same path as a tick, so positions the tick path would skip keep their figures
+                if let Some(price) = self.bidask_cache.get_by_id(&position.asset_pair) {
+                    apply_price_with_settings(
+                        &mut position,
+                        price,
+                        &self.bidask_cache,
+                        settings,
+                        raw_spread,
+                    );
+                }
+
+                position
+            })
+            .collect::<Vec<_>>();
+        let positions = positions.iter().collect::<Vec<_>>();
+
+        Some(account.recalculate_account_data(&positions, settings, &self.bidask_cache))
+    }
+
+    /// Drops a group's settings. Its accounts are then handled by the unknown group policy, see
+    /// `set_unknown_group_policy`.
+    pub async fn trading_settings_removed(
+        &mut self,
+        group_id: &str,
+    ) -> Option<MicroEngineTradingGroupSettings> {
+        self.settings_cache.remove_settings(group_id)
+    }
+
+    /// Ids of accounts whose trading group has no settings, i.e. left out of recalculation.
+    pub async fn accounts_without_settings(&self) -> Vec<String> {
+        self.accounts
+            .accounts_without_settings(&self.settings_cache)
+    }
+
     pub async fn insert_or_update_account(
         &mut self,
         account: impl Into<MicroEngineAccount>,
@@ -91,6 +514,7 @@ impl MicroEngine {
             account,
             &mut self.settings_cache,
             &self.positions_cache,
+            &self.bidask_cache,
         )
     }
 
@@ -98,30 +522,146 @@ impl MicroEngine {
         &mut self,
         position: impl Into<MicroEnginePosition>,
     ) -> Result<MicroEngineAccountCalculationUpdate, MicroEngineError> {
-        let position: MicroEnginePosition = position.into();
+        let position = self.prepare_position(position.into())?;
+
+        self.positions_cache.add_position(position.clone());
+
+        self.accounts
+            .recalculate_account_data(
+                &self.settings_cache,
+                &self.positions_cache,
+                &self.bidask_cache,
+                &position.account_id,
+            )
+            .ok_or(MicroEngineError::AccountNotFound)
+    }
+
+    /// Replaces the positions of `account_id` with `positions`: unknown ids are added, known
+    /// ones updated and the ones missing from the snapshot removed with their P&L realized, as
+    /// `remove_position` does. Snapshot positions are taken as belonging to `account_id`. The
+    /// account is recalculated once. Nothing is applied if any position is rejected.
+    pub async fn reconcile_positions(
+        &mut self,
+        account_id: &str,
+        positions: Vec<MicroEnginePosition>,
+    ) -> Result<MicroEngineAccountCalculationUpdate, MicroEngineError> {
+        if self.accounts.get_account(account_id).is_none() {
+            return Err(MicroEngineError::AccountNotFound);
+        }
+
+        let positions = positions
+            .into_iter()
+            .map(|mut position| {
+                position.account_id = account_id.to_string();
+                self.prepare_position(position)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let snapshot_ids = positions
+            .iter()
+            .map(|x| x.id.as_str())
+            .collect::<HashSet<_>>();
+        let removed_ids = self
+            .positions_cache
+            .get_account_positions(account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| !snapshot_ids.contains(x.id.as_str()))
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+
+        for position_id in removed_ids {
+            if let Some(removed_position) = self.positions_cache.remove_position(&position_id) {
+                self.accounts
+                    .realize_position_pl(&self.settings_cache, &removed_position);
+            }
+        }
+
+        for position in positions {
+            self.positions_cache.add_position(position);
+        }
+
+        self.accounts
+            .recalculate_account_data(
+                &self.settings_cache,
+                &self.positions_cache,
+                &self.bidask_cache,
+                account_id,
+            )
+            .ok_or(MicroEngineError::AccountNotFound)
+    }
+
+    /// Backfills and validates an incoming position and resolves its conversion subscriptions.
+    fn prepare_position(
+        &mut self,
+        mut position: MicroEnginePosition,
+    ) -> Result<MicroEnginePosition, MicroEngineError> {
+        if let Some(settings) = self.settings_cache.resolve_by_account(&position.account_id) {
+            position.apply_default_contract_size(settings);
+
+            if let Some(instrument_settings) = settings.instruments.get(&position.asset_pair)
+                && !instrument_settings.is_valid_lot_size(position.lots_amount)
+            {
+                return Err(MicroEngineError::InvalidLotSize {
+                    asset_pair: position.asset_pair.clone(),
+                    lots_amount: position.lots_amount,
+                });
+            }
+        }
 
-        let mut position: MicroEnginePosition = position.into();
+        self.bidask_cache
+            .fetch_missing(&position.quote, &position.collateral);
 
         let (_, sources) = self
             .bidask_cache
             .get_price_with_source(&position.quote, &position.collateral)
             .ok_or(MicroEngineError::ProfitPriceNotFond)?;
 
-        position.profit_price_assets_subscriptions = sources.unwrap_or_default();
+        position.profit_price_assets_subscriptions =
+            sources.unwrap_or_default().into_iter().collect();
 
         // Note: We don't apply markup to open_bidask here because positions from trading engine
         // already have markup applied to open_bidask. We only apply markup to active_bidask
         // when prices update via update_bidask.
 
-        self.positions_cache.add_position(position.clone());
+        Ok(position)
+    }
 
-        self.accounts
-            .recalculate_account_data(
-                &self.settings_cache,
-                &self.positions_cache,
-                &position.account_id,
-            )
-            .ok_or(MicroEngineError::AccountNotFound)
+    /// Makes the position recalculate whenever `asset_id` ticks, on top of the conversion
+    /// instruments resolved when it was inserted.
+    pub async fn add_position_profit_subscription(
+        &mut self,
+        position_id: &str,
+        asset_id: &str,
+    ) -> Result<(), MicroEngineError> {
+        match self
+            .positions_cache
+            .add_profit_subscription(position_id, asset_id)
+        {
+            true => Ok(()),
+            false => Err(MicroEngineError::PositionNotFound),
+        }
+    }
+
+    /// Removes the account only. Its positions stay in the cache as orphans, see
+    /// `purge_orphan_positions`.
+    pub async fn remove_account(&mut self, account_id: &str) -> Option<MicroEngineAccount> {
+        self.accounts.remove_account(account_id)
+    }
+
+    /// Removes positions whose account no longer exists and returns them.
+    pub async fn purge_orphan_positions(&mut self) -> Vec<MicroEnginePosition> {
+        let orphan_ids = self
+            .positions_cache
+            .orphans(&self.accounts)
+            .into_iter()
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+
+        orphan_ids
+            .iter()
+            .filter_map(|id| self.positions_cache.remove_position(id))
+            .collect()
     }
 
     pub async fn remove_position(
@@ -133,64 +673,456 @@ impl MicroEngine {
             .remove_position(position_id)
             .ok_or(MicroEngineError::PositionNotFound)?;
 
+        self.accounts
+            .realize_position_pl(&self.settings_cache, &removed_position);
+
         self.accounts
             .recalculate_account_data(
                 &self.settings_cache,
                 &self.positions_cache,
+                &self.bidask_cache,
                 &removed_position.account_id,
             )
             .ok_or(MicroEngineError::AccountNotFound)
     }
 
-    pub async fn recalculate_accordint_to_updates(
+    /// Changes a position's volume. Commission is recomputed for volume-dependent commission
+    /// models and preserved for fixed ones. Reducing the volume books the closed share of the
+    /// floating P&L into the position's `realized_pl`.
+    pub async fn modify_position_volume(
         &mut self,
-    ) -> (
-        Option<Vec<MicroEngineAccountCalculationUpdate>>,
-        Option<Vec<MicroEnginePositionCalculationUpdate>>,
-    ) {
-        let updated_prices: Vec<String> = {
-            if self.updated_assets.is_empty() {
-                return (None, None);
-            }
-
-            self.updated_assets.drain().collect()
-        };
+        position_id: &str,
+        lots_amount: f64,
+    ) -> Result<MicroEngineAccountCalculationUpdate, MicroEngineError> {
+        let position = self
+            .positions_cache
+            .get_position_mut(position_id)
+            .ok_or(MicroEngineError::PositionNotFound)?;
 
-        let positions_update_result = self.positions_cache.recalculate_positions_pl(
-            &updated_prices,
-            &mut self.bidask_cache,
-            &self.settings_cache,
-        );
+        let Some(settings) = self.settings_cache.resolve_by_account(&position.account_id) else {
+            let group_id = self
+                .accounts
+                .get_account(&position.account_id)
+                .map(|x| x.trading_group.clone())
+                .unwrap_or_default();
 
-        let Some(positions_update_result) = positions_update_result else {
-            return (None, None);
+            return Err(MicroEngineError::AccountSettingsNotFound(group_id));
         };
 
-        let updated_accounts = positions_update_result
-            .iter()
-            .map(|x| x.account_id.as_str())
-            .collect::<Vec<_>>();
+        if lots_amount < position.lots_amount && position.lots_amount > 0.0 {
+            let closed_share = (position.lots_amount - lots_amount) / position.lots_amount;
+            position.realized_pl += position.pl * closed_share;
+        }
 
-        let accounts_update_result = self.accounts.recalculate_accounts_data(
-            &self.settings_cache,
-            &self.positions_cache,
-            updated_accounts.as_slice(),
-        );
+        position.lots_amount = lots_amount;
 
-        (Some(accounts_update_result), Some(positions_update_result))
-    }
+        if let Some(commission) = settings
+            .instruments
+            .get(&position.asset_pair)
+            .and_then(|x| x.commission_model.calculate(position))
+        {
+            position.commission = commission;
+        }
 
-    async fn recalculate_all(&mut self) {
-        self.positions_cache
-            .recalculate_all_positions(&mut self.bidask_cache, &self.settings_cache);
+        position.recalculate_pl(settings);
+
+        let account_id = position.account_id.clone();
 
         self.accounts
-            .recalculate_all_accounts(&self.settings_cache, &self.positions_cache);
+            .recalculate_account_data(
+                &self.settings_cache,
+                &self.positions_cache,
+                &self.bidask_cache,
+                &account_id,
+            )
+            .ok_or(MicroEngineError::AccountNotFound)
     }
 
-    pub async fn query_account_cache(
-        &self,
-        call: impl Fn(&MicroEngineAccountCache) -> Vec<MicroEngineAccount>,
+    /// Recomputes one position against the cached instrument and conversion prices. Account
+    /// figures are left as they are.
+    pub async fn recalculate_position(
+        &mut self,
+        position_id: &str,
+    ) -> Result<MicroEnginePositionCalculationUpdate, MicroEngineError> {
+        let position = self
+            .positions_cache
+            .get_position_mut(position_id)
+            .ok_or(MicroEngineError::PositionNotFound)?;
+
+        if self
+            .settings_cache
+            .resolve_by_account(&position.account_id)
+            .is_none()
+        {
+            let group_id = self
+                .accounts
+                .get_account(&position.account_id)
+                .map(|x| x.trading_group.clone())
+                .unwrap_or_default();
+
+            return Err(MicroEngineError::AccountSettingsNotFound(group_id));
+        }
+
+        // Same path as a tick, so a closed market or a skipped conversion leaves it unchanged
+        match recalculate_position_from_cache(
+            position,
+            &mut self.bidask_cache,
+            &self.settings_cache,
+        ) {
+            Some(update) => Ok(update),
+            None => Ok(MicroEnginePositionCalculationUpdate::new(
+                position,
+                position.get_gross_pl(),
+            )),
+        }
+    }
+
+    /// Removes a batch of positions and recalculates every affected account once.
+    /// Unknown position ids are skipped.
+    pub async fn remove_positions(
+        &mut self,
+        ids: &[String],
+    ) -> Vec<MicroEngineAccountCalculationUpdate> {
+        let mut affected_accounts: Vec<String> = vec![];
+
+        for id in ids {
+            let Some(removed_position) = self.positions_cache.remove_position(id) else {
+                continue;
+            };
+
+            self.accounts
+                .realize_position_pl(&self.settings_cache, &removed_position);
+
+            if !affected_accounts.contains(&removed_position.account_id) {
+                affected_accounts.push(removed_position.account_id);
+            }
+        }
+
+        let affected_accounts = affected_accounts
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+
+        self.accounts.recalculate_accounts_data(
+            &self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+            affected_accounts.as_slice(),
+        )
+    }
+
+    /// Pre-trade margin check: the margin an order would add on top of the account's current
+    /// margin for the instrument, taking hedging with existing positions into account. Negative
+    /// when the order offsets existing exposure. `None` when the order can't be built, see
+    /// `new_order_position`.
+    pub async fn margin_for_new_order(
+        &self,
+        account_id: &str,
+        asset_pair: &str,
+        lots: f64,
+        is_buy: bool,
+    ) -> Option<f64> {
+        let account = self.accounts.get_account(account_id)?;
+        let settings = self.settings_cache.resolve_by_account(account_id)?;
+        let instrument_settings = settings.instruments.get(asset_pair)?;
+
+        let mut positions = self
+            .positions_cache
+            .get_account_positions(account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| x.asset_pair == asset_pair)
+            .collect::<Vec<_>>();
+
+        let order = self.new_order_position(account_id, asset_pair, lots, is_buy)?;
+
+        let (current_margin, _) = calculate_specific_instrument_margin_and_gross_pl(
+            positions.as_slice(),
+            account,
+            settings.hedge_coef,
+            instrument_settings,
+            &settings.equity_components,
+            settings.reserve_swaps_in_margin,
+            &self.bidask_cache,
+        )?;
+
+        positions.push(&order);
+
+        let (new_margin, _) = calculate_specific_instrument_margin_and_gross_pl(
+            positions.as_slice(),
+            account,
+            settings.hedge_coef,
+            instrument_settings,
+            &settings.equity_components,
+            settings.reserve_swaps_in_margin,
+            &self.bidask_cache,
+        )?;
+
+        Some(new_margin - current_margin)
+    }
+
+    /// The order as a position priced at the current marked-up quote. Currencies and contract
+    /// size come from an existing position in the instrument, preferably the account's own, or
+    /// else from the cached quote and the instrument's `default_contract_size`. Collateral is the
+    /// one of the account's positions, its deposit currency or the group's only collateral.
+    fn new_order_position(
+        &self,
+        account_id: &str,
+        asset_pair: &str,
+        lots: f64,
+        is_buy: bool,
+    ) -> Option<MicroEnginePosition> {
+        let account = self.accounts.get_account(account_id)?;
+        let settings = self.settings_cache.resolve_by_account(account_id)?;
+        let instrument_settings = settings.instruments.get(asset_pair)?;
+
+        let account_positions = self
+            .positions_cache
+            .get_account_positions(account_id)
+            .unwrap_or_default();
+
+        let template = match account_positions
+            .iter()
+            .find(|x| x.asset_pair == asset_pair)
+        {
+            Some(position) => Some(*position),
+            None => self
+                .positions_cache
+                .get_asset_pair_positions(asset_pair)
+                .and_then(|x| x.first().copied()),
+        };
+
+        let mut margin_bidask = self.bidask_cache.get_by_id(asset_pair)?.clone();
+
+        let (base, quote, contract_size) = match template {
+            Some(position) => (
+                position.base.clone(),
+                position.quote.clone(),
+                position.contract_size,
+            ),
+            None => (
+                margin_bidask.base.clone(),
+                margin_bidask.quote.clone(),
+                instrument_settings.default_contract_size?,
+            ),
+        };
+
+        let only_collateral = match settings.collaterals.len() {
+            1 => settings.collaterals.keys().next().cloned(),
+            _ => None,
+        };
+
+        let collateral = account_positions
+            .first()
+            .map(|x| x.collateral.clone())
+            .or_else(|| account.deposit_currency.clone())
+            .or(only_collateral)
+            .or_else(|| template.map(|x| x.collateral.clone()))?;
+
+        if !account.raw_spread {
+            instrument_settings.mutate_bidask(&mut margin_bidask);
+        }
+
+        // Without a conversion the order's P&L and notional can't be taken in the collateral
+        let profit_bidask = self.bidask_cache.get_price(&quote, &collateral)?;
+
+        Some(MicroEnginePosition {
+            id: String::new(),
+            trader_id: account.trader_id.clone(),
+            account_id: account.id.clone(),
+            base,
+            quote,
+            collateral,
+            asset_pair: asset_pair.to_string(),
+            lots_amount: lots,
+            contract_size,
+            is_buy,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: margin_bidask.clone(),
+            active_bidask: margin_bidask.clone(),
+            margin_bidask,
+            profit_bidask,
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        })
+    }
+
+    /// Net notional of the account in its deposit currency: per instrument, the absolute value
+    /// of buys minus sells at the current mid price, summed. Pending positions are left out.
+    /// `None` when a collateral can't be converted into the deposit currency.
+    pub async fn total_notional(&self, account_id: &str) -> Option<f64> {
+        let account = self.accounts.get_account(account_id)?;
+
+        let positions = self
+            .positions_cache
+            .get_account_positions(account_id)
+            .unwrap_or_default();
+
+        net_notional(account, &positions, &self.bidask_cache)
+    }
+
+    /// Pre-trade check combining `margin_for_new_order` with the instrument's lot size limits,
+    /// the account's free margin, the group's `min_margin_level_to_open` and its
+    /// `max_account_notional`. An order over the notional cap is still allowed when it reduces
+    /// the account's `total_notional`. `None` when the order's quote currency can't be converted
+    /// into its collateral, or the required margin or notional into the account's deposit
+    /// currency.
+    pub async fn can_open(
+        &self,
+        account_id: &str,
+        asset_pair: &str,
+        lots: f64,
+        is_buy: bool,
+    ) -> Option<OrderCheck> {
+        let required_margin = self
+            .margin_for_new_order(account_id, asset_pair, lots, is_buy)
+            .await?;
+        let account = self.accounts.get_account(account_id)?;
+        let settings = self.settings_cache.resolve_by_account(account_id)?;
+        let order = self.new_order_position(account_id, asset_pair, lots, is_buy)?;
+        let free_margin = account.free_margin;
+
+        // Order margin is in the collateral, account figures in the deposit currency
+        let required_margin = account.convert_to_deposit_currency(
+            required_margin,
+            &order.collateral,
+            &self.bidask_cache,
+        )?;
+
+        let invalid_lot_size = settings
+            .instruments
+            .get(asset_pair)
+            .is_some_and(|x| !x.is_valid_lot_size(lots));
+
+        // Margin level the account would be left with, same convention as on the account
+        let new_margin = account.margin + required_margin;
+        let margin_level_too_low = settings
+            .min_margin_level_to_open
+            .is_some_and(|min| new_margin >= 0.00001 && account.equity / new_margin * 100.0 < min);
+
+        let exposure_limit_exceeded = match settings.max_account_notional {
+            Some(max_notional) => {
+                let mut positions = self
+                    .positions_cache
+                    .get_account_positions(account_id)
+                    .unwrap_or_default();
+
+                let current_notional = net_notional(account, &positions, &self.bidask_cache)?;
+                positions.push(&order);
+                let new_notional = net_notional(account, &positions, &self.bidask_cache)?;
+
+                new_notional > max_notional && new_notional > current_notional
+            }
+            None => false,
+        };
+
+        let reject_reason = if invalid_lot_size {
+            Some(OrderRejectReason::InvalidLotSize)
+        } else if free_margin < required_margin {
+            Some(OrderRejectReason::InsufficientFreeMargin)
+        } else if margin_level_too_low {
+            Some(OrderRejectReason::MarginLevelTooLow)
+        } else if exposure_limit_exceeded {
+            Some(OrderRejectReason::ExposureLimitExceeded)
+        } else {
+            None
+        };
+
+        Some(OrderCheck {
+            required_margin,
+            free_margin,
+            allowed: reject_reason.is_none(),
+            reject_reason,
+        })
+    }
+
+    pub async fn recalculate_accordint_to_updates(
+        &mut self,
+    ) -> (
+        Option<Vec<MicroEngineAccountCalculationUpdate>>,
+        Option<Vec<MicroEnginePositionCalculationUpdate>>,
+    ) {
+        let mut updated_prices: Vec<String> = {
+            if self.frozen || self.updated_assets.is_empty() {
+                return (None, None);
+            }
+
+            self.updated_assets.drain().collect()
+        };
+
+        // Recalculation only reads the cache, so missing conversion prices are fetched first.
+        // They go ahead of the ticks, which then see them.
+        let fetched = self
+            .positions_cache
+            .fetch_missing_conversions(&updated_prices, &mut self.bidask_cache);
+        updated_prices.splice(0..0, fetched);
+
+        #[cfg(not(feature = "parallel"))]
+        let positions_update_result = self.positions_cache.recalculate_positions_pl(
+            &updated_prices,
+            &self.bidask_cache,
+            &self.settings_cache,
+        );
+
+        #[cfg(feature = "parallel")]
+        let positions_update_result = self.positions_cache.recalculate_positions_pl_parallel(
+            &updated_prices,
+            &self.bidask_cache,
+            &self.settings_cache,
+        );
+
+        let Some(positions_update_result) = positions_update_result else {
+            return (None, None);
+        };
+
+        let updated_accounts = positions_update_result
+            .iter()
+            .map(|x| x.account_id.as_str())
+            .collect::<Vec<_>>();
+
+        let accounts_update_result = match self.changed_only_epsilon {
+            Some(epsilon) => self.accounts.recalculate_accounts_data_changed_only(
+                &self.settings_cache,
+                &self.positions_cache,
+                &self.bidask_cache,
+                updated_accounts.as_slice(),
+                epsilon,
+            ),
+            None => self.accounts.recalculate_accounts_data(
+                &self.settings_cache,
+                &self.positions_cache,
+                &self.bidask_cache,
+                updated_accounts.as_slice(),
+            ),
+        };
+
+        (Some(accounts_update_result), Some(positions_update_result))
+    }
+
+    async fn recalculate_all(&mut self) {
+        self.positions_cache.recalculate_all_positions(
+            &mut self.bidask_cache,
+            &self.settings_cache,
+            self.deterministic_order,
+        );
+
+        self.accounts.recalculate_all_accounts(
+            &self.settings_cache,
+            &self.positions_cache,
+            &self.bidask_cache,
+        );
+    }
+
+    pub async fn query_account_cache(
+        &self,
+        call: impl Fn(&MicroEngineAccountCache) -> Vec<MicroEngineAccount>,
     ) -> Vec<MicroEngineAccount> {
         call(&self.accounts)
     }
@@ -201,6 +1133,107 @@ impl MicroEngine {
     ) -> Vec<MicroEnginePosition> {
         call(&self.positions_cache)
     }
+
+    /// Ids of positions whose instrument has no cached price yet, sorted. These keep their
+    /// opening figures until the first tick arrives.
+    pub async fn positions_without_price(&self) -> Vec<String> {
+        let mut ids = self
+            .positions_cache
+            .get_all_positions()
+            .into_iter()
+            .filter(|x| self.bidask_cache.get_by_id(&x.asset_pair).is_none())
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids
+    }
+
+    /// Ids of positions whose `asset_pair` price was last quoted more than `max_age` before
+    /// `now`, sorted. Prices without a timestamp are never stale; positions with no price at
+    /// all are reported by `positions_without_price`.
+    pub async fn stale_positions(
+        &self,
+        now: DateTime<Utc>,
+        max_age: chrono::Duration,
+    ) -> Vec<String> {
+        let mut ids = self
+            .positions_cache
+            .get_all_positions()
+            .into_iter()
+            .filter(|x| {
+                self.bidask_cache
+                    .get_by_id(&x.asset_pair)
+                    .is_some_and(|price| price.is_stale(now, max_age))
+            })
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids
+    }
+
+    /// Whether `asset_pair` trades at `now`. Closed if any group configuring the instrument has
+    /// it outside its trading hours.
+    pub async fn is_instrument_open(&self, asset_pair: &str, now: DateTime<Utc>) -> bool {
+        self.settings_cache
+            .groups
+            .values()
+            .filter_map(|x| x.instruments.get(asset_pair))
+            .all(|x| x.is_open(now))
+    }
+
+    /// Current price of `asset_pair` as quoted to `group_id`, i.e. with the group's markup.
+    pub async fn group_price(&self, group_id: &str, asset_pair: &str) -> Option<MicroEngineBidask> {
+        let bidask = self.bidask_cache.get_by_id(asset_pair)?;
+
+        self.settings_cache.effective_price(group_id, bidask)
+    }
+
+    pub async fn get_group_settings(
+        &self,
+        group_id: &str,
+    ) -> Option<MicroEngineTradingGroupSettings> {
+        self.settings_cache.get_group(group_id).cloned()
+    }
+
+    pub async fn stats(&self) -> EngineStats {
+        EngineStats {
+            accounts: self.accounts.accounts_count(),
+            positions: self.positions_cache.positions_count(),
+            prices: self.bidask_cache.prices_count(),
+            groups: self.settings_cache.groups.len(),
+        }
+    }
+
+    pub async fn get_instrument_positions(&self, asset_pair: &str) -> Vec<MicroEnginePosition> {
+        let mut positions = self
+            .positions_cache
+            .get_asset_pair_positions(asset_pair)
+            .unwrap_or_default()
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        positions.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+        positions
+    }
+
+    pub async fn list_accounts(&self, offset: usize, limit: usize) -> Vec<MicroEngineAccount> {
+        self.accounts
+            .list_accounts(offset, limit)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn list_positions(&self, offset: usize, limit: usize) -> Vec<MicroEnginePosition> {
+        self.positions_cache
+            .list_positions(offset, limit)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -209,6 +1242,84 @@ pub enum MicroEngineError {
     AccountNotFound,
     PositionNotFound,
     AccountSettingsNotFound(String),
+    /// Position requires a quote -> collateral conversion that can't be resolved from the
+    /// known instruments. Contains the position id.
+    UnresolvablePosition(String),
+    LeverageExceedsGroupMax {
+        account_id: String,
+        leverage: f64,
+        max_leverage: f64,
+    },
+    /// Volume breaks the instrument's lot size limits.
+    InvalidLotSize {
+        asset_pair: String,
+        lots_amount: f64,
+    },
+}
+
+/// Sizes of the engine caches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    pub accounts: usize,
+    pub positions: usize,
+    pub prices: usize,
+    pub groups: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderCheck {
+    /// Margin the order adds, in the account's deposit currency like `free_margin`.
+    pub required_margin: f64,
+    pub free_margin: f64,
+    pub allowed: bool,
+    /// Why the order isn't allowed, `None` when it is.
+    pub reject_reason: Option<OrderRejectReason>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderRejectReason {
+    /// The volume breaks the instrument's lot size limits
+    InvalidLotSize,
+    /// The order needs more margin than the account has free
+    InsufficientFreeMargin,
+    /// The margin level after the order would fall below the group's `min_margin_level_to_open`
+    MarginLevelTooLow,
+    /// The order would take `total_notional` above the group's `max_account_notional`
+    ExposureLimitExceeded,
+}
+
+/// See `MicroEngine::total_notional`.
+fn net_notional(
+    account: &MicroEngineAccount,
+    positions: &[&MicroEnginePosition],
+    bidask_cache: &MicroEngineBidAskCache,
+) -> Option<f64> {
+    let mut per_instrument: HashMap<(&str, &str), f64> = HashMap::new();
+
+    for position in positions.iter().filter(|x| !x.is_pending) {
+        let key = (position.asset_pair.as_str(), position.collateral.as_str());
+        *per_instrument.entry(key).or_default() += position.signed_notional();
+    }
+
+    per_instrument
+        .into_iter()
+        .map(|((_, collateral), notional)| {
+            account.convert_to_deposit_currency(notional.abs(), collateral, bidask_cache)
+        })
+        .sum()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigIssue {
+    MissingTradingGroup {
+        account_id: String,
+        trading_group: String,
+    },
+    MissingInstrumentSettings {
+        position_id: String,
+        trading_group: String,
+        asset_pair: String,
+    },
 }
 
 #[cfg(test)]
@@ -231,7 +1342,21 @@ mod tests {
                     markup_ask: 0.0,
                     min_spread: Some(0.00020),
                     max_spread: None,
+                    markup_order: Default::default(),
+                    spread_clamp_order: Default::default(),
+                    spread_rounding: Default::default(),
+                    volume_markup: None,
+                    clamp_preserves_mid: false,
+                    on_inverted: Default::default(),
                 }),
+                margin_price_mode: Default::default(),
+                kind: Default::default(),
+                commission_model: Default::default(),
+                lot_step: None,
+                min_lots: None,
+                max_lots: None,
+                trading_hours: None,
+                default_contract_size: None,
             },
         );
 
@@ -242,6 +1367,17 @@ mod tests {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         }
     }
 
@@ -257,7 +1393,21 @@ mod tests {
                     markup_ask: 500.0 * 0.00001,
                     min_spread: None,
                     max_spread: None,
+                    markup_order: Default::default(),
+                    spread_clamp_order: Default::default(),
+                    spread_rounding: Default::default(),
+                    volume_markup: None,
+                    clamp_preserves_mid: false,
+                    on_inverted: Default::default(),
                 }),
+                margin_price_mode: Default::default(),
+                kind: Default::default(),
+                commission_model: Default::default(),
+                lot_step: None,
+                min_lots: None,
+                max_lots: None,
+                trading_hours: None,
+                default_contract_size: None,
             },
         );
 
@@ -268,6 +1418,17 @@ mod tests {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         }
     }
 
@@ -277,11 +1438,16 @@ mod tests {
             trader_id: "TR1".to_string(),
             trading_group: "tg1".to_string(),
             balance: 100000.0,
+            credit: 0.0,
             leverage: 100.0,
             margin: 0.0,
             equity: 0.0,
             free_margin: 0.0,
             margin_level: 0.0,
+            realized_pl: 0.0,
+            deposit_currency: None,
+            last_calculated: None,
+            raw_spread: false,
         }
     }
 
@@ -300,6 +1466,7 @@ mod tests {
             ask: 1.1,
             base: "EUR".to_string(),
             quote: "USD".to_string(),
+            date: None,
         }
     }
 
@@ -311,7 +1478,7 @@ mod tests {
 
         let collaterals = HashSet::from(["USD".to_string()]);
 
-        let (mut engine, errors) = MicroEngine::initialize(
+        let (mut engine, errors, _) = MicroEngine::initialize(
             vec![account.clone()],
             vec![MicroEnginePosition {
                 id: "id".to_string(),
@@ -332,6 +1499,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 active_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -339,6 +1507,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 margin_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -346,10 +1515,17 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 profit_bidask: MicroEngineBidask::create_blank(),
-                profit_price_assets_subscriptions: vec![],
+                profit_price_assets_subscriptions: HashSet::new(),
                 swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
             }],
             vec![settings],
             collaterals,
@@ -360,6 +1536,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         )
         .await;
@@ -371,6 +1548,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }])
             .await;
 
@@ -394,7 +1572,7 @@ mod tests {
 
         let collaterals = HashSet::from(["USD".to_string()]);
 
-        let (mut engine, errors) = MicroEngine::initialize(
+        let (mut engine, errors, _) = MicroEngine::initialize(
             vec![account.clone()],
             vec![MicroEnginePosition {
                 id: "id".to_string(),
@@ -415,6 +1593,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 active_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -422,6 +1601,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 margin_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -429,10 +1609,17 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 profit_bidask: MicroEngineBidask::create_blank(),
-                profit_price_assets_subscriptions: vec![],
+                profit_price_assets_subscriptions: HashSet::new(),
                 swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
             }],
             vec![settings],
             collaterals,
@@ -443,6 +1630,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         )
         .await;
@@ -454,6 +1642,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }])
             .await;
 
@@ -477,7 +1666,7 @@ mod tests {
 
         let collaterals = HashSet::from(["USD".to_string()]);
 
-        let (mut engine, errors) = MicroEngine::initialize(
+        let (mut engine, errors, _) = MicroEngine::initialize(
             vec![account.clone()],
             vec![MicroEnginePosition {
                 id: "id".to_string(),
@@ -498,6 +1687,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 active_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -505,6 +1695,7 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 margin_bidask: MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -512,10 +1703,17 @@ mod tests {
                     ask: 1.25542,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 profit_bidask: MicroEngineBidask::create_blank(),
-                profit_price_assets_subscriptions: vec![],
+                profit_price_assets_subscriptions: HashSet::new(),
                 swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
             }],
             vec![settings],
             collaterals,
@@ -526,6 +1724,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         )
         .await;
@@ -537,6 +1736,7 @@ mod tests {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }])
             .await;
 
@@ -551,4 +1751,2991 @@ mod tests {
 
         assert!(errors.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_unresolvable_position_reported_at_initialize() {
+        let account = sample_account();
+        let mut settings = sample_settings();
+        settings.instruments.insert(
+            "EURCAD".to_string(),
+            TradingGroupInstrumentSettings {
+                digits: 5,
+                max_leverage: None,
+                markup_settings: None,
+                margin_price_mode: Default::default(),
+                kind: Default::default(),
+                commission_model: Default::default(),
+                lot_step: None,
+                min_lots: None,
+                max_lots: None,
+                trading_hours: None,
+                default_contract_size: None,
+            },
+        );
+
+        let eurcad = MicroEngineBidask {
+            id: "EURCAD".to_string(),
+            bid: 1.45000,
+            ask: 1.45020,
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        let position = MicroEnginePosition {
+            id: "eurcad-position".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURCAD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: eurcad.clone(),
+            active_bidask: eurcad.clone(),
+            margin_bidask: eurcad.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (_, _, errors) = MicroEngine::initialize(
+            vec![account.clone()],
+            vec![position.clone()],
+            vec![settings.clone()],
+            HashSet::from(["USD".to_string()]),
+            vec![MicroEngineInstrument {
+                id: "EURCAD".to_string(),
+                base: "EUR".to_string(),
+                quote: "CAD".to_string(),
+            }],
+            vec![eurcad.clone()],
+        )
+        .await;
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            MicroEngineError::UnresolvablePosition(id) if id == "eurcad-position"
+        ));
+
+        // USDCAD is a known instrument without a price yet, the position waits for it
+        let (engine, _, errors) = MicroEngine::initialize(
+            vec![account],
+            vec![position],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                MicroEngineInstrument {
+                    id: "EURCAD".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                },
+                MicroEngineInstrument {
+                    id: "USDCAD".to_string(),
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                },
+            ],
+            vec![eurcad],
+        )
+        .await;
+
+        assert!(errors.is_empty());
+        let position = engine.list_positions(0, 1).await.remove(0);
+        assert!(position.subscribes_to("USDCAD"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_positions_recalculates_each_account_once() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let position = |id: &str, account_id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: account_id.to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.01,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut second_account = sample_account();
+        second_account.id = "ACC2".to_string();
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account(), second_account],
+            vec![
+                position("P1", "ACC1"),
+                position("P2", "ACC2"),
+                position("P3", "ACC2"),
+            ],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let updates = engine
+            .remove_positions(&["P1".to_string(), "P2".to_string(), "unknown".to_string()])
+            .await;
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].account_id, "ACC1");
+        assert_eq!(updates[1].account_id, "ACC2");
+        assert_eq!(updates[0].margin, 0.0);
+        assert!(updates[1].margin > 0.0);
+
+        let remaining = engine.list_positions(0, 10).await;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, "P3");
+    }
+
+    #[tokio::test]
+    async fn test_remove_position_settles_realized_pl() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.settle_on_close = true;
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: MicroEngineBidask {
+                    id: "EURUSD".to_string(),
+                    bid: 1.25000,
+                    ask: 1.25002,
+                    base: "EUR".to_string(),
+                    quote: "USD".to_string(),
+                    date: None,
+                },
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let gross_pl = engine
+            .query_positions_cache(|cache| cache.get_position("P1").into_iter().cloned().collect())
+            .await[0]
+            .get_gross_pl();
+        assert!(gross_pl > 0.0);
+
+        let update = engine.remove_position("P1").await.unwrap();
+        let account = engine.list_accounts(0, 1).await.remove(0);
+
+        assert_eq!(
+            format!("{:.2}", update.balance),
+            format!("{:.2}", 100000.0 + gross_pl)
+        );
+        assert_eq!(
+            format!("{:.2}", account.realized_pl),
+            format!("{:.2}", gross_pl)
+        );
+        assert_eq!(update.equity, update.balance);
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_missing_instrument_settings() {
+        let gbpusd = MicroEngineBidask {
+            id: "GBPUSD".to_string(),
+            bid: 1.27000,
+            ask: 1.27020,
+            base: "GBP".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let (_, _, _, issues) = MicroEngine::initialize_and_validate(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "gbpusd-position".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "GBP".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "GBPUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: gbpusd.clone(),
+                active_bidask: gbpusd.clone(),
+                margin_bidask: gbpusd.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "GBPUSD".to_string(),
+                    base: "GBP".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![sample_bidask(), gbpusd],
+        )
+        .await;
+
+        assert_eq!(
+            issues,
+            vec![ConfigIssue::MissingInstrumentSettings {
+                position_id: "gbpusd-position".to_string(),
+                trading_group: "tg1".to_string(),
+                asset_pair: "GBPUSD".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_margin_for_new_order_respects_hedging() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.hedge_coef = Some(0.5);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let same_direction = engine
+            .margin_for_new_order("ACC1", "EURUSD", 0.1, true)
+            .await
+            .unwrap();
+        let hedging = engine
+            .margin_for_new_order("ACC1", "EURUSD", 0.1, false)
+            .await
+            .unwrap();
+
+        assert!(same_direction > 0.0);
+        assert!(hedging < same_direction);
+        assert!(hedging < 0.0);
+        assert!(
+            engine
+                .margin_for_new_order("ACC1", "GBPUSD", 0.1, true)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_margin_for_new_order_without_positions() {
+        let mut settings = sample_settings();
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings.clone()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        // No position to take the contract size from and no default for the instrument
+        assert!(
+            engine
+                .margin_for_new_order("ACC1", "EURUSD", 0.1, true)
+                .await
+                .is_none()
+        );
+
+        settings
+            .instruments
+            .get_mut("EURUSD")
+            .unwrap()
+            .default_contract_size = Some(100000.0);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let margin = engine
+            .margin_for_new_order("ACC1", "EURUSD", 0.1, true)
+            .await
+            .unwrap();
+
+        // 0.1 * 100000 * 1.1 (ask) / 100
+        assert_eq!(format!("{:.2}", margin), "110.00");
+    }
+
+    #[tokio::test]
+    async fn test_account_leverage_clamped_to_group_max() {
+        let mut settings = sample_settings();
+        settings.max_leverage = Some(200.0);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            Vec::<MicroEngineAccount>::new(),
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let mut account = sample_account();
+        account.leverage = 500.0;
+
+        assert!(engine.insert_or_update_account(account).await.is_ok());
+        assert_eq!(engine.list_accounts(0, 1).await[0].leverage, 200.0);
+    }
+
+    #[tokio::test]
+    async fn test_account_leverage_rejected_above_group_max() {
+        let mut settings = sample_settings();
+        settings.max_leverage = Some(200.0);
+        settings.account_leverage_policy = crate::settings::AccountLeveragePolicy::Reject;
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            Vec::<MicroEngineAccount>::new(),
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let mut account = sample_account();
+        account.leverage = 500.0;
+
+        let result = engine.insert_or_update_account(account).await;
+
+        assert!(matches!(
+            result,
+            Err(MicroEngineError::LeverageExceedsGroupMax { max_leverage, .. }) if max_leverage == 200.0
+        ));
+        assert!(engine.list_accounts(0, 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_apply_price_shock_moves_pl() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let position = |id: &str, is_buy: bool| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("buy", true), position("sell", false)],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        engine.apply_price_shock(1.05).await;
+
+        let (_, position_updates) = engine.recalculate_accordint_to_updates().await;
+        let position_updates = position_updates.unwrap();
+
+        let gross = |id: &str| {
+            position_updates
+                .iter()
+                .find(|x| x.position_id == id)
+                .unwrap()
+                .gross_pl
+        };
+
+        assert!(gross("buy") > 0.0);
+        assert!(gross("sell") < 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_changed_only_skips_unchanged_accounts() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        engine.set_changed_only_epsilon(Some(0.000001)).await;
+
+        engine.handle_new_price(vec![price.clone()]).await;
+        let (account_updates, position_updates) = engine.recalculate_accordint_to_updates().await;
+
+        assert_eq!(position_updates.unwrap().len(), 1);
+        assert!(account_updates.unwrap().is_empty());
+
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                bid: 1.25640,
+                ask: 1.25642,
+                ..price.clone()
+            }])
+            .await;
+        let (account_updates, _) = engine.recalculate_accordint_to_updates().await;
+
+        assert_eq!(account_updates.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handle_price_and_recalc_single_account() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let position = |id: &str, account_id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: account_id.to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut second_account = sample_account();
+        second_account.id = "ACC2".to_string();
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account(), second_account],
+            vec![position("P1", "ACC1"), position("P2", "ACC2")],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let equity_before = engine.list_accounts(1, 1).await[0].equity;
+
+        let moved_price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.26540,
+            ask: 1.26542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let update = engine
+            .handle_price_and_recalc_account(moved_price, "ACC1")
+            .await
+            .unwrap();
+
+        assert_eq!(update.account_id, "ACC1");
+        assert!(update.equity > equity_before);
+        assert_eq!(engine.list_accounts(1, 1).await[0].equity, equity_before);
+
+        // The next drain catches up the other account on the instrument
+        let (accounts, _) = engine.recalculate_accordint_to_updates().await;
+
+        assert!(accounts.unwrap().iter().any(|x| x.account_id == "ACC2"));
+        assert_eq!(engine.list_accounts(1, 1).await[0].equity, update.equity);
+    }
+
+    #[tokio::test]
+    async fn test_modify_position_volume_commission_models() {
+        use crate::settings::CommissionModel;
+
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let cases = [
+            (CommissionModel::Fixed, "0.70"),
+            (CommissionModel::PerLot { per_lot: 7.0 }, "1.40"),
+            (CommissionModel::PercentOfNotional { percent: 0.01 }, "2.51"),
+        ];
+
+        for (commission_model, expected_commission) in cases {
+            let mut settings = sample_settings();
+            settings
+                .instruments
+                .get_mut("EURUSD")
+                .unwrap()
+                .commission_model = commission_model;
+
+            let (mut engine, _, _) = MicroEngine::initialize(
+                vec![sample_account()],
+                vec![MicroEnginePosition {
+                    id: "P1".to_string(),
+                    trader_id: "TR1".to_string(),
+                    account_id: "ACC1".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "USD".to_string(),
+                    collateral: "USD".to_string(),
+                    asset_pair: "EURUSD".to_string(),
+                    lots_amount: 0.1,
+                    contract_size: 100000.0,
+                    is_buy: true,
+                    pl: 0.0,
+                    commission: 0.7,
+                    open_bidask: price.clone(),
+                    active_bidask: price.clone(),
+                    margin_bidask: price.clone(),
+                    profit_bidask: MicroEngineBidask::create_blank(),
+                    profit_price_assets_subscriptions: HashSet::new(),
+                    swaps_sum: 0.0,
+                    realized_pl: 0.0,
+                    leverage: None,
+                    open_time: None,
+                    last_swap_accrual: None,
+                    is_pending: false,
+                    metadata: None,
+                }],
+                vec![settings],
+                HashSet::from(["USD".to_string()]),
+                vec![sample_instrument()],
+                vec![price.clone()],
+            )
+            .await;
+
+            let update = engine.modify_position_volume("P1", 0.2).await.unwrap();
+            let position = engine.list_positions(0, 1).await.remove(0);
+
+            assert_eq!(position.lots_amount, 0.2);
+            assert_eq!(format!("{:.2}", position.commission), expected_commission);
+            assert_eq!(update.account_id, "ACC1");
+        }
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        assert!(matches!(
+            engine.modify_position_volume("unknown", 1.0).await,
+            Err(MicroEngineError::PositionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_conversion_policies() {
+        use crate::settings::MissingConversionPolicy;
+
+        let eurcad = |bid: f64, ask: f64| MicroEngineBidask {
+            id: "EURCAD".to_string(),
+            bid,
+            ask,
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        let cases = [
+            MissingConversionPolicy::UseLastKnown,
+            MissingConversionPolicy::SkipUpdate,
+            MissingConversionPolicy::Zero,
+        ];
+
+        for policy in cases {
+            let mut settings = sample_settings();
+            settings.missing_conversion_policy = policy;
+            settings.instruments.insert(
+                "EURCAD".to_string(),
+                TradingGroupInstrumentSettings {
+                    digits: 5,
+                    max_leverage: None,
+                    markup_settings: None,
+                    margin_price_mode: Default::default(),
+                    kind: Default::default(),
+                    commission_model: Default::default(),
+                    lot_step: None,
+                    min_lots: None,
+                    max_lots: None,
+                    trading_hours: None,
+                    default_contract_size: None,
+                },
+            );
+
+            let (mut engine, _, _) = MicroEngine::initialize(
+                vec![sample_account()],
+                vec![MicroEnginePosition {
+                    id: "P1".to_string(),
+                    trader_id: "TR1".to_string(),
+                    account_id: "ACC1".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                    collateral: "USD".to_string(),
+                    asset_pair: "EURCAD".to_string(),
+                    lots_amount: 0.1,
+                    contract_size: 100000.0,
+                    is_buy: true,
+                    pl: 0.0,
+                    commission: 0.0,
+                    open_bidask: eurcad(1.45000, 1.45000),
+                    active_bidask: eurcad(1.45000, 1.45000),
+                    margin_bidask: eurcad(1.45000, 1.45000),
+                    profit_bidask: MicroEngineBidask {
+                        id: "CADUSD".to_string(),
+                        bid: 0.74,
+                        ask: 0.74,
+                        base: "CAD".to_string(),
+                        quote: "USD".to_string(),
+                        date: None,
+                    },
+                    profit_price_assets_subscriptions: HashSet::new(),
+                    swaps_sum: 0.0,
+                    realized_pl: 0.0,
+                    leverage: None,
+                    open_time: None,
+                    last_swap_accrual: None,
+                    is_pending: false,
+                    metadata: None,
+                }],
+                vec![settings],
+                HashSet::from(["USD".to_string()]),
+                vec![MicroEngineInstrument {
+                    id: "EURCAD".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                }],
+                vec![eurcad(1.45000, 1.45000)],
+            )
+            .await;
+
+            engine
+                .handle_new_price(vec![eurcad(1.46000, 1.46000)])
+                .await;
+            let (_, position_updates) = engine.recalculate_accordint_to_updates().await;
+            let position = engine.list_positions(0, 1).await.remove(0);
+
+            match policy {
+                MissingConversionPolicy::UseLastKnown => {
+                    // 0.01 * 0.1 * 100000 * 0.74
+                    assert_eq!(format!("{:.2}", position.pl), "74.00");
+                    assert_eq!(position_updates.unwrap().len(), 1);
+                }
+                MissingConversionPolicy::SkipUpdate => {
+                    assert_eq!(position.pl, 0.0);
+                    assert_eq!(position.active_bidask.bid, 1.45000);
+                    assert_eq!(position.active_bidask.ask, 1.45000);
+                    assert!(position_updates.is_none());
+                }
+                MissingConversionPolicy::Zero => {
+                    assert_eq!(position.pl, 0.0);
+                    assert_eq!(position.profit_bidask.bid, 0.0);
+                    assert_eq!(position_updates.unwrap().len(), 1);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_instrument_positions() {
+        let eurusd = sample_bidask();
+        let usdcad = MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.35000,
+            ask: 1.35020,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        let position = |id: &str, price: &MicroEngineBidask| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: price.base.clone(),
+            quote: price.quote.clone(),
+            collateral: "USD".to_string(),
+            asset_pair: price.id.clone(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![
+                position("P1", &eurusd),
+                position("P2", &usdcad),
+                position("P3", &eurusd),
+            ],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "USDCAD".to_string(),
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                },
+            ],
+            vec![eurusd.clone(), usdcad.clone()],
+        )
+        .await;
+
+        let positions = engine.get_instrument_positions("EURUSD").await;
+
+        assert_eq!(
+            positions.iter().map(|x| x.id.as_str()).collect::<Vec<_>>(),
+            vec!["P1", "P3"]
+        );
+        assert!(engine.get_instrument_positions("GBPUSD").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_can_open() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let check = engine.can_open("ACC1", "EURUSD", 1.0, true).await.unwrap();
+        assert!(check.allowed);
+        assert!(check.required_margin > 0.0);
+        assert!(check.free_margin >= check.required_margin);
+
+        let check = engine
+            .can_open("ACC1", "EURUSD", 1000.0, true)
+            .await
+            .unwrap();
+        assert!(!check.allowed);
+        assert!(check.free_margin < check.required_margin);
+        assert_eq!(
+            check.reject_reason,
+            Some(OrderRejectReason::InsufficientFreeMargin)
+        );
+
+        assert!(engine.can_open("ACC2", "EURUSD", 1.0, true).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_can_open_compares_margin_in_deposit_currency() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25,
+            ask: 1.25,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let mut account = sample_account();
+        account.balance = 1100.0;
+        account.deposit_currency = Some("EUR".to_string());
+
+        let mut settings = sample_settings();
+        settings
+            .instruments
+            .get_mut("EURUSD")
+            .unwrap()
+            .default_contract_size = Some(100000.0);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![account],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let check = engine.can_open("ACC1", "EURUSD", 1.0, true).await.unwrap();
+
+        // 1 * 100000 * 1.25 / 100 = 1250 USD plus markup, ~1000 EUR against 1100 EUR free
+        assert_eq!(format!("{:.0}", check.required_margin), "1000");
+        assert_eq!(check.free_margin, 1100.0);
+        assert!(check.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let price = sample_bidask();
+        let mut other_group = sample_settings();
+        other_group.id = "tg2".to_string();
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![sample_settings(), other_group],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        assert_eq!(
+            engine.stats().await,
+            EngineStats {
+                accounts: 1,
+                positions: 1,
+                prices: 1,
+                groups: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accrue_swaps_triple_on_wednesday() {
+        use chrono::TimeZone;
+
+        let price = sample_bidask();
+        // Monday
+        let open_time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.5,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price.clone(),
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: Some(open_time),
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: None,
+            }],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let rates = HashMap::from([("EURUSD".to_string(), -2.0)]);
+
+        // Thursday: Monday and Tuesday rollovers plus the triple Wednesday one
+        let thursday = Utc.with_ymd_and_hms(2024, 1, 4, 12, 0, 0).unwrap();
+        let updates = engine.accrue_swaps(thursday, &rates).await;
+
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].account_id, "ACC1");
+        assert_eq!(
+            engine.get_instrument_positions("EURUSD").await[0].swaps_sum,
+            -5.0
+        );
+
+        // Accruing again at the same moment adds nothing
+        assert!(engine.accrue_swaps(thursday, &rates).await.is_empty());
+
+        // Next Monday: Thursday and Friday rollovers, none for the weekend
+        let monday = Utc.with_ymd_and_hms(2024, 1, 8, 12, 0, 0).unwrap();
+        engine.accrue_swaps(monday, &rates).await;
+
+        assert_eq!(
+            engine.get_instrument_positions("EURUSD").await[0].swaps_sum,
+            -7.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_orphan_positions() {
+        let price = sample_bidask();
+        let position = |id: &str, account_id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: account_id.to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut other_account = sample_account();
+        other_account.id = "ACC2".to_string();
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account(), other_account],
+            vec![
+                position("P1", "ACC1"),
+                position("P2", "ACC1"),
+                position("P3", "ACC2"),
+            ],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        assert!(engine.purge_orphan_positions().await.is_empty());
+        assert!(engine.remove_account("ACC1").await.is_some());
+        assert_eq!(engine.get_instrument_positions("EURUSD").await.len(), 3);
+
+        let mut purged = engine
+            .purge_orphan_positions()
+            .await
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        purged.sort_unstable();
+
+        assert_eq!(purged, vec!["P1", "P2"]);
+        assert_eq!(
+            engine
+                .get_instrument_positions("EURUSD")
+                .await
+                .into_iter()
+                .map(|x| x.id)
+                .collect::<Vec<_>>(),
+            vec!["P3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_positions_without_price() {
+        let eurusd = sample_bidask();
+        let gbpusd = MicroEngineBidask {
+            id: "GBPUSD".to_string(),
+            bid: 1.27000,
+            ask: 1.27020,
+            base: "GBP".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let position = |id: &str, price: &MicroEngineBidask| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: price.base.clone(),
+            quote: price.quote.clone(),
+            collateral: "USD".to_string(),
+            asset_pair: price.id.clone(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("P1", &eurusd), position("P2", &gbpusd)],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "GBPUSD".to_string(),
+                    base: "GBP".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![eurusd.clone()],
+        )
+        .await;
+
+        assert_eq!(engine.positions_without_price().await, vec!["P2"]);
+
+        engine.handle_new_price(vec![gbpusd]).await;
+
+        assert!(engine.positions_without_price().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_raw_spread_account_skips_markup() {
+        let price = sample_bidask();
+        let position = |id: &str, account_id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: account_id.to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut raw_account = sample_account();
+        raw_account.id = "ACC2".to_string();
+        raw_account.raw_spread = true;
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account(), raw_account],
+            vec![position("P1", "ACC1"), position("P2", "ACC2")],
+            vec![sample_settings_with_markup()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                id: "EURUSD".to_string(),
+                bid: 1.08000,
+                ask: 1.08010,
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                date: None,
+            }])
+            .await;
+        engine.recalculate_accordint_to_updates().await;
+
+        let positions = engine.get_instrument_positions("EURUSD").await;
+        let active = |id: &str| {
+            let position = positions.iter().find(|x| x.id == id).unwrap();
+            (position.active_bidask.bid, position.active_bidask.ask)
+        };
+
+        assert_eq!(active("P2"), (1.08000, 1.08010));
+        assert_ne!(active("P1"), active("P2"));
+    }
+
+    #[tokio::test]
+    async fn test_recalculate_group_after_markup_change() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let before = engine.recalculate_group("tg1").await;
+        assert_eq!(before.len(), 1);
+
+        engine
+            .trading_settings_changed(sample_settings_with_markup())
+            .await;
+        let after = engine.recalculate_group("tg1").await;
+
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].account_id, "ACC1");
+        // bid marked down by 300 points on a 0.1 lot buy
+        assert!((before[0].equity - after[0].equity - 30.0).abs() < 1e-6);
+        assert!((after[0].margin_level - after[0].equity / after[0].margin * 100.0).abs() < 1e-9);
+        assert!(engine.recalculate_group("unknown").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_positions() {
+        use chrono::TimeZone;
+
+        let now = Utc.with_ymd_and_hms(2024, 1, 8, 12, 0, 0).unwrap();
+
+        let mut eurusd = sample_bidask();
+        eurusd.date = Some(now - chrono::Duration::seconds(5));
+        let gbpusd = MicroEngineBidask {
+            id: "GBPUSD".to_string(),
+            bid: 1.27000,
+            ask: 1.27020,
+            base: "GBP".to_string(),
+            quote: "USD".to_string(),
+            date: Some(now - chrono::Duration::minutes(5)),
+        };
+
+        let position = |id: &str, price: &MicroEngineBidask| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: price.base.clone(),
+            quote: price.quote.clone(),
+            collateral: "USD".to_string(),
+            asset_pair: price.id.clone(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("P1", &eurusd), position("P2", &gbpusd)],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "GBPUSD".to_string(),
+                    base: "GBP".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![eurusd.clone(), gbpusd.clone()],
+        )
+        .await;
+
+        assert_eq!(
+            engine
+                .stale_positions(now, chrono::Duration::minutes(1))
+                .await,
+            vec!["P2"]
+        );
+        assert!(
+            engine
+                .stale_positions(now, chrono::Duration::minutes(10))
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_position_profit_subscription() {
+        let eurusd = sample_bidask();
+        let gbpusd = MicroEngineBidask {
+            id: "GBPUSD".to_string(),
+            bid: 1.27000,
+            ask: 1.27020,
+            base: "GBP".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: eurusd.clone(),
+            active_bidask: eurusd.clone(),
+            margin_bidask: eurusd.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "GBPUSD".to_string(),
+                    base: "GBP".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![eurusd, gbpusd.clone()],
+        )
+        .await;
+
+        engine.handle_new_price(vec![gbpusd.clone()]).await;
+        let (_, pos_updates) = engine.recalculate_accordint_to_updates().await;
+        assert!(pos_updates.is_none());
+
+        engine
+            .add_position_profit_subscription("P1", "GBPUSD")
+            .await
+            .unwrap();
+        assert!(matches!(
+            engine
+                .add_position_profit_subscription("P2", "GBPUSD")
+                .await,
+            Err(MicroEngineError::PositionNotFound)
+        ));
+
+        engine.handle_new_price(vec![gbpusd]).await;
+        let (_, pos_updates) = engine.recalculate_accordint_to_updates().await;
+        let pos_updates = pos_updates.unwrap();
+
+        assert_eq!(pos_updates.len(), 1);
+        assert_eq!(pos_updates[0].position_id, "P1");
+    }
+
+    #[tokio::test]
+    async fn test_recalculate_position() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 2.5,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                id: "EURUSD".to_string(),
+                bid: 1.2,
+                ask: 1.3,
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                date: None,
+            }])
+            .await;
+
+        let update = engine.recalculate_position("P1").await.unwrap();
+
+        // (1.2 - 1.1) * 0.1 * 100000 - 2.5
+        assert_eq!(update.position_id, "P1");
+        assert!((update.gross_pl - 997.5).abs() < 1e-9);
+        assert!(matches!(
+            engine.recalculate_position("P2").await,
+            Err(MicroEngineError::PositionNotFound)
+        ));
+
+        // The error names the group without settings, not the account
+        engine.trading_settings_removed("tg1").await;
+        assert!(matches!(
+            engine.recalculate_position("P1").await,
+            Err(MicroEngineError::AccountSettingsNotFound(group_id)) if group_id == "tg1"
+        ));
+        assert!(matches!(
+            engine.modify_position_volume("P1", 0.2).await,
+            Err(MicroEngineError::AccountSettingsNotFound(group_id)) if group_id == "tg1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_insert_position_validates_lot_size() {
+        let price = sample_bidask();
+        let position = |id: &str, lots_amount: f64| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        let instrument = settings.instruments.get_mut("EURUSD").unwrap();
+        instrument.lot_step = Some(0.01);
+        instrument.min_lots = Some(0.02);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        assert!(
+            engine
+                .insert_or_update_position(position("P1", 0.03))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            engine.insert_or_update_position(position("P2", 0.025)).await,
+            Err(MicroEngineError::InvalidLotSize { lots_amount, .. }) if lots_amount == 0.025
+        ));
+        assert!(matches!(
+            engine.insert_or_update_position(position("P3", 0.01)).await,
+            Err(MicroEngineError::InvalidLotSize { .. })
+        ));
+        assert_eq!(engine.get_instrument_positions("EURUSD").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_group_price() {
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings_with_markup()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let raw = sample_bidask();
+        let price = engine.group_price("tg1", "EURUSD").await.unwrap();
+
+        // -300 / +500 points of markup
+        assert_eq!(price.id, raw.id);
+        assert_eq!(
+            format!("{:.5}", price.bid),
+            format!("{:.5}", raw.bid - 0.003)
+        );
+        assert_eq!(
+            format!("{:.5}", price.ask),
+            format!("{:.5}", raw.ask + 0.005)
+        );
+
+        assert!(engine.group_price("tg2", "EURUSD").await.is_none());
+        assert!(engine.group_price("tg1", "GBPUSD").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_accounts_without_settings() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let account = |engine: &MicroEngine| engine.accounts.get_account("ACC1").unwrap().clone();
+        let margin = account(&engine).margin;
+
+        assert!(margin > 0.0);
+        assert!(engine.accounts_without_settings().await.is_empty());
+
+        assert!(engine.trading_settings_removed("tg1").await.is_some());
+        assert_eq!(engine.accounts_without_settings().await, vec!["ACC1"]);
+
+        assert!(engine.recalculate_group("tg1").await.is_empty());
+        assert_eq!(account(&engine).margin, margin);
+
+        engine
+            .set_unknown_group_policy(UnknownGroupPolicy::ZeroOut)
+            .await;
+        engine.recalculate_group("tg1").await;
+
+        let account = account(&engine);
+        assert_eq!(account.margin, 0.0);
+        assert_eq!(account.equity, account.balance);
+    }
+
+    #[tokio::test]
+    async fn test_closed_instrument_freezes_pl() {
+        use chrono::TimeZone;
+
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        // Monday 00:00 to Friday 22:00
+        let mut settings = sample_settings();
+        settings
+            .instruments
+            .get_mut("EURUSD")
+            .unwrap()
+            .trading_hours = Some(vec![(0, 4 * 24 * 60 + 22 * 60)]);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let saturday = Utc.with_ymd_and_hms(2024, 1, 13, 12, 0, 0).unwrap();
+        let monday = Utc.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap();
+        let tick = |date| MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.2,
+            ask: 1.3,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: Some(date),
+        };
+        let pl = |engine: &MicroEngine| engine.positions_cache.get_position("P1").unwrap().pl;
+
+        assert!(!engine.is_instrument_open("EURUSD", saturday).await);
+        assert!(engine.is_instrument_open("EURUSD", monday).await);
+
+        let pl_before = pl(&engine);
+        engine.handle_new_price(vec![tick(saturday)]).await;
+        let (_, pos_updates) = engine.recalculate_accordint_to_updates().await;
+
+        assert!(pos_updates.is_none());
+        assert_eq!(pl(&engine), pl_before);
+
+        engine.handle_new_price(vec![tick(monday)]).await;
+        engine.recalculate_accordint_to_updates().await;
+
+        assert_ne!(pl(&engine), pl_before);
+    }
+
+    #[tokio::test]
+    async fn test_default_contract_size_backfill() {
+        let price = sample_bidask();
+        let position = |id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 0.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings
+            .instruments
+            .get_mut("EURUSD")
+            .unwrap()
+            .default_contract_size = Some(100000.0);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("P1")],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let update = engine
+            .insert_or_update_position(position("P2"))
+            .await
+            .unwrap();
+
+        let positions = engine.get_instrument_positions("EURUSD").await;
+        assert_eq!(positions.len(), 2);
+        for position in positions {
+            assert_eq!(position.contract_size, 100000.0);
+        }
+        assert!(update.margin > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_move_account_to_group() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut wide_markup = sample_settings_with_markup();
+        wide_markup.id = "tg2".to_string();
+        wide_markup.max_leverage = Some(50.0);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings(), wide_markup],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let before = engine.accounts.get_account("ACC1").unwrap().clone();
+        let update = engine.move_account_to_group("ACC1", "tg2").await.unwrap();
+
+        assert_eq!(
+            engine.accounts.get_account("ACC1").unwrap().trading_group,
+            "tg2"
+        );
+        // 300 points of bid markup on 0.1 lot, and half the leverage
+        assert!((before.equity - update.equity - 30.0).abs() < 1e-6);
+        assert!((update.margin - before.margin * 2.0).abs() < 1e-6);
+
+        assert!(matches!(
+            engine.move_account_to_group("ACC1", "tg3").await,
+            Err(MicroEngineError::AccountSettingsNotFound(_))
+        ));
+        assert!(matches!(
+            engine.move_account_to_group("ACC2", "tg1").await,
+            Err(MicroEngineError::AccountNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_freeze_suppresses_updates() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        let tick = MicroEngineBidask {
+            bid: 1.2,
+            ask: 1.3,
+            ..price
+        };
+
+        engine.freeze().await;
+        assert!(engine.is_frozen().await);
+        assert_eq!(engine.handle_new_price(vec![tick.clone()]).await, 0);
+        assert!(
+            engine
+                .handle_price_and_recalc_account(tick.clone(), "ACC1")
+                .await
+                .is_none()
+        );
+
+        let (accounts, positions) = engine.recalculate_accordint_to_updates().await;
+        assert!(accounts.is_none() && positions.is_none());
+        assert_eq!(engine.bidask_cache.get_by_id("EURUSD").unwrap().bid, 1.0);
+
+        engine.unfreeze().await;
+        assert!(!engine.is_frozen().await);
+        assert_eq!(engine.handle_new_price(vec![tick]).await, 0);
+
+        let (accounts, positions) = engine.recalculate_accordint_to_updates().await;
+        assert_eq!(accounts.unwrap().len(), 1);
+        assert_eq!(positions.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_group_settings() {
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let group = engine.get_group_settings("tg1").await.unwrap();
+        assert_eq!(group.id, "tg1");
+        assert!(group.instruments.contains_key("EURUSD"));
+
+        assert!(engine.get_group_settings("tg2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_can_open_min_margin_level() {
+        let price = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.25540,
+            ask: 1.25542,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.min_margin_level_to_open = Some(200.0);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        // ~62771 more margin fits into ~99874 free margin, but leaves a ~159% margin level
+        let check = engine.can_open("ACC1", "EURUSD", 50.0, true).await.unwrap();
+        assert!(check.free_margin >= check.required_margin);
+        assert!(!check.allowed);
+        assert_eq!(
+            check.reject_reason,
+            Some(OrderRejectReason::MarginLevelTooLow)
+        );
+
+        let check = engine.can_open("ACC1", "EURUSD", 1.0, true).await.unwrap();
+        assert!(check.allowed);
+        assert_eq!(check.reject_reason, None);
+    }
+
+    #[tokio::test]
+    async fn test_position_metadata_round_trip() {
+        let price = sample_bidask();
+        let metadata = HashMap::from([
+            ("strategy_id".to_string(), "S42".to_string()),
+            ("source".to_string(), "fix".to_string()),
+        ]);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        engine
+            .insert_or_update_position(MicroEnginePosition {
+                id: "P1".to_string(),
+                trader_id: "TR1".to_string(),
+                account_id: "ACC1".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+                collateral: "USD".to_string(),
+                asset_pair: "EURUSD".to_string(),
+                lots_amount: 0.1,
+                contract_size: 100000.0,
+                is_buy: true,
+                pl: 0.0,
+                commission: 0.0,
+                open_bidask: price.clone(),
+                active_bidask: price.clone(),
+                margin_bidask: price,
+                profit_bidask: MicroEngineBidask::create_blank(),
+                profit_price_assets_subscriptions: HashSet::new(),
+                swaps_sum: 0.0,
+                realized_pl: 0.0,
+                leverage: None,
+                open_time: None,
+                last_swap_accrual: None,
+                is_pending: false,
+                metadata: Some(metadata.clone()),
+            })
+            .await
+            .unwrap();
+        engine.recalculate_position("P1").await.unwrap();
+
+        let position = engine.positions_cache.get_position("P1").unwrap();
+        assert_eq!(position.metadata, Some(metadata));
+    }
+
+    #[tokio::test]
+    async fn test_realized_and_unrealized_after_partial_close() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.2,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: -3.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                bid: 1.2,
+                ask: 1.3,
+                ..price
+            }])
+            .await;
+        engine.recalculate_accordint_to_updates().await;
+
+        // (1.2 - 1.1) * 0.2 * 100000 floating, half of it closed
+        engine.modify_position_volume("P1", 0.1).await.unwrap();
+        let update = engine.recalculate_position("P1").await.unwrap();
+
+        assert_eq!(format!("{:.2}", update.unrealized_pl), "1000.00");
+        assert_eq!(format!("{:.2}", update.realized_pl), "997.00");
+        assert_eq!(format!("{:.2}", update.gross_pl), "997.00");
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_positions() {
+        let price = sample_bidask();
+        let position = |id: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("P1"), position("P2")],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+        let margin_before = engine.accounts.get_account("ACC1").unwrap().margin;
+
+        let mut modified = position("P1");
+        modified.lots_amount = 0.2;
+        let mut dropped = position("P2");
+        dropped.commission = 5.0;
+        engine.positions_cache.add_position(dropped);
+
+        let update = engine
+            .reconcile_positions("ACC1", vec![modified, position("P3")])
+            .await
+            .unwrap();
+
+        let mut ids = engine
+            .positions_cache
+            .get_account_positions("ACC1")
+            .unwrap()
+            .iter()
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        assert_eq!(ids, vec!["P1", "P3"]);
+        assert_eq!(
+            engine
+                .positions_cache
+                .get_position("P1")
+                .unwrap()
+                .lots_amount,
+            0.2
+        );
+        // 0.3 lots against 0.2 before
+        assert!((update.margin - margin_before * 1.5).abs() < 1e-6);
+        assert_eq!(
+            engine.accounts.get_account("ACC1").unwrap().realized_pl,
+            -5.0
+        );
+
+        assert!(matches!(
+            engine.reconcile_positions("ACC2", vec![]).await,
+            Err(MicroEngineError::AccountNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_validate_collaterals() {
+        let price = sample_bidask();
+        let position = |id: &str, collateral: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: collateral.to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![
+                position("P2", "EUR"),
+                position("P1", "USD"),
+                position("P0", "EUR"),
+            ],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string(), "EUR".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        assert_eq!(engine.validate_collaterals().await, vec!["P0", "P2"]);
+    }
+
+    #[tokio::test]
+    async fn test_can_open_max_account_notional() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 1.0,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.max_account_notional = Some(120000.0);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        // 1 lot at the 1.05 mid
+        let notional = engine.total_notional("ACC1").await.unwrap();
+        assert_eq!(format!("{:.2}", notional), "105000.00");
+
+        let check = engine.can_open("ACC1", "EURUSD", 0.5, true).await.unwrap();
+        assert!(check.free_margin >= check.required_margin);
+        assert!(!check.allowed);
+        assert_eq!(
+            check.reject_reason,
+            Some(OrderRejectReason::ExposureLimitExceeded)
+        );
+
+        let check = engine.can_open("ACC1", "EURUSD", 0.5, false).await.unwrap();
+        assert!(check.allowed);
+
+        assert!(engine.total_notional("ACC2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_total_notional_in_deposit_currency() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 1.0,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut account = sample_account();
+        account.deposit_currency = Some("EUR".to_string());
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![account],
+            vec![position],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        // 105000 USD at the 1.05 mid, in EUR through the reversed EURUSD: 1 / 1.1
+        let notional = engine.total_notional("ACC1").await.unwrap();
+        assert_eq!(format!("{:.2}", notional), "95454.55");
+    }
+
+    #[tokio::test]
+    async fn test_can_open_without_conversion_price() {
+        let mut settings = sample_settings();
+        let mut instrument_settings = settings.instruments["EURUSD"].clone();
+        instrument_settings.default_contract_size = Some(100000.0);
+        settings
+            .instruments
+            .insert("EURGBP".to_string(), instrument_settings);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                MicroEngineInstrument {
+                    id: "EURGBP".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "GBP".to_string(),
+                },
+                MicroEngineInstrument {
+                    id: "GBPUSD".to_string(),
+                    base: "GBP".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![MicroEngineBidask {
+                id: "EURGBP".to_string(),
+                bid: 0.85000,
+                ask: 0.85020,
+                base: "EUR".to_string(),
+                quote: "GBP".to_string(),
+                date: None,
+            }],
+        )
+        .await;
+
+        // GBP can't be taken as USD, so neither the margin nor the notional can be
+        assert!(engine.can_open("ACC1", "EURGBP", 0.1, true).await.is_none());
+
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                id: "GBPUSD".to_string(),
+                bid: 1.25000,
+                ask: 1.25020,
+                base: "GBP".to_string(),
+                quote: "USD".to_string(),
+                date: None,
+            }])
+            .await;
+
+        let check = engine.can_open("ACC1", "EURGBP", 0.1, true).await.unwrap();
+        assert!(check.required_margin > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_ids_in_batch_keep_newest() {
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let now = Utc::now();
+        let newer = MicroEngineBidask {
+            bid: 1.2,
+            ask: 1.3,
+            date: Some(now),
+            ..sample_bidask()
+        };
+        let older = MicroEngineBidask {
+            bid: 1.15,
+            ask: 1.25,
+            date: Some(now - chrono::Duration::seconds(1)),
+            ..sample_bidask()
+        };
+
+        let rejected = engine.handle_new_price(vec![newer, older]).await;
+
+        let cached = engine.bidask_cache.get_by_id("EURUSD").unwrap();
+        assert_eq!(rejected, 0);
+        assert_eq!((cached.bid, cached.ask), (1.2, 1.3));
+        assert_eq!(cached.date, Some(now));
+        assert!(engine.updated_assets.contains("EURUSD"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_account_with_settings() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.instruments.get_mut("EURUSD").unwrap().max_leverage = Some(50.0);
+
+        let (engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![settings.clone()],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let actual = engine.accounts.get_account("ACC1").unwrap().clone();
+
+        settings.instruments.get_mut("EURUSD").unwrap().max_leverage = Some(100.0);
+        let preview = engine
+            .preview_account_with_settings("ACC1", &settings)
+            .await
+            .unwrap();
+
+        assert!((preview.margin - actual.margin / 2.0).abs() < 1e-6);
+        assert!((preview.equity - actual.equity).abs() < 1e-6);
+
+        let stored = engine.accounts.get_account("ACC1").unwrap();
+        assert_eq!(stored.margin, actual.margin);
+        assert_eq!(
+            engine.settings_cache.get_group("tg1").unwrap().instruments["EURUSD"].max_leverage,
+            Some(50.0)
+        );
+        assert!(
+            engine
+                .preview_account_with_settings("ACC2", &settings)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_lots_per_instrument() {
+        let price = sample_bidask();
+        let position = |id: &str, lots_amount: f64| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings.instruments.get_mut("EURUSD").unwrap().max_lots = Some(1.0);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price.clone()],
+        )
+        .await;
+
+        assert!(
+            engine
+                .insert_or_update_position(position("P1", 0.1))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            engine.insert_or_update_position(position("P2", 1.5)).await,
+            Err(MicroEngineError::InvalidLotSize { lots_amount, .. }) if lots_amount == 1.5
+        ));
+        assert_eq!(engine.get_instrument_positions("EURUSD").await.len(), 1);
+
+        let check = engine.can_open("ACC1", "EURUSD", 1.5, true).await.unwrap();
+        assert!(!check.allowed);
+        assert_eq!(check.reject_reason, Some(OrderRejectReason::InvalidLotSize));
+
+        let check = engine.can_open("ACC1", "EURUSD", 1.0, true).await.unwrap();
+        assert!(check.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_apply_settings_batch() {
+        let account = |id: &str, group: &str| MicroEngineAccount {
+            id: id.to_string(),
+            trading_group: group.to_string(),
+            ..sample_account()
+        };
+        let group = |id: &str| MicroEngineTradingGroupSettings {
+            id: id.to_string(),
+            ..sample_settings()
+        };
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![
+                account("ACC1", "tg1"),
+                account("ACC2", "tg1"),
+                account("ACC3", "tg2"),
+                account("ACC4", "tg3"),
+            ],
+            Vec::<MicroEnginePosition>::new(),
+            vec![group("tg1"), group("tg2"), group("tg3")],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        let reloaded = ["tg1", "tg2"]
+            .into_iter()
+            .map(|id| MicroEngineTradingGroupSettings {
+                max_leverage: Some(50.0),
+                ..group(id)
+            })
+            .collect();
+
+        let updates = engine.apply_settings_batch(reloaded).await;
+
+        let ids = updates
+            .iter()
+            .map(|x| x.account_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["ACC1", "ACC2", "ACC3"]);
+        assert_eq!(
+            engine.settings_cache.get_group("tg2").unwrap().max_leverage,
+            Some(50.0)
+        );
+        assert_eq!(
+            engine.settings_cache.get_group("tg3").unwrap().max_leverage,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_move_account_to_group_with_lower_max_leverage() {
+        let mut reject = sample_settings();
+        reject.id = "tg2".to_string();
+        reject.max_leverage = Some(50.0);
+        reject.account_leverage_policy = crate::settings::AccountLeveragePolicy::Reject;
+
+        let mut clamp = reject.clone();
+        clamp.id = "tg3".to_string();
+        clamp.account_leverage_policy = crate::settings::AccountLeveragePolicy::Clamp;
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            Vec::<MicroEnginePosition>::new(),
+            vec![sample_settings(), reject, clamp],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![sample_bidask()],
+        )
+        .await;
+
+        assert!(matches!(
+            engine.move_account_to_group("ACC1", "tg2").await,
+            Err(MicroEngineError::LeverageExceedsGroupMax { max_leverage, .. }) if max_leverage == 50.0
+        ));
+        // Nothing moved: neither the stored account nor the group mapping
+        assert_eq!(
+            engine.accounts.get_account("ACC1").unwrap().trading_group,
+            "tg1"
+        );
+        assert_eq!(
+            engine.settings_cache.resolve_by_account("ACC1").unwrap().id,
+            "tg1"
+        );
+
+        assert!(engine.move_account_to_group("ACC1", "tg3").await.is_ok());
+        let account = engine.accounts.get_account("ACC1").unwrap();
+        assert_eq!(account.trading_group, "tg3");
+        assert_eq!(account.leverage, 50.0);
+    }
+
+    #[tokio::test]
+    async fn test_recalculate_position_keeps_closed_market_frozen() {
+        let price = sample_bidask();
+        let position = MicroEnginePosition {
+            id: "P1".to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings();
+        settings
+            .instruments
+            .get_mut("EURUSD")
+            .unwrap()
+            .trading_hours = Some(vec![]);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position],
+            vec![settings],
+            HashSet::from(["USD".to_string()]),
+            vec![sample_instrument()],
+            vec![price],
+        )
+        .await;
+
+        let before = engine.positions_cache.get_position("P1").unwrap().pl;
+        engine
+            .handle_new_price(vec![MicroEngineBidask {
+                bid: 1.2,
+                ask: 1.3,
+                ..sample_bidask()
+            }])
+            .await;
+
+        // The tick path leaves the position alone while its market is closed, and so does this
+        let update = engine.recalculate_position("P1").await.unwrap();
+
+        assert_eq!(update.delta, 0.0);
+        assert_eq!(
+            engine.positions_cache.get_position("P1").unwrap().pl,
+            before
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preview_matches_applied_settings() {
+        let price = sample_bidask();
+        let position = |id: &str, asset_pair: &str| MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: asset_pair.to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: MicroEngineBidask {
+                id: asset_pair.to_string(),
+                ..price.clone()
+            },
+            active_bidask: MicroEngineBidask {
+                id: asset_pair.to_string(),
+                ..price.clone()
+            },
+            margin_bidask: MicroEngineBidask {
+                id: asset_pair.to_string(),
+                ..price.clone()
+            },
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut settings = sample_settings_with_markup();
+        let closed = TradingGroupInstrumentSettings {
+            trading_hours: Some(vec![]),
+            ..settings.instruments["EURUSD"].clone()
+        };
+        settings.instruments.insert("EURGBP".to_string(), closed);
+
+        let (mut engine, _, _) = MicroEngine::initialize(
+            vec![sample_account()],
+            vec![position("P1", "EURUSD"), position("P2", "EURGBP")],
+            vec![sample_settings()],
+            HashSet::from(["USD".to_string()]),
+            vec![
+                sample_instrument(),
+                MicroEngineInstrument {
+                    id: "EURGBP".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "USD".to_string(),
+                },
+            ],
+            vec![
+                price.clone(),
+                MicroEngineBidask {
+                    id: "EURGBP".to_string(),
+                    bid: 1.3,
+                    ask: 1.4,
+                    ..price.clone()
+                },
+            ],
+        )
+        .await;
+
+        let preview = engine
+            .preview_account_with_settings("ACC1", &settings)
+            .await
+            .unwrap();
+
+        engine.trading_settings_changed(settings).await;
+        let applied = engine.recalculate_group("tg1").await.remove(0);
+
+        // The closed instrument keeps its figures in both
+        assert!((preview.equity - applied.equity).abs() < 1e-9);
+        assert!((preview.margin - applied.margin).abs() < 1e-9);
+    }
 }
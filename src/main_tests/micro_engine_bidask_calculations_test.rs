@@ -19,6 +19,14 @@ mod tests {
                 digits: 5,
                 max_leverage: None,
                 markup_settings: None,
+                margin_price_mode: Default::default(),
+                kind: Default::default(),
+                commission_model: Default::default(),
+                lot_step: None,
+                min_lots: None,
+                max_lots: None,
+                trading_hours: None,
+                default_contract_size: None,
             },
         );
         MicroEngineTradingGroupSettings {
@@ -28,6 +36,17 @@ mod tests {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         }
     }
 
@@ -37,11 +56,16 @@ mod tests {
             trader_id: "TR1".to_string(),
             trading_group: "G1".to_string(),
             balance: 1000.0,
+            credit: 0.0,
             leverage: 100.0,
             margin: 0.0,
             equity: 0.0,
             free_margin: 0.0,
             margin_level: 0.0,
+            realized_pl: 0.0,
+            deposit_currency: None,
+            last_calculated: None,
+            raw_spread: false,
         }
     }
 
@@ -60,6 +84,7 @@ mod tests {
             ask: 1.1,
             base: "EUR".to_string(),
             quote: "USD".to_string(),
+            date: None,
         }
     }
 
@@ -82,8 +107,14 @@ mod tests {
             active_bidask: price.clone(),
             margin_bidask: price.clone(),
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: Vec::new(),
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         }
     }
 
@@ -95,7 +126,7 @@ mod tests {
         let bidask = sample_bidask();
 
         let collaterals = HashSet::from(["USD".to_string()]);
-        let (mut engine, errors) = MicroEngine::initialize(
+        let (mut engine, errors, _) = MicroEngine::initialize(
             vec![account.clone()],
             Vec::<MicroEnginePosition>::new(),
             vec![settings],
@@ -116,6 +147,7 @@ mod tests {
             ask: 1.3,
             base: "EUR".to_string(),
             quote: "USD".to_string(),
+            date: None,
         };
         engine.handle_new_price(vec![new_price]).await;
         let (acc_updates, pos_updates) = engine.recalculate_accordint_to_updates().await;
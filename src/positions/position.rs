@@ -1,9 +1,11 @@
-use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Datelike, Utc, Weekday};
 
 use crate::{
     bidask::{MicroEngineBidAskCache, dto::MicroEngineBidask},
     round_float_to_digits,
-    settings::MicroEngineTradingGroupSettings,
+    settings::{InstrumentKind, MicroEngineTradingGroupSettings, MissingConversionPolicy},
 };
 
 #[derive(Default, Clone, Debug)]
@@ -29,9 +31,22 @@ pub struct MicroEnginePosition {
     pub open_bidask: MicroEngineBidask,
     pub active_bidask: MicroEngineBidask,
     pub margin_bidask: MicroEngineBidask,
+    /// Converts 1 unit of `quote` into `collateral`. Bid converts profits, ask converts losses.
     pub profit_bidask: MicroEngineBidask,
-    pub profit_price_assets_subscriptions: Vec<String>,
+    /// Instrument ids whose ticks move `profit_bidask`.
+    pub profit_price_assets_subscriptions: HashSet<String>,
     pub swaps_sum: f64,
+    /// Floating P&L booked by partial closes. Not part of `get_gross_pl`.
+    pub realized_pl: f64,
+    /// Leverage fixed at open. Overrides the account/group leverage for this position's margin.
+    pub leverage: Option<f64>,
+    pub open_time: Option<DateTime<Utc>>,
+    /// Swaps have been accrued up to this moment. Accrual starts from `open_time` when `None`.
+    pub last_swap_accrual: Option<DateTime<Utc>>,
+    /// Placeholder for a pending order. Indexed for subscriptions, ignored for margin and P&L.
+    pub is_pending: bool,
+    /// Opaque passthrough data, e.g. strategy id or source. Stored and returned, never read.
+    pub metadata: Option<HashMap<String, String>>,
 }
 
 impl MicroEnginePosition {
@@ -40,17 +55,56 @@ impl MicroEnginePosition {
     }
 
 
+    /// Returns `false` if the position was left without a P&L update because its conversion
+    /// price is missing under `MissingConversionPolicy::SkipUpdate` or its market is closed at
+    /// the tick's time.
     pub fn update_bidask(
         &mut self,
         bidask: &MicroEngineBidask,
         bidask_cache: &mut MicroEngineBidAskCache,
         settings: &MicroEngineTradingGroupSettings,
-    ) {
+    ) -> bool {
+        self.update_bidask_from_snapshot(bidask, bidask_cache, settings, false)
+    }
+
+    /// `update_bidask` that only reads the cache, so it can run against a shared snapshot.
+    /// With `raw_spread` the active bidask takes the raw price, skipping markup and spread limits.
+    pub(crate) fn update_bidask_from_snapshot(
+        &mut self,
+        bidask: &MicroEngineBidask,
+        bidask_cache: &MicroEngineBidAskCache,
+        settings: &MicroEngineTradingGroupSettings,
+        raw_spread: bool,
+    ) -> bool {
+        // Closed market: P&L stays frozen until the instrument reopens
+        if let Some(position_instrument) = settings.instruments.get(&self.asset_pair)
+            && !position_instrument.is_open(bidask.date.unwrap_or_else(Utc::now))
+        {
+            return false;
+        }
+
         let Some(instrument_settings) = settings.instruments.get(&bidask.id) else {
-            return;
+            return true;
         };
 
-        let (new_bid, new_ask) = instrument_settings.calculate_bidask(bidask);
+        // UseLastKnown keeps whatever profit price is there, other policies need to know whether
+        // the conversion is currently resolvable. Checked before any price is touched, so a
+        // skipped update leaves the position as it was
+        if self.quote != self.collateral
+            && settings.missing_conversion_policy != MissingConversionPolicy::UseLastKnown
+            && bidask_cache
+                .get_price(&self.quote, &self.collateral)
+                .is_none()
+            && !self.apply_missing_conversion_policy(settings)
+        {
+            return false;
+        }
+
+        let (new_bid, new_ask) = if raw_spread {
+            (bidask.bid, bidask.ask)
+        } else {
+            instrument_settings.calculate_bidask_for_volume(bidask, self.lots_amount)
+        };
 
         if self.asset_pair == bidask.id {
             // Update active_bidask with markup applied
@@ -61,19 +115,14 @@ impl MicroEnginePosition {
             self.active_bidask.ask = new_ask;
         }
 
-        let mut profit_hit = false;
-
-        for asset in &self.profit_price_assets_subscriptions {
-            if asset == &bidask.id {
-                profit_hit = true;
-                break;
-            }
-        }
-
-        if profit_hit {
-            if (bidask.base == self.quote && bidask.quote == self.collateral) 
-                || (bidask.base == self.collateral && bidask.quote == self.quote) {
-                
+        if self.subscribes_to(&bidask.id) {
+            if let Some(pinned) =
+                bidask_cache.get_conversion_override(&self.quote, &self.collateral)
+            {
+                self.profit_bidask = pinned;
+            } else if (bidask.base == self.quote && bidask.quote == self.collateral)
+                || (bidask.base == self.collateral && bidask.quote == self.quote)
+            {
                 // Match trading-engine behavior: use raw bidask, reverse if needed
                 let mut profit_price = bidask.clone();
                 
@@ -92,24 +141,153 @@ impl MicroEnginePosition {
         }
 
         self.recalculate_pl(settings);
+
+        true
     }
 
-    /// Recalculate PL based on current open_price, close_price, and profit_bidask
-    pub fn recalculate_pl(&mut self, settings: &MicroEngineTradingGroupSettings) {
+    /// Adds `swap_rate_per_lot * lots * rollover days` since the last accrual to `swaps_sum`.
+    /// Returns `false` if the position has no open time to accrue from.
+    pub fn accrue_swaps(
+        &mut self,
+        now: DateTime<Utc>,
+        swap_rate_per_lot: f64,
+        triple_swap_weekday: Weekday,
+    ) -> bool {
+        let Some(from) = self.last_swap_accrual.or(self.open_time) else {
+            return false;
+        };
+
+        if now <= from {
+            return false;
+        }
+
+        let days = count_rollover_days(from, now, triple_swap_weekday);
+        self.swaps_sum += swap_rate_per_lot * self.lots_amount * days as f64;
+        self.last_swap_accrual = Some(now);
+
+        true
+    }
+
+    /// Returns `false` when the policy asks to skip the update.
+    fn apply_missing_conversion_policy(
+        &mut self,
+        settings: &MicroEngineTradingGroupSettings,
+    ) -> bool {
+        match settings.missing_conversion_policy {
+            MissingConversionPolicy::UseLastKnown => true,
+            MissingConversionPolicy::SkipUpdate => false,
+            MissingConversionPolicy::Zero => {
+                self.profit_bidask.bid = 0.0;
+                self.profit_bidask.ask = 0.0;
+                true
+            }
+        }
+    }
+
+    /// Price movement in the position's favour: close - open for buys, open - close for sells
+    fn price_diff(&self) -> f64 {
         let open_price = self.open_bidask.get_open_price(self.is_buy);
         let close_price = self.active_bidask.get_close_price(self.is_buy);
 
-        let diff = match self.is_buy {
+        match self.is_buy {
             true => close_price - open_price,
             false => open_price - close_price,
-        };
+        }
+    }
+
+    /// Unrealized PL in the instrument's quote currency, before conversion to collateral
+    pub fn unrealized_pl_quote(&self) -> f64 {
+        self.price_diff() * self.lots_amount * self.contract_size
+    }
+
+    /// Gross P&L in percent of the notional at open. Zero for a zero notional.
+    pub fn return_percent(&self) -> f64 {
+        let notional =
+            self.lots_amount * self.contract_size * self.open_bidask.get_open_price(self.is_buy);
+
+        match notional == 0.0 {
+            true => 0.0,
+            false => self.get_gross_pl() / notional * 100.0,
+        }
+    }
 
-        let profit_price = match diff >= 0.0 {
+    /// Scales into the position: adds `add_lots` opened at `add_price` and moves the open price
+    /// of the position's side to the volume-weighted average.
+    pub fn average_in(&mut self, add_lots: f64, add_price: f64) {
+        let total_lots = self.lots_amount + add_lots;
+
+        if total_lots > 0.0 {
+            let open_price = self.open_bidask.get_open_price(self.is_buy);
+            let average_price = (open_price * self.lots_amount + add_price * add_lots) / total_lots;
+
+            self.open_bidask
+                .update_open_price(self.is_buy, average_price);
+        }
+
+        self.lots_amount = total_lots;
+    }
+
+    /// Whether ticks of instrument `id` update the position's profit price.
+    pub fn subscribes_to(&self, id: &str) -> bool {
+        self.profit_price_assets_subscriptions.contains(id)
+    }
+
+    /// Notional in collateral at the current mid price, converted with the profit price bid.
+    /// Negative for sells.
+    pub fn signed_notional(&self) -> f64 {
+        let mid = (self.active_bidask.bid + self.active_bidask.ask) / 2.0;
+        let notional = self.lots_amount * self.contract_size * mid * self.profit_bidask.bid;
+
+        match self.is_buy {
+            true => notional,
+            false => -notional,
+        }
+    }
+
+    /// Backfills a missing (zero) `contract_size` from the instrument's `default_contract_size`.
+    pub fn apply_default_contract_size(&mut self, settings: &MicroEngineTradingGroupSettings) {
+        if self.contract_size == 0.0
+            && let Some(default_contract_size) = settings
+                .instruments
+                .get(&self.asset_pair)
+                .and_then(|x| x.default_contract_size)
+        {
+            self.contract_size = default_contract_size;
+        }
+    }
+
+    /// Splits the forex P&L into `(price_pl_in_quote, conversion_effect)`: the move in quote
+    /// currency and what converting it at the profit price adds on top. Their sum is `pl`
+    /// before rounding.
+    pub fn pl_components(&self) -> (f64, f64) {
+        let quote_pl = self.unrealized_pl_quote();
+
+        (quote_pl, quote_pl * self.profit_price() - quote_pl)
+    }
+
+    /// Conversion price for the current P&L: bid converts a profit, ask a loss.
+    fn profit_price(&self) -> f64 {
+        match self.price_diff() >= 0.0 {
             true => self.profit_bidask.bid,
             false => self.profit_bidask.ask,
-        };
+        }
+    }
 
-        let raw_pl = diff * self.lots_amount * self.contract_size * profit_price;
+    /// Recalculate PL based on current open_price, close_price, and profit_bidask
+    pub fn recalculate_pl(&mut self, settings: &MicroEngineTradingGroupSettings) {
+        let diff = self.price_diff();
+        let profit_price = self.profit_price();
+
+        let kind = settings
+            .instruments
+            .get(&self.asset_pair)
+            .map(|x| x.kind)
+            .unwrap_or_default();
+
+        let raw_pl = match kind {
+            InstrumentKind::Forex => diff * self.lots_amount * self.contract_size * profit_price,
+            InstrumentKind::Cfd { point_value } => diff * self.lots_amount * point_value,
+        };
         
         // Use collateral currency digits for rounding, matching trading-engine-core behavior
         let digits = settings
@@ -122,19 +300,59 @@ impl MicroEnginePosition {
     }
 
     /// Update profit_bidask from raw cache prices (no markup), matching trading-engine behavior
-    pub fn update_profit_bidask_from_cache(&mut self, bidask_cache: &mut MicroEngineBidAskCache) {
+    /// A missing conversion price is pulled from the cache's price provider, if any.
+    /// Returns `false` if the conversion price is missing and the group's
+    /// `MissingConversionPolicy` asks to skip the update.
+    pub fn update_profit_bidask_from_cache(
+        &mut self,
+        bidask_cache: &mut MicroEngineBidAskCache,
+        settings: &MicroEngineTradingGroupSettings,
+    ) -> bool {
+        bidask_cache.fetch_missing(&self.quote, &self.collateral);
+
+        self.update_profit_bidask_from_snapshot(bidask_cache, settings)
+    }
+
+    /// `update_profit_bidask_from_cache` that only reads the cache.
+    pub(crate) fn update_profit_bidask_from_snapshot(
+        &mut self,
+        bidask_cache: &MicroEngineBidAskCache,
+        settings: &MicroEngineTradingGroupSettings,
+    ) -> bool {
         // Only update if position needs currency conversion
         if self.quote == self.collateral {
-            return;
+            return true;
         }
 
         // Match trading-engine behavior: get raw price from cache, no markup
-        if let Some(profit_price) = bidask_cache.get_price(&self.quote, &self.collateral) {
-            self.profit_bidask = profit_price;
+        match bidask_cache.get_price(&self.quote, &self.collateral) {
+            Some(profit_price) => {
+                self.profit_bidask = profit_price;
+                true
+            }
+            None => self.apply_missing_conversion_policy(settings),
         }
     }
 }
 
+/// Rollovers happen at midnight UTC. The rollover closing `triple_swap_weekday` counts three
+/// days to cover the weekend, the ones closing Saturday and Sunday count none.
+fn count_rollover_days(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    triple_swap_weekday: Weekday,
+) -> u32 {
+    from.date_naive()
+        .iter_days()
+        .take_while(|day| *day < to.date_naive())
+        .map(|day| match day.weekday() {
+            Weekday::Sat | Weekday::Sun => 0,
+            weekday if weekday == triple_swap_weekday => 3,
+            _ => 1,
+        })
+        .sum()
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::{HashMap, HashSet};
@@ -160,6 +378,7 @@ mod test {
                 ask: 1.15173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -172,6 +391,14 @@ mod test {
                         digits: 5,
                         max_leverage: None,
                         markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -180,6 +407,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -201,6 +439,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -208,6 +447,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -215,10 +455,17 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -228,6 +475,7 @@ mod test {
                 ask: 1.07113,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -251,6 +499,7 @@ mod test {
                 ask: 1.15173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -269,7 +518,21 @@ mod test {
                             markup_ask: 135.0 * point_size,
                             min_spread: None,
                             max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -278,6 +541,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -299,6 +573,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -306,6 +581,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -313,10 +589,17 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -326,6 +609,7 @@ mod test {
                 ask: 1.07113,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -351,6 +635,7 @@ mod test {
                 ask: 1.15173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -369,7 +654,21 @@ mod test {
                             markup_ask: 0.0 * point_size,
                             min_spread: Some(10.0 * point_size),
                             max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -378,6 +677,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -399,6 +709,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -406,6 +717,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -413,10 +725,17 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -426,6 +745,7 @@ mod test {
                 ask: 1.07113,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -451,6 +771,7 @@ mod test {
                 ask: 1.15173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -469,7 +790,21 @@ mod test {
                             markup_ask: 0.0 * point_size,
                             min_spread: None,
                             max_spread: Some(10.0 * point_size),
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -478,6 +813,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -499,6 +845,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -506,6 +853,7 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -513,10 +861,17 @@ mod test {
                 ask: 1.05173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -526,6 +881,7 @@ mod test {
                 ask: 1.07121,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -551,6 +907,7 @@ mod test {
                 ask: 1.15173,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -568,6 +925,14 @@ mod test {
                         //     markup_ask: -55.0 * (1f64 / 10f64.powi(5 as i32)),
                         //     min_spread: None,
                         //     max_spread: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -576,6 +941,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -597,6 +973,7 @@ mod test {
                 ask: 1.16823,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -604,6 +981,7 @@ mod test {
                 ask: 1.16823,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -611,10 +989,17 @@ mod test {
                 ask: 1.16804,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -624,6 +1009,7 @@ mod test {
                 ask: 1.16804,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -648,6 +1034,7 @@ mod test {
                 ask: 1.35555,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -664,7 +1051,21 @@ mod test {
                             markup_ask: -250.0 * 0.00001,
                             min_spread: None,
                             max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -673,6 +1074,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -694,6 +1106,7 @@ mod test {
                 ask: 1.35555,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -701,6 +1114,7 @@ mod test {
                 ask: 1.35555,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -708,10 +1122,17 @@ mod test {
                 ask: 1.35555,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -721,6 +1142,7 @@ mod test {
                 ask: 1.55555,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -744,6 +1166,7 @@ mod test {
                 ask: 1.25580,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -760,7 +1183,21 @@ mod test {
                             markup_ask: 0.0,
                             min_spread: None,
                             max_spread: Some(0.00020),
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -769,6 +1206,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -790,6 +1238,7 @@ mod test {
                 ask: 1.25580,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -797,6 +1246,7 @@ mod test {
                 ask: 1.25580,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -804,10 +1254,17 @@ mod test {
                 ask: 1.25580,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -817,6 +1274,7 @@ mod test {
                 ask: 1.25580,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -843,6 +1301,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             }],
         );
 
@@ -859,7 +1318,21 @@ mod test {
                             markup_ask: 0.0,
                             min_spread: Some(0.00020),
                             max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -868,6 +1341,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -889,6 +1373,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -896,6 +1381,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURUSD".to_string(),
@@ -903,10 +1389,17 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec![],
+            profit_price_assets_subscriptions: HashSet::new(),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         position.update_bidask(
@@ -916,6 +1409,7 @@ mod test {
                 ask: 1.25542,
                 base: "EUR".to_string(),
                 quote: "USD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -945,6 +1439,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             }],
         );
 
@@ -957,6 +1452,14 @@ mod test {
                         digits: 5,
                         max_leverage: None,
                         markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -965,6 +1468,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -986,6 +1500,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -993,6 +1508,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1000,10 +1516,18 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec!["USDCAD".to_string()], // CAD->USD needs USDCAD
+            // CAD->USD needs USDCAD
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         // Update with new price: USDCAD rises to 1.3600
@@ -1014,6 +1538,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1052,6 +1577,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             }],
         );
 
@@ -1064,6 +1590,14 @@ mod test {
                         digits: 5,
                         max_leverage: None,
                         markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -1072,6 +1606,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -1093,6 +1638,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1100,6 +1646,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1107,10 +1654,17 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec!["USDCAD".to_string()],
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         // Update with new price: USDCAD drops to 1.3500
@@ -1121,6 +1675,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1156,6 +1711,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             }],
         );
 
@@ -1174,7 +1730,21 @@ mod test {
                             markup_ask: 50.0 * point_size,   // +0.0005
                             min_spread: None,
                             max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
                         }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -1183,6 +1753,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -1204,6 +1785,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1211,6 +1793,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1218,10 +1801,17 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec!["USDCAD".to_string()],
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         // Update with new price: USDCAD rises to 1.3600
@@ -1232,6 +1822,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1284,6 +1875,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             }],
         );
 
@@ -1296,6 +1888,14 @@ mod test {
                         digits: 5,
                         max_leverage: None,
                         markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
                     },
                 )]
                 .into_iter(),
@@ -1304,6 +1904,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -1325,6 +1936,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1332,6 +1944,7 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "USDCAD".to_string(),
@@ -1339,10 +1952,18 @@ mod test {
                 ask: 1.3602,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
-            profit_price_assets_subscriptions: vec!["USDCAD".to_string()], // Needs USDCAD to convert CAD->USD
+            // Needs USDCAD to convert CAD->USD
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         // Price moves down: USDCAD drops from 1.3600 to 1.3500 (profit for SELL)
@@ -1353,6 +1974,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1426,6 +2048,7 @@ mod test {
                     ask: 1.4502,
                     base: "EUR".to_string(),
                     quote: "CAD".to_string(),
+                    date: None,
                 },
                 MicroEngineBidask {
                     id: "EURUSD".to_string(),
@@ -1433,6 +2056,7 @@ mod test {
                     ask: 1.0802,
                     base: "EUR".to_string(),
                     quote: "USD".to_string(),
+                    date: None,
                 },
                 MicroEngineBidask {
                     id: "USDCAD".to_string(),
@@ -1440,6 +2064,7 @@ mod test {
                     ask: 1.3402,
                     base: "USD".to_string(),
                     quote: "CAD".to_string(),
+                    date: None,
                 },
             ],
         );
@@ -1454,6 +2079,14 @@ mod test {
                             digits: 5,
                             max_leverage: None,
                             markup_settings: None,
+                            margin_price_mode: Default::default(),
+                            kind: Default::default(),
+                            commission_model: Default::default(),
+                            lot_step: None,
+                            min_lots: None,
+                            max_lots: None,
+                            trading_hours: None,
+                            default_contract_size: None,
                         },
                     ),
                     (
@@ -1462,6 +2095,14 @@ mod test {
                             digits: 5,
                             max_leverage: None,
                             markup_settings: None,
+                            margin_price_mode: Default::default(),
+                            kind: Default::default(),
+                            commission_model: Default::default(),
+                            lot_step: None,
+                            min_lots: None,
+                            max_lots: None,
+                            trading_hours: None,
+                            default_contract_size: None,
                         },
                     ),
                     (
@@ -1470,6 +2111,14 @@ mod test {
                             digits: 5,
                             max_leverage: None,
                             markup_settings: None,
+                            margin_price_mode: Default::default(),
+                            kind: Default::default(),
+                            commission_model: Default::default(),
+                            lot_step: None,
+                            min_lots: None,
+                            max_lots: None,
+                            trading_hours: None,
+                            default_contract_size: None,
                         },
                     ),
                 ]
@@ -1479,6 +2128,17 @@ mod test {
             collaterals: HashMap::from_iter(vec![
                 ("USD".to_string(), crate::settings::CollateralSettings { digits: 2 }),
             ]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
         };
 
         let mut position = MicroEnginePosition {
@@ -1500,6 +2160,7 @@ mod test {
                 ask: 1.4502,
                 base: "EUR".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             active_bidask: MicroEngineBidask {
                 id: "EURCAD".to_string(),
@@ -1507,6 +2168,7 @@ mod test {
                 ask: 1.4502,
                 base: "EUR".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             margin_bidask: MicroEngineBidask {
                 id: "EURCAD".to_string(),
@@ -1514,11 +2176,21 @@ mod test {
                 ask: 1.4502,
                 base: "EUR".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             profit_bidask: MicroEngineBidask::create_blank(),
             // IMPORTANT: subscribes to both USDCAD (direct) and EURUSD (for cross-rate fallback)
-            profit_price_assets_subscriptions: vec!["USDCAD".to_string(), "EURUSD".to_string()],
+            profit_price_assets_subscriptions: HashSet::from([
+                "USDCAD".to_string(),
+                "EURUSD".to_string(),
+            ]),
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         };
 
         // First update: EURCAD price moves (position instrument)
@@ -1528,6 +2200,7 @@ mod test {
             ask: 1.4402,
             base: "EUR".to_string(),
             quote: "CAD".to_string(),
+            date: None,
         });
         
         position.update_bidask(
@@ -1537,6 +2210,7 @@ mod test {
                 ask: 1.4402,
                 base: "EUR".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1562,6 +2236,7 @@ mod test {
             ask: 1.3502,
             base: "USD".to_string(),
             quote: "CAD".to_string(),
+            date: None,
         });
         
         position.update_bidask(
@@ -1571,6 +2246,7 @@ mod test {
                 ask: 1.3502,
                 base: "USD".to_string(),
                 quote: "CAD".to_string(),
+                date: None,
             },
             &mut bidask_cache,
             &settings,
@@ -1586,4 +2262,727 @@ mod test {
         
         assert!(position.profit_bidask.bid > 0.74 && position.profit_bidask.bid < 0.75);
     }
+
+    #[tokio::test]
+    pub async fn test_unrealized_pl_quote_usdcad() {
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from_iter(vec!["USD".to_string()].into_iter()),
+            vec![MicroEngineInstrument {
+                id: "USDCAD".to_string(),
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+            }],
+            vec![MicroEngineBidask {
+                id: "USDCAD".to_string(),
+                bid: 1.3500,
+                ask: 1.3502,
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+                date: None,
+            }],
+        );
+
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::from_iter(vec![(
+                "USDCAD".to_string(),
+                TradingGroupInstrumentSettings {
+                    digits: 5,
+                    max_leverage: None,
+                    markup_settings: None,
+                    margin_price_mode: Default::default(),
+                    kind: Default::default(),
+                    commission_model: Default::default(),
+                    lot_step: None,
+                    min_lots: None,
+                    max_lots: None,
+                    trading_hours: None,
+                    default_contract_size: None,
+                },
+            )]),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                crate::settings::CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let usdcad = MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.3500,
+            ask: 1.3502,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "USDCAD".to_string(),
+            lots_amount: 1.0,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: usdcad.clone(),
+            active_bidask: usdcad.clone(),
+            margin_bidask: usdcad.clone(),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        position.update_bidask(
+            &MicroEngineBidask {
+                id: "USDCAD".to_string(),
+                bid: 1.3600,
+                ask: 1.3602,
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+                date: None,
+            },
+            &mut bidask_cache,
+            &settings,
+        );
+
+        // (1.3600 - 1.3502) * 1.0 * 100000 = 980 CAD
+        assert_eq!(format!("{:.2}", position.unrealized_pl_quote()), "980.00");
+        // 980 CAD * 1 / 1.3602 = 720.48 USD
+        assert_eq!(format!("{:.2}", position.pl), "720.48");
+    }
+
+    #[tokio::test]
+    pub async fn test_three_digit_instrument_with_zero_digit_collateral() {
+        let usdjpy = |bid: f64, ask: f64| MicroEngineBidask {
+            id: "USDJPY".to_string(),
+            bid,
+            ask,
+            base: "USD".to_string(),
+            quote: "JPY".to_string(),
+            date: None,
+        };
+
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from_iter(vec!["JPY".to_string()].into_iter()),
+            vec![MicroEngineInstrument {
+                id: "USDJPY".to_string(),
+                base: "USD".to_string(),
+                quote: "JPY".to_string(),
+            }],
+            vec![usdjpy(103.490, 103.500)],
+        );
+
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::from_iter(
+                vec![(
+                    "USDJPY".to_string(),
+                    TradingGroupInstrumentSettings {
+                        digits: 3,
+                        max_leverage: None,
+                        markup_settings: Some(TradingGroupInstrumentMarkupSettings {
+                            markup_bid: 0.0,
+                            markup_ask: 0.0,
+                            min_spread: Some(0.010),
+                            max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: None,
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
+                        }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
+                    },
+                )]
+                .into_iter(),
+            ),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "JPY".to_string(),
+                crate::settings::CollateralSettings { digits: 0 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "USD".to_string(),
+            quote: "JPY".to_string(),
+            collateral: "JPY".to_string(),
+            asset_pair: "USDJPY".to_string(),
+            lots_amount: 0.013,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: usdjpy(103.490, 103.500),
+            active_bidask: usdjpy(103.490, 103.500),
+            margin_bidask: usdjpy(103.490, 103.500),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        position.update_bidask(&usdjpy(104.123, 104.124), &mut bidask_cache, &settings);
+
+        // 1 point spread widened to 10 points, staying on the 3-digit grid
+        assert_eq!(position.active_bidask.bid, 104.118);
+        assert_eq!(position.active_bidask.ask, 104.128);
+
+        // (104.118 - 103.500) * 0.013 * 100000 = 803.4, rounded to 0 JPY digits
+        assert_eq!(position.pl, 803.0);
+    }
+
+    #[tokio::test]
+    pub async fn test_pl_calculation_cfd_point_value() {
+        let us500 = |bid: f64, ask: f64| MicroEngineBidask {
+            id: "US500".to_string(),
+            bid,
+            ask,
+            base: "US500".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from_iter(vec!["USD".to_string()].into_iter()),
+            vec![MicroEngineInstrument {
+                id: "US500".to_string(),
+                base: "US500".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![us500(5089.5, 5090.0)],
+        );
+
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::from_iter(
+                vec![(
+                    "US500".to_string(),
+                    TradingGroupInstrumentSettings {
+                        digits: 1,
+                        max_leverage: None,
+                        markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: crate::settings::InstrumentKind::Cfd { point_value: 10.0 },
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
+                    },
+                )]
+                .into_iter(),
+            ),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                crate::settings::CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "US500".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "US500".to_string(),
+            lots_amount: 2.0,
+            contract_size: 1.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: us500(5089.5, 5090.0),
+            active_bidask: us500(5089.5, 5090.0),
+            margin_bidask: us500(5089.5, 5090.0),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        position.update_bidask(&us500(5100.5, 5101.0), &mut bidask_cache, &settings);
+
+        // (5100.5 - 5090.0) * 2 lots * 10 USD per point
+        assert_eq!(format!("{:.2}", position.pl), "210.00");
+    }
+
+    #[tokio::test]
+    pub async fn test_volume_markup_widens_large_positions() {
+        let eurusd = |bid: f64, ask: f64| MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid,
+            ask,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from_iter(vec!["USD".to_string()].into_iter()),
+            vec![MicroEngineInstrument {
+                id: "EURUSD".to_string(),
+                base: "EUR".to_string(),
+                quote: "USD".to_string(),
+            }],
+            vec![eurusd(1.08000, 1.08010)],
+        );
+
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::from_iter(
+                vec![(
+                    "EURUSD".to_string(),
+                    TradingGroupInstrumentSettings {
+                        digits: 5,
+                        max_leverage: None,
+                        markup_settings: Some(TradingGroupInstrumentMarkupSettings {
+                            markup_bid: 0.0,
+                            markup_ask: 0.0,
+                            min_spread: None,
+                            max_spread: None,
+                            markup_order: Default::default(),
+                            spread_clamp_order: Default::default(),
+                            spread_rounding: Default::default(),
+                            volume_markup: Some(vec![(1.0, 5.0), (5.0, 20.0)]),
+                            clamp_preserves_mid: false,
+                            on_inverted: Default::default(),
+                        }),
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
+                    },
+                )]
+                .into_iter(),
+            ),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                crate::settings::CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let position = |lots_amount: f64| MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: eurusd(1.08000, 1.08010),
+            active_bidask: eurusd(1.08000, 1.08010),
+            margin_bidask: eurusd(1.08000, 1.08010),
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let mut small = position(0.1);
+        let mut large = position(10.0);
+
+        small.update_bidask(&eurusd(1.08100, 1.08110), &mut bidask_cache, &settings);
+        large.update_bidask(&eurusd(1.08100, 1.08110), &mut bidask_cache, &settings);
+
+        // below the first tier, no extra spread
+        assert_eq!(format!("{:.5}", small.active_bidask.bid), "1.08100");
+        assert_eq!(format!("{:.5}", small.active_bidask.ask), "1.08110");
+
+        // 20 extra points of spread, 10 on each side
+        assert_eq!(format!("{:.5}", large.active_bidask.bid), "1.08090");
+        assert_eq!(format!("{:.5}", large.active_bidask.ask), "1.08120");
+    }
+
+    #[tokio::test]
+    pub async fn test_pl_calculation_usdcad_with_pinned_conversion() {
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from_iter(vec!["USD".to_string()].into_iter()),
+            vec![MicroEngineInstrument {
+                id: "USDCAD".to_string(),
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+            }],
+            vec![MicroEngineBidask {
+                id: "USDCAD".to_string(),
+                bid: 1.3500,
+                ask: 1.3502,
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+                date: None,
+            }],
+        );
+        bidask_cache.set_conversion_override("CAD", "USD", 0.7);
+
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::from_iter(
+                vec![(
+                    "USDCAD".to_string(),
+                    TradingGroupInstrumentSettings {
+                        digits: 5,
+                        max_leverage: None,
+                        markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
+                    },
+                )]
+                .into_iter(),
+            ),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                crate::settings::CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let open_bidask = MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.3500,
+            ask: 1.3502,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "USDCAD".to_string(),
+            lots_amount: 0.01,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: open_bidask.clone(),
+            active_bidask: open_bidask.clone(),
+            margin_bidask: open_bidask,
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        let new_bidask = MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.3600,
+            ask: 1.3602,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        // 9.8 CAD at the pinned 0.7 instead of 1 / 1.3602
+        position.update_bidask(&new_bidask, &mut bidask_cache, &settings);
+        assert_eq!(position.profit_bidask.bid, 0.7);
+        assert_eq!(format!("{:.5}", position.get_gross_pl()), "6.86000");
+
+        bidask_cache.clear_conversion_override("CAD", "USD");
+        position.update_bidask(&new_bidask, &mut bidask_cache, &settings);
+        assert_eq!(format!("{:.5}", position.get_gross_pl()), "7.20000");
+    }
+
+    #[test]
+    fn test_return_percent() {
+        let open_bidask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.24980,
+            ask: 1.25000,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 1.0,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 250.0,
+            commission: 0.0,
+            open_bidask: open_bidask.clone(),
+            active_bidask: open_bidask.clone(),
+            margin_bidask: open_bidask,
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        // 250 / (1 * 100000 * 1.25) * 100
+        assert_eq!(format!("{:.4}", position.return_percent()), "0.2000");
+
+        position.lots_amount = 0.0;
+        assert_eq!(position.return_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_pl_components_usdcad() {
+        let settings = crate::settings::MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            instruments: HashMap::new(),
+            hedge_coef: None,
+            collaterals: HashMap::from_iter(vec![(
+                "USD".to_string(),
+                crate::settings::CollateralSettings { digits: 2 },
+            )]),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let open_bidask = MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.3500,
+            ask: 1.3502,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+        let active_bidask = MicroEngineBidask {
+            bid: 1.3600,
+            ask: 1.3602,
+            ..open_bidask.clone()
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "USDCAD".to_string(),
+            lots_amount: 1.0,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: open_bidask.clone(),
+            active_bidask: active_bidask.clone(),
+            margin_bidask: open_bidask,
+            profit_bidask: active_bidask.reverse(),
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+        position.recalculate_pl(&settings);
+
+        let (price_pl, conversion_effect) = position.pl_components();
+
+        // (1.3600 - 1.3502) * 1.0 * 100000 = 980 CAD, worth 720.48 USD
+        assert_eq!(format!("{:.2}", price_pl), "980.00");
+        assert_eq!(format!("{:.2}", conversion_effect), "-259.52");
+        assert!((price_pl + conversion_effect - position.pl).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_average_in() {
+        let open_bidask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.19980,
+            ask: 1.20000,
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            date: None,
+        };
+
+        let mut position = MicroEnginePosition {
+            id: "id".to_string(),
+            trader_id: "trader_id".to_string(),
+            account_id: "account_id".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURUSD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: open_bidask.clone(),
+            active_bidask: open_bidask.clone(),
+            margin_bidask: open_bidask,
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::new(),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        };
+
+        position.average_in(0.1, 1.10);
+
+        assert_eq!(format!("{:.2}", position.lots_amount), "0.20");
+        assert_eq!(format!("{:.5}", position.open_bidask.ask), "1.15000");
+        // The other side is left as it was
+        assert_eq!(position.open_bidask.bid, 1.19980);
+    }
+
+    #[test]
+    fn test_subscribes_to() {
+        let position = MicroEnginePosition {
+            id: "id".to_string(),
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURCAD".to_string(),
+            profit_price_assets_subscriptions: HashSet::from([
+                "USDCAD".to_string(),
+                "EURUSD".to_string(),
+            ]),
+            ..Default::default()
+        };
+
+        assert!(position.subscribes_to("USDCAD"));
+        assert!(position.subscribes_to("EURUSD"));
+        assert!(!position.subscribes_to("EURCAD"));
+        assert!(!position.subscribes_to("USD"));
+    }
 }
@@ -1,9 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc, Weekday};
 
 use crate::{
-    bidask::MicroEngineBidAskCache,
+    MicroEngineError,
+    accounts::account_cache::MicroEngineAccountCache,
+    bidask::{MicroEngineBidAskCache, dto::MicroEngineBidask},
     positions::{position::MicroEnginePosition, positions_cache_index::PositionsCacheIndex},
-    settings::TradingSettingsCache,
+    settings::{MicroEngineTradingGroupSettings, TradingSettingsCache},
 };
 
 #[derive(Debug, Clone)]
@@ -11,6 +15,30 @@ pub struct MicroEnginePositionCalculationUpdate {
     pub account_id: String,
     pub position_id: String,
     pub gross_pl: f64,
+    /// Gross P&L before this recalculation.
+    pub previous_gross_pl: f64,
+    /// `gross_pl - previous_gross_pl`.
+    pub delta: f64,
+    /// Current floating P&L.
+    pub unrealized_pl: f64,
+    /// Booked so far: P&L of partial closes plus swaps.
+    pub realized_pl: f64,
+}
+
+impl MicroEnginePositionCalculationUpdate {
+    pub(crate) fn new(position: &MicroEnginePosition, previous_gross_pl: f64) -> Self {
+        let gross_pl = position.get_gross_pl();
+
+        Self {
+            account_id: position.account_id.clone(),
+            position_id: position.id.clone(),
+            gross_pl,
+            previous_gross_pl,
+            delta: gross_pl - previous_gross_pl,
+            unrealized_pl: position.pl,
+            realized_pl: position.realized_pl + position.swaps_sum,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,39 +50,64 @@ pub struct MicroEnginePositionCache {
 impl MicroEnginePositionCache {
     pub(crate) fn new(
         bidask_cache: &MicroEngineBidAskCache,
+        settings_cache: &TradingSettingsCache,
         positions: Vec<impl Into<MicroEnginePosition>>,
-    ) -> Self {
+    ) -> (Self, Vec<MicroEngineError>) {
         let mut indexes = PositionsCacheIndex::default();
         let mut positions_cache = HashMap::new();
+        let mut errors = vec![];
 
         for position in positions {
             let mut position: MicroEnginePosition = position.into();
 
+            // A conversion price that isn't cached yet is fine as long as some instruments
+            // lead there, the position subscribes to them and is priced once they tick
             if position.quote != position.collateral {
-                if let Some((_, sources)) =
-                    bidask_cache.get_price_with_source(&position.quote, &position.collateral)
+                let sources = match bidask_cache
+                    .get_price_with_source(&position.quote, &position.collateral)
                 {
-                    position.profit_price_assets_subscriptions = sources.unwrap_or_default();
+                    Some((_, sources)) => Some(sources.unwrap_or_default()),
+                    None => bidask_cache.conversion_legs(&position.quote, &position.collateral),
+                };
+
+                match sources {
+                    Some(sources) => {
+                        position.profit_price_assets_subscriptions = sources.into_iter().collect();
+                    }
+                    None => {
+                        errors.push(MicroEngineError::UnresolvablePosition(position.id.clone()));
+                    }
                 }
             }
 
-            // Note: We don't apply markup to open_bidask here because we don't have settings yet
-            // It will be applied in recalculate_all_positions or insert_or_update_position
+            if let Some(settings) = settings_cache.resolve_by_account(&position.account_id) {
+                position.apply_default_contract_size(settings);
+            }
+
+            // Note: We don't apply markup to open_bidask here, positions from trading engine
+            // already have markup applied to open_bidask
 
             indexes.add_index(&position);
             positions_cache.insert(position.id.clone(), position);
         }
 
-        Self {
-            indexes,
-            positions: positions_cache,
-        }
+        (
+            Self {
+                indexes,
+                positions: positions_cache,
+            },
+            errors,
+        )
     }
 
     pub fn get_position(&self, id: &str) -> Option<&MicroEnginePosition> {
         self.positions.get(id)
     }
 
+    pub(crate) fn get_position_mut(&mut self, id: &str) -> Option<&mut MicroEnginePosition> {
+        self.positions.get_mut(id)
+    }
+
     pub fn get_account_positions(&self, account_id: &str) -> Option<Vec<&MicroEnginePosition>> {
         let ids = self.indexes.account_id_index.get(account_id)?;
 
@@ -66,6 +119,17 @@ impl MicroEnginePositionCache {
         Some(result)
     }
 
+    /// Account positions without pending placeholders, i.e. the ones that carry risk.
+    pub fn get_account_active_positions(
+        &self,
+        account_id: &str,
+    ) -> Option<Vec<&MicroEnginePosition>> {
+        let mut positions = self.get_account_positions(account_id)?;
+        positions.retain(|x| !x.is_pending);
+
+        Some(positions)
+    }
+
     pub fn get_trader_positions(&self, trader_id: &str) -> Option<Vec<&MicroEnginePosition>> {
         let ids = self.indexes.trader_id_index.get(trader_id)?;
 
@@ -77,17 +141,103 @@ impl MicroEnginePositionCache {
         Some(result)
     }
 
+    pub fn get_asset_pair_positions(&self, asset_pair: &str) -> Option<Vec<&MicroEnginePosition>> {
+        let ids = self.indexes.asset_pair_index.get(asset_pair)?;
+
+        let result = ids
+            .into_iter()
+            .filter_map(|x| self.positions.get(x))
+            .collect::<Vec<_>>();
+
+        Some(result)
+    }
+
+    /// Positions whose profit conversion depends on the given instrument.
+    pub fn get_positions_by_profit_subscription(
+        &self,
+        asset_id: &str,
+    ) -> Option<Vec<&MicroEnginePosition>> {
+        let ids = self
+            .indexes
+            .profit_price_subscription_indexes
+            .get(asset_id)?;
+
+        let result = ids
+            .into_iter()
+            .filter_map(|x| self.positions.get(x))
+            .collect::<Vec<_>>();
+
+        Some(result)
+    }
+
     pub fn get_all_positions(&self) -> Vec<&MicroEnginePosition> {
         self.positions.values().collect()
     }
 
+    /// Positions whose account is no longer in `account_cache`.
+    pub fn orphans(&self, account_cache: &MicroEngineAccountCache) -> Vec<&MicroEnginePosition> {
+        self.positions
+            .values()
+            .filter(|x| account_cache.get_account(&x.account_id).is_none())
+            .collect()
+    }
+
+    pub fn positions_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Page through positions ordered by id, so consecutive pages never overlap or skip entries.
+    pub fn list_positions(&self, offset: usize, limit: usize) -> Vec<&MicroEnginePosition> {
+        let mut positions = self.positions.values().collect::<Vec<_>>();
+        positions.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+
+        positions.into_iter().skip(offset).take(limit).collect()
+    }
+
     pub fn add_position(&mut self, position: impl Into<MicroEnginePosition>) {
         let position: MicroEnginePosition = position.into();
 
+        if let Some(replaced) = self.positions.get(&position.id) {
+            self.indexes.remove_indexes(replaced);
+        }
+
         self.indexes.add_index(&position);
         self.positions.insert(position.id.clone(), position);
     }
 
+    /// Rebuilds the index entries of a position from its current fields. Returns `false` if
+    /// the position is unknown.
+    pub fn reindex_position(&mut self, id: &str) -> bool {
+        let Some(position) = self.positions.get(id) else {
+            return false;
+        };
+
+        self.indexes.remove_position_id(id);
+        self.indexes.add_index(position);
+
+        true
+    }
+
+    /// Subscribes the position to `asset_id` ticks for profit conversion. Returns `false` if the
+    /// position doesn't exist.
+    pub fn add_profit_subscription(&mut self, position_id: &str, asset_id: &str) -> bool {
+        let Some(position) = self.positions.get_mut(position_id) else {
+            return false;
+        };
+
+        position
+            .profit_price_assets_subscriptions
+            .insert(asset_id.to_string());
+
+        self.indexes
+            .profit_price_subscription_indexes
+            .entry(asset_id.to_string())
+            .or_default()
+            .insert(position_id.to_string());
+
+        true
+    }
+
     pub fn remove_position(&mut self, id: &str) -> Option<MicroEnginePosition> {
         let removed_position = self.positions.remove(id)?;
         self.indexes.remove_indexes(&removed_position);
@@ -95,10 +245,40 @@ impl MicroEnginePositionCache {
         Some(removed_position)
     }
 
+    /// Asks the price provider for the conversion prices the positions on `updated_prices` can't
+    /// resolve yet, so the recalculation, which only reads the cache, finds them. Returns the
+    /// ids of the fetched prices, to be recalculated along with `updated_prices`.
+    pub fn fetch_missing_conversions(
+        &self,
+        updated_prices: &[String],
+        bidask_cache: &mut MicroEngineBidAskCache,
+    ) -> Vec<String> {
+        let mut fetched = Vec::new();
+
+        if !bidask_cache.has_price_provider() {
+            return fetched;
+        }
+
+        for price_id in updated_prices {
+            let Some(position_ids) = self.indexes.asset_pair_index.get(price_id) else {
+                continue;
+            };
+
+            for position in position_ids.iter().filter_map(|x| self.positions.get(x)) {
+                if position.quote != position.collateral {
+                    let ids = bidask_cache.fetch_missing(&position.quote, &position.collateral);
+                    fetched.extend(ids);
+                }
+            }
+        }
+
+        fetched
+    }
+
     pub fn recalculate_positions_pl(
         &mut self,
         updated_prices: &[String],
-        bidask_cache: &mut MicroEngineBidAskCache,
+        bidask_cache: &MicroEngineBidAskCache,
         settings_cache: &TradingSettingsCache,
     ) -> Option<Vec<MicroEnginePositionCalculationUpdate>> {
         if updated_prices.is_empty() {
@@ -106,98 +286,780 @@ impl MicroEnginePositionCache {
         }
 
         let mut updated_positions: Option<Vec<MicroEnginePositionCalculationUpdate>> = None;
-        for price_id in updated_prices.into_iter() {
-            
-            let target_price = match bidask_cache.get_by_id(&price_id).cloned() {
-                Some(price) => price,
-                None => continue,
+        for price_id in updated_prices {
+            // Borrowed straight from the cache, positions only ever read it
+            let Some(target_price) = bidask_cache.get_by_id(price_id) else {
+                continue;
             };
 
-            bidask_cache.handle_new(&target_price);
-
-            let mut positions = vec![];
+            let direct = self.indexes.asset_pair_index.get(price_id);
+            let profit = self.indexes.profit_price_subscription_indexes.get(price_id);
 
-            if let Some(direct_positions) = self.indexes.asset_pair_index.get(price_id) {
-                positions.extend(direct_positions);
+            for position_id in direct.into_iter().chain(profit).flatten() {
+                if let Some(position) = self.positions.get_mut(position_id)
+                    && let Some(update) = apply_price_to_position(
+                        position,
+                        target_price,
+                        bidask_cache,
+                        settings_cache,
+                    )
+                {
+                    updated_positions.get_or_insert_default().push(update);
+                }
             }
+        }
+        updated_positions
+    }
 
-            if let Some(profit_positions) =
-                self.indexes.profit_price_subscription_indexes.get(price_id)
-            {
-                positions.extend(profit_positions);
-            }
+    /// Parallel counterpart of `recalculate_positions_pl`. Positions touched by the updated
+    /// prices are recalculated concurrently against an immutable view of the bidask cache; each
+    /// position still sees its prices in the order they were updated. The order of the returned
+    /// updates is not specified.
+    #[cfg(feature = "parallel")]
+    pub fn recalculate_positions_pl_parallel(
+        &mut self,
+        updated_prices: &[String],
+        bidask_cache: &MicroEngineBidAskCache,
+        settings_cache: &TradingSettingsCache,
+    ) -> Option<Vec<MicroEnginePositionCalculationUpdate>> {
+        use rayon::prelude::*;
 
-            for position_id in positions {
-                if let Some(position) = self.positions.get_mut(position_id) {
-                    let Some(group_settings) =
-                        settings_cache.resolve_by_account(&position.account_id)
-                    else {
-                        continue;
-                    };
-
-                    position.update_bidask(&target_price, bidask_cache, group_settings);
-
-                    // Explicitly update profit_bidask from raw cache prices for positions that need currency conversion
-                    // This is necessary because positions loaded from trading-engine have empty profit_price_assets_subscriptions,
-                    // so profit_bidask doesn't get updated in update_bidask. We need to ensure it's updated from raw cache prices
-                    // to match trading-engine behavior (no markup on profit_bidask updates).
-                    if position.quote != position.collateral {
-                        position.update_profit_bidask_from_cache(bidask_cache);
-                        position.recalculate_pl(group_settings);
-                    }
+        if updated_prices.is_empty() {
+            return None;
+        }
 
-                    updated_positions.get_or_insert_default().push(
-                        MicroEnginePositionCalculationUpdate {
-                            account_id: position.account_id.clone(),
-                            position_id: position.id.clone(),
-                            gross_pl: position.get_gross_pl(),
-                        },
-                    );
-                }
+        let mut touched: HashMap<&str, Vec<&MicroEngineBidask>> = HashMap::new();
+        for price_id in updated_prices {
+            let Some(target_price) = bidask_cache.get_by_id(price_id) else {
+                continue;
+            };
+
+            let direct = self.indexes.asset_pair_index.get(price_id);
+            let profit = self.indexes.profit_price_subscription_indexes.get(price_id);
+            for position_id in direct.into_iter().chain(profit).flatten() {
+                touched
+                    .entry(position_id.as_str())
+                    .or_default()
+                    .push(target_price);
             }
         }
-        updated_positions
+
+        let updated_positions: Vec<MicroEnginePositionCalculationUpdate> = self
+            .positions
+            .par_iter_mut()
+            .filter_map(|(id, position)| touched.get(id.as_str()).map(|prices| (position, prices)))
+            .flat_map_iter(|(position, prices)| {
+                prices
+                    .iter()
+                    .filter_map(|price| {
+                        apply_price_to_position(position, price, bidask_cache, settings_cache)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        if updated_positions.is_empty() {
+            None
+        } else {
+            Some(updated_positions)
+        }
     }
 
-    pub fn recalculate_all_positions(
+    /// Refreshes only the conversion (profit) prices of positions subscribed to `conversion_ids`
+    /// and recomputes their P&L. Active bidasks are left as they are.
+    pub fn recompute_conversions(
         &mut self,
+        conversion_ids: &[String],
         bidask_cache: &mut MicroEngineBidAskCache,
         settings_cache: &TradingSettingsCache,
     ) -> Option<Vec<MicroEnginePositionCalculationUpdate>> {
+        let position_ids = conversion_ids
+            .iter()
+            .filter_map(|x| self.indexes.profit_price_subscription_indexes.get(x))
+            .flatten()
+            .cloned()
+            .collect::<HashSet<_>>();
+
         let mut updated_positions: Option<Vec<MicroEnginePositionCalculationUpdate>> = None;
 
-        for (id, position) in self.positions.iter_mut() {
+        for position_id in position_ids {
+            let Some(position) = self.positions.get_mut(&position_id) else {
+                continue;
+            };
+
             let Some(group_settings) = settings_cache.resolve_by_account(&position.account_id)
             else {
                 continue;
             };
 
-            // Get price by asset_pair, not position ID
-            // Note: We don't apply markup to open_bidask here because positions from trading engine
-            // already have markup applied to open_bidask. We only apply markup to active_bidask
-            // when prices update.
-            if let Some(price) = bidask_cache.get_by_id(&position.asset_pair).cloned() {
-                position.update_bidask(&price, bidask_cache, group_settings);
-
-                // Explicitly update profit_bidask from raw cache prices for positions that need currency conversion
-                // This is necessary because positions loaded from trading-engine have empty profit_price_assets_subscriptions,
-                // so profit_bidask doesn't get updated in update_bidask. We need to ensure it's updated from raw cache prices
-                // to match trading-engine behavior (no markup on profit_bidask updates).
-                if position.quote != position.collateral {
-                    position.update_profit_bidask_from_cache(bidask_cache);
-                    position.recalculate_pl(group_settings);
+            let previous_gross_pl = position.get_gross_pl();
+            if !position.update_profit_bidask_from_cache(bidask_cache, group_settings) {
+                continue;
+            }
+            position.recalculate_pl(group_settings);
+
+            updated_positions.get_or_insert_default().push(
+                MicroEnginePositionCalculationUpdate::new(position, previous_gross_pl),
+            );
+        }
+
+        updated_positions
+    }
+
+    /// Applies a single price to the positions of one account only, i.e. positions on that
+    /// instrument or subscribed to it for profit conversion.
+    pub fn recalculate_account_positions_pl(
+        &mut self,
+        account_id: &str,
+        price_id: &str,
+        bidask_cache: &MicroEngineBidAskCache,
+        settings_cache: &TradingSettingsCache,
+    ) -> Vec<MicroEnginePositionCalculationUpdate> {
+        let Some(target_price) = bidask_cache.get_by_id(price_id) else {
+            return vec![];
+        };
+
+        let Some(account_positions) = self.indexes.account_id_index.get(account_id) else {
+            return vec![];
+        };
+
+        account_positions
+            .iter()
+            .filter_map(|position_id| {
+                let position = self.positions.get_mut(position_id)?;
+
+                if position.asset_pair != price_id && !position.subscribes_to(price_id) {
+                    return None;
                 }
 
-                updated_positions.get_or_insert_default().push(
-                    MicroEnginePositionCalculationUpdate {
-                        account_id: position.account_id.clone(),
-                        position_id: position.id.clone(),
-                        gross_pl: position.get_gross_pl(),
-                    },
-                );
+                apply_price_to_position(position, target_price, bidask_cache, settings_cache)
+            })
+            .collect()
+    }
+
+    /// Accrues swaps on every position with a rate for its instrument. Returns the ids of the
+    /// accounts whose positions changed.
+    pub(crate) fn accrue_swaps(
+        &mut self,
+        now: DateTime<Utc>,
+        swap_rate_per_lot: &HashMap<String, f64>,
+        triple_swap_weekday: Weekday,
+    ) -> HashSet<String> {
+        let mut affected_accounts = HashSet::new();
+
+        for position in self.positions.values_mut() {
+            let Some(rate) = swap_rate_per_lot.get(&position.asset_pair) else {
+                continue;
+            };
+
+            if position.accrue_swaps(now, *rate, triple_swap_weekday) {
+                affected_accounts.insert(position.account_id.clone());
             }
         }
 
-        updated_positions
+        affected_accounts
+    }
+
+    /// Re-applies the cached prices to every position of `account_id`, e.g. after its group
+    /// settings changed.
+    pub fn recalculate_account_positions(
+        &mut self,
+        account_id: &str,
+        bidask_cache: &mut MicroEngineBidAskCache,
+        settings_cache: &TradingSettingsCache,
+    ) -> Vec<MicroEnginePositionCalculationUpdate> {
+        let Some(account_positions) = self.indexes.account_id_index.get(account_id) else {
+            return vec![];
+        };
+
+        account_positions
+            .iter()
+            .filter_map(|position_id| {
+                let position = self.positions.get_mut(position_id)?;
+                recalculate_position_from_cache(position, bidask_cache, settings_cache)
+            })
+            .collect()
+    }
+
+    /// With `deterministic_order` positions are walked, and updates returned, sorted by id.
+    pub fn recalculate_all_positions(
+        &mut self,
+        bidask_cache: &mut MicroEngineBidAskCache,
+        settings_cache: &TradingSettingsCache,
+        deterministic_order: bool,
+    ) -> Option<Vec<MicroEnginePositionCalculationUpdate>> {
+        let mut positions = self.positions.values_mut().collect::<Vec<_>>();
+
+        if deterministic_order {
+            positions.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        }
+
+        let updated_positions = positions
+            .into_iter()
+            .filter_map(|position| {
+                recalculate_position_from_cache(position, bidask_cache, settings_cache)
+            })
+            .collect::<Vec<_>>();
+
+        match updated_positions.is_empty() {
+            true => None,
+            false => Some(updated_positions),
+        }
+    }
+}
+
+pub(crate) fn recalculate_position_from_cache(
+    position: &mut MicroEnginePosition,
+    bidask_cache: &mut MicroEngineBidAskCache,
+    settings_cache: &TradingSettingsCache,
+) -> Option<MicroEnginePositionCalculationUpdate> {
+    // Get price by asset_pair, not position ID
+    // Note: We don't apply markup to open_bidask here because positions from trading engine
+    // already have markup applied to open_bidask. We only apply markup to active_bidask
+    // when prices update.
+    let price = bidask_cache
+        .get_by_id_or_fetch(&position.asset_pair)
+        .cloned()?;
+    bidask_cache.fetch_missing(&position.quote, &position.collateral);
+
+    apply_price_to_position(position, &price, bidask_cache, settings_cache)
+}
+
+/// Applies `price` to `position` and recomputes its P&L, reading the bidask cache only.
+fn apply_price_to_position(
+    position: &mut MicroEnginePosition,
+    price: &MicroEngineBidask,
+    bidask_cache: &MicroEngineBidAskCache,
+    settings_cache: &TradingSettingsCache,
+) -> Option<MicroEnginePositionCalculationUpdate> {
+    let group_settings = settings_cache.resolve_by_account(&position.account_id)?;
+
+    apply_price_with_settings(
+        position,
+        price,
+        bidask_cache,
+        group_settings,
+        settings_cache.is_raw_spread(&position.account_id),
+    )
+}
+
+/// `apply_price_to_position` under the given group settings. `None` when the position is left
+/// as it is, e.g. its market is closed or its conversion update is skipped.
+pub(crate) fn apply_price_with_settings(
+    position: &mut MicroEnginePosition,
+    price: &MicroEngineBidask,
+    bidask_cache: &MicroEngineBidAskCache,
+    group_settings: &MicroEngineTradingGroupSettings,
+    raw_spread: bool,
+) -> Option<MicroEnginePositionCalculationUpdate> {
+    let previous_gross_pl = position.get_gross_pl();
+
+    if !position.update_bidask_from_snapshot(price, bidask_cache, group_settings, raw_spread) {
+        return None;
+    }
+
+    // Explicitly update profit_bidask from raw cache prices for positions that need currency conversion
+    // This is necessary because positions loaded from trading-engine have empty profit_price_assets_subscriptions,
+    // so profit_bidask doesn't get updated in update_bidask. We need to ensure it's updated from raw cache prices
+    // to match trading-engine behavior (no markup on profit_bidask updates).
+    if position.quote != position.collateral {
+        if !position.update_profit_bidask_from_snapshot(bidask_cache, group_settings) {
+            return None;
+        }
+        position.recalculate_pl(group_settings);
+    }
+
+    Some(MicroEnginePositionCalculationUpdate::new(
+        position,
+        previous_gross_pl,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::bidask::{
+        MicroEngineBidAskCacheOptions, MicroEngineInstrument, PriceProvider, dto::MicroEngineBidask,
+    };
+    use crate::settings::{
+        CollateralSettings, MicroEngineTradingGroupSettings, TradingGroupInstrumentSettings,
+    };
+
+    fn eurcad_position(id: &str) -> MicroEnginePosition {
+        let price = MicroEngineBidask {
+            id: "EURCAD".to_string(),
+            bid: 1.45000,
+            ask: 1.45020,
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        };
+
+        MicroEnginePosition {
+            id: id.to_string(),
+            trader_id: "TR1".to_string(),
+            account_id: "ACC1".to_string(),
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            collateral: "USD".to_string(),
+            asset_pair: "EURCAD".to_string(),
+            lots_amount: 0.1,
+            contract_size: 100000.0,
+            is_buy: true,
+            pl: 0.0,
+            commission: 0.0,
+            open_bidask: price.clone(),
+            active_bidask: price.clone(),
+            margin_bidask: price,
+            profit_bidask: MicroEngineBidask::create_blank(),
+            profit_price_assets_subscriptions: HashSet::from(["USDCAD".to_string()]),
+            swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_get_positions_by_profit_subscription() {
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        cache.add_position(eurcad_position("P1"));
+        cache.add_position(eurcad_position("P2"));
+
+        let mut ids = cache
+            .get_positions_by_profit_subscription("USDCAD")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.id.as_str())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        assert_eq!(ids, vec!["P1", "P2"]);
+        assert!(
+            cache
+                .get_positions_by_profit_subscription("EURUSD")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_reindex_position_after_asset_pair_change() {
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        cache.add_position(eurcad_position("P1"));
+        cache.positions.get_mut("P1").unwrap().asset_pair = "EURCHF".to_string();
+
+        assert!(cache.reindex_position("P1"));
+        assert!(cache.get_asset_pair_positions("EURCAD").is_none());
+        assert_eq!(
+            cache.get_asset_pair_positions("EURCHF").unwrap()[0].id,
+            "P1"
+        );
+        assert!(!cache.reindex_position("unknown"));
+    }
+
+    #[test]
+    fn test_add_position_replaces_stale_index_entries() {
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        cache.add_position(eurcad_position("P1"));
+
+        let mut updated = eurcad_position("P1");
+        updated.asset_pair = "EURCHF".to_string();
+        cache.add_position(updated);
+
+        assert!(cache.get_asset_pair_positions("EURCAD").is_none());
+        assert_eq!(cache.get_asset_pair_positions("EURCHF").unwrap().len(), 1);
+    }
+
+    fn eurcad_settings_cache() -> TradingSettingsCache {
+        TradingSettingsCache::new_with_mapping(
+            vec![MicroEngineTradingGroupSettings {
+                id: "tg1".to_string(),
+                hedge_coef: None,
+                instruments: HashMap::from([(
+                    "EURCAD".to_string(),
+                    TradingGroupInstrumentSettings {
+                        digits: 5,
+                        max_leverage: None,
+                        markup_settings: None,
+                        margin_price_mode: Default::default(),
+                        kind: Default::default(),
+                        commission_model: Default::default(),
+                        lot_step: None,
+                        min_lots: None,
+                        max_lots: None,
+                        trading_hours: None,
+                        default_contract_size: None,
+                    },
+                )]),
+                collaterals: HashMap::from([("USD".to_string(), CollateralSettings { digits: 2 })]),
+                negative_balance_protection: false,
+                equity_components: Default::default(),
+                settle_on_close: false,
+                max_leverage: None,
+                account_leverage_policy: Default::default(),
+                missing_conversion_policy: Default::default(),
+                round_account_figures: false,
+                min_margin_level_to_open: None,
+                swaps_reduce_free_margin: false,
+                reserve_swaps_in_margin: false,
+                max_account_notional: None,
+            }],
+            HashMap::from([("ACC1".to_string(), "tg1".to_string())]),
+        )
+    }
+
+    fn eurcad_bidask_cache() -> MicroEngineBidAskCache {
+        let (bidask_cache, _) = MicroEngineBidAskCache::new(
+            HashSet::from(["USD".to_string()]),
+            vec![
+                MicroEngineInstrument {
+                    id: "EURCAD".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                },
+                MicroEngineInstrument {
+                    id: "USDCAD".to_string(),
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                },
+            ],
+            vec![
+                eurcad_position("P0").active_bidask,
+                MicroEngineBidask {
+                    id: "USDCAD".to_string(),
+                    bid: 1.35000,
+                    ask: 1.35020,
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                    date: None,
+                },
+            ],
+        );
+
+        bidask_cache
+    }
+
+    #[derive(Debug)]
+    struct UsdCadProvider;
+
+    impl PriceProvider for UsdCadProvider {
+        fn fetch(&self, id: &str) -> Option<MicroEngineBidask> {
+            (id == "USDCAD").then(|| MicroEngineBidask {
+                id: "USDCAD".to_string(),
+                bid: 1.35000,
+                ask: 1.35020,
+                base: "USD".to_string(),
+                quote: "CAD".to_string(),
+                date: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_recalculate_all_positions_fetches_conversion_price() {
+        let settings_cache = eurcad_settings_cache();
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new_with_options(
+            HashSet::from(["USD".to_string()]),
+            vec![
+                MicroEngineInstrument {
+                    id: "EURCAD".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                },
+                MicroEngineInstrument {
+                    id: "USDCAD".to_string(),
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                },
+            ],
+            vec![eurcad_position("P0").active_bidask],
+            MicroEngineBidAskCacheOptions {
+                price_provider: Some(Box::new(UsdCadProvider)),
+                ..Default::default()
+            },
+        );
+
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+        cache.add_position(eurcad_position("P0"));
+
+        let updates = cache
+            .recalculate_all_positions(&mut bidask_cache, &settings_cache, false)
+            .unwrap();
+
+        assert_eq!(updates.len(), 1);
+        assert!(bidask_cache.get_by_id("USDCAD").is_some());
+        // CAD -> USD through the fetched USDCAD, 1 / 1.35020
+        assert_eq!(
+            format!("{:.5}", cache.positions["P0"].profit_bidask.bid),
+            "0.74063"
+        );
+    }
+
+    #[test]
+    fn test_tick_fetches_missing_conversion_price() {
+        let settings_cache = eurcad_settings_cache();
+        let (mut bidask_cache, _) = MicroEngineBidAskCache::new_with_options(
+            HashSet::from(["USD".to_string()]),
+            vec![
+                MicroEngineInstrument {
+                    id: "EURCAD".to_string(),
+                    base: "EUR".to_string(),
+                    quote: "CAD".to_string(),
+                },
+                MicroEngineInstrument {
+                    id: "USDCAD".to_string(),
+                    base: "USD".to_string(),
+                    quote: "CAD".to_string(),
+                },
+            ],
+            vec![eurcad_position("P0").active_bidask],
+            MicroEngineBidAskCacheOptions {
+                price_provider: Some(Box::new(UsdCadProvider)),
+                ..Default::default()
+            },
+        );
+
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+        cache.add_position(eurcad_position("P0"));
+
+        let mut updated_prices = vec!["EURCAD".to_string()];
+        let fetched = cache.fetch_missing_conversions(&updated_prices, &mut bidask_cache);
+        assert_eq!(fetched, vec!["USDCAD".to_string()]);
+
+        updated_prices.splice(0..0, fetched);
+        let updates = cache
+            .recalculate_positions_pl(&updated_prices, &bidask_cache, &settings_cache)
+            .unwrap();
+
+        assert!(!updates.is_empty());
+        // CAD -> USD through the fetched USDCAD, 1 / 1.35020
+        assert_eq!(
+            format!("{:.5}", cache.positions["P0"].profit_bidask.bid),
+            "0.74063"
+        );
+
+        // Once cached nothing is fetched again
+        let fetched = cache.fetch_missing_conversions(&updated_prices, &mut bidask_cache);
+        assert!(fetched.is_empty());
+    }
+
+    #[test]
+    fn test_recalculate_all_positions_deterministic_order() {
+        let settings_cache = eurcad_settings_cache();
+        let mut bidask_cache = eurcad_bidask_cache();
+
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        for i in (0..20).rev() {
+            cache.add_position(eurcad_position(&format!("P{i:02}")));
+        }
+
+        let updates = cache
+            .recalculate_all_positions(&mut bidask_cache, &settings_cache, true)
+            .unwrap();
+
+        let ids = updates
+            .iter()
+            .map(|x| x.position_id.as_str())
+            .collect::<Vec<_>>();
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+
+        assert_eq!(ids.len(), 20);
+        assert_eq!(ids, sorted);
+    }
+
+    #[test]
+    fn test_recompute_conversions_keeps_active_bidask() {
+        let settings_cache = eurcad_settings_cache();
+        let mut bidask_cache = eurcad_bidask_cache();
+
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+        let mut position = eurcad_position("P1");
+        position.active_bidask.bid = 1.46000;
+        position.active_bidask.ask = 1.46020;
+        cache.add_position(position);
+
+        let conversion_ids = vec!["USDCAD".to_string()];
+
+        cache
+            .recompute_conversions(&conversion_ids, &mut bidask_cache, &settings_cache)
+            .unwrap();
+        let pl_before = cache.get_position("P1").unwrap().pl;
+
+        bidask_cache.handle_new(&MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.40000,
+            ask: 1.40020,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        });
+
+        let updates = cache
+            .recompute_conversions(&conversion_ids, &mut bidask_cache, &settings_cache)
+            .unwrap();
+        let position = cache.get_position("P1").unwrap();
+
+        assert_eq!(updates.len(), 1);
+        assert!(position.pl < pl_before);
+        assert_eq!(position.active_bidask.bid, 1.46000);
+        assert_eq!(position.active_bidask.ask, 1.46020);
+        assert!(
+            cache
+                .recompute_conversions(&["EURUSD".to_string()], &mut bidask_cache, &settings_cache)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_position_update_carries_pl_delta() {
+        let settings_cache = eurcad_settings_cache();
+        let mut bidask_cache = eurcad_bidask_cache();
+
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+        cache.add_position(eurcad_position("P1"));
+
+        let mut previous = cache.get_position("P1").unwrap().get_gross_pl();
+        for bid in [1.45500, 1.44800] {
+            bidask_cache.handle_new(&MicroEngineBidask {
+                id: "EURCAD".to_string(),
+                bid,
+                ask: bid + 0.0002,
+                base: "EUR".to_string(),
+                quote: "CAD".to_string(),
+                date: None,
+            });
+
+            let updates = cache
+                .recalculate_positions_pl(&["EURCAD".to_string()], &bidask_cache, &settings_cache)
+                .unwrap();
+            let update = &updates[0];
+
+            assert_eq!(updates.len(), 1);
+            assert_eq!(update.previous_gross_pl, previous);
+            assert_eq!(update.delta, update.gross_pl - update.previous_gross_pl);
+            assert_ne!(update.delta, 0.0);
+
+            previous = update.gross_pl;
+        }
+    }
+
+    #[test]
+    fn test_get_account_active_positions() {
+        let mut cache = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        let mut pending = eurcad_position("P2");
+        pending.is_pending = true;
+
+        cache.add_position(eurcad_position("P1"));
+        cache.add_position(pending);
+
+        let active = cache.get_account_active_positions("ACC1").unwrap();
+
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, "P1");
+        assert_eq!(cache.get_account_positions("ACC1").unwrap().len(), 2);
+        assert_eq!(
+            cache
+                .get_positions_by_profit_subscription("USDCAD")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_recalculation_matches_sequential() {
+        let settings_cache = eurcad_settings_cache();
+        let mut bidask_cache = eurcad_bidask_cache();
+
+        let mut sequential = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+        let mut parallel = MicroEnginePositionCache {
+            indexes: PositionsCacheIndex::default(),
+            positions: HashMap::new(),
+        };
+
+        for i in 0..256 {
+            let mut position = eurcad_position(&format!("P{i:03}"));
+            position.is_buy = i % 2 == 0;
+            position.lots_amount = 0.1 * (1 + i % 7) as f64;
+            sequential.add_position(position.clone());
+            parallel.add_position(position);
+        }
+
+        bidask_cache.handle_new(&MicroEngineBidask {
+            id: "EURCAD".to_string(),
+            bid: 1.45310,
+            ask: 1.45330,
+            base: "EUR".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        });
+        bidask_cache.handle_new(&MicroEngineBidask {
+            id: "USDCAD".to_string(),
+            bid: 1.36010,
+            ask: 1.36030,
+            base: "USD".to_string(),
+            quote: "CAD".to_string(),
+            date: None,
+        });
+
+        let updated_prices = vec!["EURCAD".to_string(), "USDCAD".to_string()];
+
+        let mut expected = sequential
+            .recalculate_positions_pl(&updated_prices, &bidask_cache, &settings_cache)
+            .unwrap();
+        let mut actual = parallel
+            .recalculate_positions_pl_parallel(&updated_prices, &bidask_cache, &settings_cache)
+            .unwrap();
+
+        expected.sort_by(|a, b| a.position_id.cmp(&b.position_id));
+        actual.sort_by(|a, b| a.position_id.cmp(&b.position_id));
+
+        assert_eq!(expected.len(), actual.len());
+        for (expected, actual) in expected.iter().zip(actual.iter()) {
+            assert_eq!(expected.position_id, actual.position_id);
+            assert_eq!(expected.gross_pl, actual.gross_pl);
+        }
     }
 }
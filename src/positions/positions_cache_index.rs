@@ -57,6 +57,21 @@ impl PositionsCacheIndex {
         }
     }
 
+    /// Drops the position id from every index, regardless of the keys it was indexed under.
+    pub fn remove_position_id(&mut self, id: &str) {
+        for index in [
+            &mut self.trader_id_index,
+            &mut self.account_id_index,
+            &mut self.asset_pair_index,
+            &mut self.profit_price_subscription_indexes,
+        ] {
+            index.retain(|_, ids| {
+                ids.remove(id);
+                !ids.is_empty()
+            });
+        }
+    }
+
     fn remove_from_index(index: &mut AHashMap<String, AHashSet<String>>, key: &str, id: &str) {
         if let Some(set) = index.get_mut(key) {
             set.remove(id);
@@ -69,6 +84,8 @@ impl PositionsCacheIndex {
 #[cfg(test)]
 mod profit_subscription_tests {
 
+    use std::collections::HashSet;
+
     use crate::bidask::dto::MicroEngineBidask;
 
     use super::*;
@@ -80,11 +97,12 @@ mod profit_subscription_tests {
             ask: 1.2,
             base: "1.2".to_string(),
             quote: "1.2".to_string(),
+            date: None,
         }
     }
 
     fn position_with_subscriptions(id: &str, subscriptions: &[&str]) -> MicroEnginePosition {
-        let subs: Vec<String> = subscriptions.iter().map(|s| s.to_string()).collect();
+        let subs: HashSet<String> = subscriptions.iter().map(|s| s.to_string()).collect();
 
         MicroEnginePosition {
             id: id.to_string(),
@@ -105,6 +123,12 @@ mod profit_subscription_tests {
             pl: 0.0,
             commission: 0.0,
             swaps_sum: 0.0,
+            realized_pl: 0.0,
+            leverage: None,
+            open_time: None,
+            last_swap_accrual: None,
+            is_pending: false,
+            metadata: None,
         }
     }
 
@@ -1,4 +1,5 @@
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::{Decimal, RoundingStrategy, prelude::FromPrimitive};
 use std::collections::HashMap;
@@ -6,11 +7,15 @@ use std::collections::HashMap;
 use crate::accounts::account::MicroEngineAccount;
 use crate::accounts::account_cache::MicroEngineAccountCache;
 use crate::bidask::dto::MicroEngineBidask;
+use crate::positions::position::MicroEnginePosition;
+use crate::round_float_to_digits;
 
 #[derive(Debug)]
 pub struct TradingSettingsCache {
     pub accounts_mapping: AHashMap<String, String>,
     pub groups: AHashMap<String, MicroEngineTradingGroupSettings>,
+    /// Accounts priced without markup, see `MicroEngineAccount::raw_spread`
+    pub raw_spread_accounts: AHashSet<String>,
 }
 
 impl TradingSettingsCache {
@@ -25,6 +30,13 @@ impl TradingSettingsCache {
             .into_iter()
             .map(|x| (x.id.clone(), x.trading_group.clone()));
 
+        let raw_spread_accounts = accounts_cache
+            .get_all_accounts()
+            .into_iter()
+            .filter(|x| x.raw_spread)
+            .map(|x| x.id.clone())
+            .collect();
+
         for group in settings {
             let group: MicroEngineTradingGroupSettings = group.into();
 
@@ -34,6 +46,7 @@ impl TradingSettingsCache {
         Self {
             accounts_mapping: accounts_mapping.collect(),
             groups,
+            raw_spread_accounts,
         }
     }
 
@@ -52,17 +65,53 @@ impl TradingSettingsCache {
         Self {
             accounts_mapping: accounts_mapping.into_iter().collect(),
             groups,
+            raw_spread_accounts: AHashSet::new(),
         }
     }
 
+    pub fn get_group(&self, group_id: &str) -> Option<&MicroEngineTradingGroupSettings> {
+        self.groups.get(group_id)
+    }
+
     pub fn resolve_by_account(&self, account: &str) -> Option<&MicroEngineTradingGroupSettings> {
         let target_group = self.accounts_mapping.get(account)?;
         self.groups.get(target_group)
     }
 
+    pub fn is_raw_spread(&self, account: &str) -> bool {
+        self.raw_spread_accounts.contains(account)
+    }
+
+    /// `bidask` with the group's markup and spread limits for the instrument applied. `None` if
+    /// the group doesn't know the instrument.
+    pub fn effective_price(
+        &self,
+        group_id: &str,
+        bidask: &MicroEngineBidask,
+    ) -> Option<MicroEngineBidask> {
+        let instrument_settings = self.get_group(group_id)?.instruments.get(&bidask.id)?;
+        let (bid, ask) = instrument_settings.calculate_bidask(bidask);
+
+        Some(MicroEngineBidask {
+            bid,
+            ask,
+            ..bidask.clone()
+        })
+    }
+
+    pub fn remove_settings(&mut self, group_id: &str) -> Option<MicroEngineTradingGroupSettings> {
+        self.groups.remove(group_id)
+    }
+
     pub fn account_updated(&mut self, account: &MicroEngineAccount) {
         self.accounts_mapping
             .insert(account.id.clone(), account.trading_group.clone());
+
+        if account.raw_spread {
+            self.raw_spread_accounts.insert(account.id.clone());
+        } else {
+            self.raw_spread_accounts.remove(&account.id);
+        }
     }
 
     pub fn insert_or_replace_settings(
@@ -70,16 +119,22 @@ impl TradingSettingsCache {
         settings: MicroEngineTradingGroupSettings,
     ) -> Vec<String> {
         let settings_id = settings.id.clone();
-        let mut result = vec![];
         self.groups.insert(settings.id.clone(), settings);
 
-        for (account_id, group_id) in &self.accounts_mapping {
-            if group_id == &settings_id {
-                result.push(account_id.clone());
-            }
-        }
+        self.get_group_accounts(&settings_id)
+    }
 
-        result
+    /// Sorted ids of the instruments `group_id` has settings for.
+    pub fn group_instruments(&self, group_id: &str) -> Option<Vec<&str>> {
+        self.get_group(group_id).map(|x| x.instrument_ids())
+    }
+
+    pub fn get_group_accounts(&self, group_id: &str) -> Vec<String> {
+        self.accounts_mapping
+            .iter()
+            .filter(|(_, group)| group.as_str() == group_id)
+            .map(|(account_id, _)| account_id.clone())
+            .collect()
     }
 }
 
@@ -89,6 +144,117 @@ pub struct MicroEngineTradingGroupSettings {
     pub hedge_coef: Option<f64>,
     pub instruments: HashMap<String, TradingGroupInstrumentSettings>,
     pub collaterals: HashMap<String, CollateralSettings>,
+    /// Floor account equity at zero so losses beyond balance are absorbed.
+    pub negative_balance_protection: bool,
+    pub equity_components: EquityComponents,
+    /// Credit the balance with the gross P&L of positions removed through the engine.
+    pub settle_on_close: bool,
+    /// Upper bound for account leverage, enforced on account insert according to
+    /// `account_leverage_policy`.
+    pub max_leverage: Option<f64>,
+    pub account_leverage_policy: AccountLeveragePolicy,
+    pub missing_conversion_policy: MissingConversionPolicy,
+    /// Round account margin, equity and free margin to the deposit currency digits.
+    pub round_account_figures: bool,
+    /// Margin level, in percent, an account must keep after a new order for `can_open` to
+    /// allow it.
+    pub min_margin_level_to_open: Option<f64>,
+    /// Subtract accrued negative swaps of the account's open positions from free margin on
+    /// account recalculation. Equity, margin and margin level are left as they are. Has no
+    /// effect when `equity_components` counts swaps or `reserve_swaps_in_margin` is set, as
+    /// free margin already reflects them.
+    pub swaps_reduce_free_margin: bool,
+    /// Add accrued negative swaps of open positions to the margin requirement, reserving funds
+    /// for them.
+    pub reserve_swaps_in_margin: bool,
+    /// Cap on the account's net notional, in collateral, enforced by `can_open`.
+    pub max_account_notional: Option<f64>,
+}
+
+impl MicroEngineTradingGroupSettings {
+    /// Sorted ids of the instruments configured in the group.
+    pub fn instrument_ids(&self) -> Vec<&str> {
+        let mut result = self
+            .instruments
+            .keys()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+
+        result
+    }
+}
+
+/// What a position does when its quote -> collateral conversion price can't be resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingConversionPolicy {
+    /// Keep the previous profit price
+    #[default]
+    UseLastKnown,
+    /// Leave the position untouched and don't report it as updated
+    SkipUpdate,
+    /// Use a zero profit price, so P&L reads as zero until the conversion is available
+    Zero,
+}
+
+/// What recalculation does with an account whose trading group isn't known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownGroupPolicy {
+    /// Leave the account's last figures untouched
+    #[default]
+    KeepStale,
+    /// Treat its positions as unpriceable: no margin, equity equal to balance
+    ZeroOut,
+}
+
+/// What to do with an account whose leverage exceeds the group's `max_leverage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccountLeveragePolicy {
+    /// Store the account with leverage clamped to the group maximum
+    #[default]
+    Clamp,
+    /// Refuse the account with `MicroEngineError::LeverageExceedsGroupMax`
+    Reject,
+}
+
+/// Which position components are summed into the gross P&L used for equity.
+/// Floating P&L is always included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EquityComponents {
+    pub commission: bool,
+    pub swaps: bool,
+    /// Commission is stored as a positive cost and subtracted. When `false` it is already
+    /// signed and added as is.
+    pub commission_is_cost: bool,
+}
+
+impl Default for EquityComponents {
+    fn default() -> Self {
+        Self {
+            commission: true,
+            swaps: true,
+            commission_is_cost: true,
+        }
+    }
+}
+
+impl EquityComponents {
+    pub fn get_gross_pl(&self, position: &MicroEnginePosition) -> f64 {
+        let mut result = position.pl;
+
+        if self.commission {
+            match self.commission_is_cost {
+                true => result -= position.commission,
+                false => result += position.commission,
+            }
+        }
+
+        if self.swaps {
+            result += position.swaps_sum;
+        }
+
+        result
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -101,6 +267,81 @@ pub struct TradingGroupInstrumentSettings {
     pub digits: u32,
     pub max_leverage: Option<f64>,
     pub markup_settings: Option<TradingGroupInstrumentMarkupSettings>,
+    pub margin_price_mode: MarginPriceMode,
+    pub kind: InstrumentKind,
+    pub commission_model: CommissionModel,
+    /// Volume granularity, e.g. 0.01. Position volumes must be a multiple of it.
+    pub lot_step: Option<f64>,
+    pub min_lots: Option<f64>,
+    /// Largest volume a single position may have.
+    pub max_lots: Option<f64>,
+    /// `(open, close)` minutes of the UTC week counted from Monday 00:00, close exclusive.
+    /// `None` trades around the clock; outside these ranges positions keep their P&L.
+    pub trading_hours: Option<Vec<(u32, u32)>>,
+    /// Contract size for positions that arrive without one (`contract_size == 0.0`).
+    pub default_contract_size: Option<f64>,
+}
+
+/// How a position's commission relates to its volume.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CommissionModel {
+    /// Flat amount, unaffected by volume changes
+    #[default]
+    Fixed,
+    /// Collateral amount per lot
+    PerLot { per_lot: f64 },
+    /// Percent of the position notional, converted to collateral with the profit price
+    PercentOfNotional { percent: f64 },
+}
+
+impl CommissionModel {
+    /// Commission for the position's current volume, or `None` when it doesn't depend on
+    /// volume.
+    pub fn calculate(&self, position: &MicroEnginePosition) -> Option<f64> {
+        match self {
+            CommissionModel::Fixed => None,
+            CommissionModel::PerLot { per_lot } => Some(per_lot * position.lots_amount),
+            CommissionModel::PercentOfNotional { percent } => {
+                let notional = position.lots_amount
+                    * position.contract_size
+                    * position.open_bidask.get_open_price(position.is_buy)
+                    * position.profit_bidask.bid;
+
+                Some(notional * percent / 100.0)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InstrumentKind {
+    /// P&L = diff * lots * contract_size, converted to collateral with the profit price
+    #[default]
+    Forex,
+    /// P&L = diff * lots * point_value, already expressed in collateral
+    Cfd { point_value: f64 },
+}
+
+/// Which side of `margin_bidask` is used as the margin price.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MarginPriceMode {
+    /// Ask for buys, bid for sells
+    #[default]
+    OpenSide,
+    Mid,
+    Bid,
+    Ask,
+}
+
+impl MarginPriceMode {
+    pub fn get_margin_price(&self, bidask: &MicroEngineBidask, is_buy: bool) -> f64 {
+        match self {
+            MarginPriceMode::OpenSide => bidask.get_open_price(is_buy),
+            MarginPriceMode::Mid => (bidask.bid + bidask.ask) / 2.0,
+            MarginPriceMode::Bid => bidask.bid,
+            MarginPriceMode::Ask => bidask.ask,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -109,44 +350,291 @@ pub struct TradingGroupInstrumentMarkupSettings {
     pub markup_ask: f64,
     pub min_spread: Option<f64>,
     pub max_spread: Option<f64>,
+    pub markup_order: MarkupOrder,
+    pub spread_clamp_order: SpreadClampOrder,
+    /// How the min/max spread adjustment is rounded to the instrument digits.
+    pub spread_rounding: SpreadRounding,
+    /// `(volume_threshold, extra_points)` tiers. A position uses the tier with the highest
+    /// threshold not above its lots amount; its spread widens by `extra_points` in total.
+    pub volume_markup: Option<Vec<(f64, f64)>>,
+    /// Clamp spreads symmetrically around the original mid instead of the odd/even pip logic.
+    pub clamp_preserves_mid: bool,
+    pub on_inverted: OnInverted,
+}
+
+impl TradingGroupInstrumentMarkupSettings {
+    pub fn get_volume_markup_points(&self, lots_amount: f64) -> f64 {
+        let Some(tiers) = &self.volume_markup else {
+            return 0.0;
+        };
+
+        tiers
+            .iter()
+            .filter(|(threshold, _)| *threshold <= lots_amount)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, extra_points)| *extra_points)
+            .unwrap_or(0.0)
+    }
+}
+
+/// Whether markup is applied before or after the min/max spread clamps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MarkupOrder {
+    #[default]
+    MarkupThenClamp,
+    ClampThenMarkup,
+}
+
+/// Order in which max and min spread clamps are applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpreadClampOrder {
+    #[default]
+    MaxThenMin,
+    MinThenMax,
+}
+
+/// Rounding of the min/max spread adjustment when the limit isn't on the instrument's price
+/// grid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpreadRounding {
+    /// Truncate the adjustment, so a min spread can end up a point short
+    #[default]
+    ToZero,
+    /// Round the adjustment to the nearest point, midpoint away from zero
+    ToNearest,
+}
+
+impl SpreadRounding {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            SpreadRounding::ToZero => RoundingStrategy::ToZero,
+            SpreadRounding::ToNearest => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+}
+
+/// What to do when markup and spread clamps leave the bid above the ask.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnInverted {
+    /// Quote both sides at the mid of the inverted bid/ask
+    #[default]
+    ClampToMid,
+    /// Drop the markup and keep the raw bid/ask
+    KeepRaw,
+}
+
+impl OnInverted {
+    fn resolve(&self, raw: (f64, f64), marked_up: (f64, f64), digits: u32) -> (f64, f64) {
+        let (bid, ask) = marked_up;
+
+        if bid <= ask {
+            return marked_up;
+        }
+
+        match self {
+            OnInverted::ClampToMid => {
+                let mid = round_float_to_digits((bid + ask) / 2.0, digits as i32);
+                (mid, mid)
+            }
+            OnInverted::KeepRaw => raw,
+        }
+    }
 }
 
 impl TradingGroupInstrumentSettings {
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let Some(trading_hours) = &self.trading_hours else {
+            return true;
+        };
+
+        let minute_of_week =
+            now.weekday().num_days_from_monday() * 24 * 60 + now.hour() * 60 + now.minute();
+
+        trading_hours
+            .iter()
+            .any(|(open, close)| *open <= minute_of_week && minute_of_week < *close)
+    }
+
+    /// Whether `lots_amount` is within `min_lots`..=`max_lots` and is a multiple of `lot_step`.
+    pub fn is_valid_lot_size(&self, lots_amount: f64) -> bool {
+        if let Some(min_lots) = self.min_lots
+            && lots_amount < min_lots
+        {
+            return false;
+        }
+
+        if let Some(max_lots) = self.max_lots
+            && lots_amount > max_lots
+        {
+            return false;
+        }
+
+        if let Some(lot_step) = self.lot_step
+            && lot_step > 0.0
+        {
+            let steps = lots_amount / lot_step;
+
+            if (steps - steps.round()).abs() > 1e-8 {
+                return false;
+            }
+        }
+
+        true
+    }
+
     pub fn calculate_bidask(&self, bidask: &MicroEngineBidask) -> (f64, f64) {
         let Some(markup_settings) = &self.markup_settings else {
             return (bidask.bid, bidask.ask);
         };
 
-        let (mut bid, mut ask) =
-            bidask.get_bid_ask_with_markup(markup_settings.markup_bid, markup_settings.markup_ask);
+        let (mut bid, mut ask) = (bidask.bid, bidask.ask);
+
+        if markup_settings.markup_order == MarkupOrder::MarkupThenClamp {
+            (bid, ask) = bidask
+                .get_bid_ask_with_markup(markup_settings.markup_bid, markup_settings.markup_ask);
+        }
+
+        let preserves_mid = markup_settings.clamp_preserves_mid;
+        let rounding = markup_settings.spread_rounding.strategy();
+
+        let max_spread = |(bid, ask): (f64, f64)| match markup_settings.max_spread {
+            Some(max_spread) if preserves_mid => {
+                calculate_max_spread_around_mid(bid, ask, max_spread, self.digits)
+            }
+            Some(max_spread) => calculate_max_spread(bid, ask, max_spread, self.digits, rounding),
+            None => (bid, ask),
+        };
+
+        let min_spread = |(bid, ask): (f64, f64)| match markup_settings.min_spread {
+            Some(min_spread) if preserves_mid => {
+                calculate_min_spread_around_mid(bid, ask, min_spread, self.digits)
+            }
+            Some(min_spread) => calculate_min_spread(bid, ask, min_spread, self.digits, rounding),
+            None => (bid, ask),
+        };
+
+        (bid, ask) = match markup_settings.spread_clamp_order {
+            SpreadClampOrder::MaxThenMin => min_spread(max_spread((bid, ask))),
+            SpreadClampOrder::MinThenMax => max_spread(min_spread((bid, ask))),
+        };
 
-        if let Some(max_spread) = markup_settings.max_spread {
-            (bid, ask) = calculate_max_spread(bid, ask, max_spread, self.digits as u32);
+        if markup_settings.markup_order == MarkupOrder::ClampThenMarkup {
+            (bid, ask) = (
+                bid + markup_settings.markup_bid,
+                ask + markup_settings.markup_ask,
+            );
         }
 
-        if let Some(min_spread) = markup_settings.min_spread {
-            (bid, ask) = calculate_min_spread(bid, ask, min_spread, self.digits as u32);
+        markup_settings
+            .on_inverted
+            .resolve((bidask.bid, bidask.ask), (bid, ask), self.digits)
+    }
+
+    /// `calculate_bidask` widened by the volume markup tier matching `lots_amount`. The extra
+    /// points come after markup and spread clamps, so `max_spread` doesn't cap them. Half go
+    /// below the bid and the rest, with an odd point, above the ask.
+    pub fn calculate_bidask_for_volume(
+        &self,
+        bidask: &MicroEngineBidask,
+        lots_amount: f64,
+    ) -> (f64, f64) {
+        let (bid, ask) = self.calculate_bidask(bidask);
+
+        let Some(markup_settings) = &self.markup_settings else {
+            return (bid, ask);
+        };
+
+        let extra_points = markup_settings.get_volume_markup_points(lots_amount);
+
+        if extra_points == 0.0 {
+            return (bid, ask);
         }
 
-        (bid, ask)
+        let point = 1.0 / 10_f64.powi(self.digits as i32);
+        let bid_points = (extra_points / 2.0).floor();
+        let ask_points = extra_points - bid_points;
+
+        (
+            round_float_to_digits(bid - bid_points * point, self.digits as i32),
+            round_float_to_digits(ask + ask_points * point, self.digits as i32),
+        )
     }
 
     pub fn mutate_bidask(&self, bidask: &mut MicroEngineBidask) {
-        if let Some(markup_settings) = &self.markup_settings {
+        let Some(markup_settings) = &self.markup_settings else {
+            return;
+        };
+
+        let raw = (bidask.bid, bidask.ask);
+
+        if markup_settings.markup_order == MarkupOrder::MarkupThenClamp {
             bidask.apply_markup(markup_settings.markup_bid, markup_settings.markup_ask);
+        }
 
+        let preserves_mid = markup_settings.clamp_preserves_mid;
+        let rounding = markup_settings.spread_rounding.strategy();
+
+        let max_spread = |bidask: &mut MicroEngineBidask| {
             if let Some(max_spread) = markup_settings.max_spread {
-                apply_max_spread(bidask, max_spread, self.digits);
+                match preserves_mid {
+                    true => {
+                        (bidask.bid, bidask.ask) = calculate_max_spread_around_mid(
+                            bidask.bid,
+                            bidask.ask,
+                            max_spread,
+                            self.digits,
+                        )
+                    }
+                    false => apply_max_spread(bidask, max_spread, self.digits, rounding),
+                }
             }
+        };
 
+        let min_spread = |bidask: &mut MicroEngineBidask| {
             if let Some(min_spread) = markup_settings.min_spread {
-                apply_min_spread(bidask, min_spread, self.digits);
+                match preserves_mid {
+                    true => {
+                        (bidask.bid, bidask.ask) = calculate_min_spread_around_mid(
+                            bidask.bid,
+                            bidask.ask,
+                            min_spread,
+                            self.digits,
+                        )
+                    }
+                    false => apply_min_spread(bidask, min_spread, self.digits, rounding),
+                }
+            }
+        };
+
+        match markup_settings.spread_clamp_order {
+            SpreadClampOrder::MaxThenMin => {
+                max_spread(bidask);
+                min_spread(bidask);
+            }
+            SpreadClampOrder::MinThenMax => {
+                min_spread(bidask);
+                max_spread(bidask);
             }
         }
+
+        if markup_settings.markup_order == MarkupOrder::ClampThenMarkup {
+            bidask.apply_markup(markup_settings.markup_bid, markup_settings.markup_ask);
+        }
+
+        let marked_up = (bidask.bid, bidask.ask);
+        (bidask.bid, bidask.ask) = markup_settings
+            .on_inverted
+            .resolve(raw, marked_up, self.digits);
     }
 }
 
-fn calculate_max_spread(bid: f64, ask: f64, max_spread: f64, digits: u32) -> (f64, f64) {
+fn calculate_max_spread(
+    bid: f64,
+    ask: f64,
+    max_spread: f64,
+    digits: u32,
+    rounding: RoundingStrategy,
+) -> (f64, f64) {
     let spread = calculate_spread(bid, ask, digits);
     let max_spread = Decimal::from_f64(max_spread).unwrap();
     let factor = i64::pow(10, digits as u32);
@@ -156,8 +644,7 @@ fn calculate_max_spread(bid: f64, ask: f64, max_spread: f64, digits: u32) -> (f6
     let mut ask = ask;
 
     if spread > max_spread {
-        let spread_diff =
-            (spread - max_spread).round_dp_with_strategy(digits, RoundingStrategy::ToZero);
+        let spread_diff = (spread - max_spread).round_dp_with_strategy(digits, rounding);
 
         let spread_rounded = (spread_diff / Decimal::from_f64(2.0).unwrap())
             .round_dp_with_strategy(digits, RoundingStrategy::ToZero);
@@ -177,12 +664,22 @@ fn calculate_max_spread(bid: f64, ask: f64, max_spread: f64, digits: u32) -> (f6
             bid += spread_rounded + pip;
             ask -= spread_rounded;
         }
+
+        // Keep the clamped prices on the instrument's price grid
+        bid = round_float_to_digits(bid, digits as i32);
+        ask = round_float_to_digits(ask, digits as i32);
     }
 
     return (bid, ask);
 }
 
-fn calculate_min_spread(bid: f64, ask: f64, min_spread: f64, digits: u32) -> (f64, f64) {
+fn calculate_min_spread(
+    bid: f64,
+    ask: f64,
+    min_spread: f64,
+    digits: u32,
+    rounding: RoundingStrategy,
+) -> (f64, f64) {
     let spread = calculate_spread(bid, ask, digits);
     let min_spread = Decimal::from_f64(min_spread).unwrap();
     let factor = i64::pow(10, digits as u32);
@@ -192,8 +689,7 @@ fn calculate_min_spread(bid: f64, ask: f64, min_spread: f64, digits: u32) -> (f6
     let mut ask = ask;
 
     if spread < min_spread {
-        let spread_diff =
-            (min_spread - spread).round_dp_with_strategy(digits, RoundingStrategy::ToZero);
+        let spread_diff = (min_spread - spread).round_dp_with_strategy(digits, rounding);
         let spread_rounded = (spread_diff / Decimal::from_f64(2.0).unwrap())
             .round_dp_with_strategy(digits, RoundingStrategy::ToZero);
 
@@ -212,19 +708,89 @@ fn calculate_min_spread(bid: f64, ask: f64, min_spread: f64, digits: u32) -> (f6
             bid -= spread_rounded + pip;
             ask += spread_rounded;
         }
+
+        // Keep the clamped prices on the instrument's price grid
+        bid = round_float_to_digits(bid, digits as i32);
+        ask = round_float_to_digits(ask, digits as i32);
     }
     return (bid, ask);
 }
 
-fn apply_max_spread(bid_ask: &mut MicroEngineBidask, max_spread: f64, digits: u32) {
+fn calculate_max_spread_around_mid(bid: f64, ask: f64, max_spread: f64, digits: u32) -> (f64, f64) {
+    if calculate_spread(bid, ask, digits) <= Decimal::from_f64(max_spread).unwrap() {
+        return (bid, ask);
+    }
+
+    // Round inwards so the spread never exceeds the max
+    spread_around_mid(
+        bid,
+        ask,
+        max_spread,
+        digits,
+        (
+            RoundingStrategy::ToPositiveInfinity,
+            RoundingStrategy::ToNegativeInfinity,
+        ),
+    )
+}
+
+fn calculate_min_spread_around_mid(bid: f64, ask: f64, min_spread: f64, digits: u32) -> (f64, f64) {
+    if calculate_spread(bid, ask, digits) >= Decimal::from_f64(min_spread).unwrap() {
+        return (bid, ask);
+    }
+
+    // Round outwards so the spread never falls below the min
+    spread_around_mid(
+        bid,
+        ask,
+        min_spread,
+        digits,
+        (
+            RoundingStrategy::ToNegativeInfinity,
+            RoundingStrategy::ToPositiveInfinity,
+        ),
+    )
+}
+
+/// Places bid and ask `spread / 2` away from the current mid. Both sides are rounded by the
+/// same distance, so the mid is kept exactly.
+fn spread_around_mid(
+    bid: f64,
+    ask: f64,
+    spread: f64,
+    digits: u32,
+    (bid_rounding, ask_rounding): (RoundingStrategy, RoundingStrategy),
+) -> (f64, f64) {
+    let bid = Decimal::from_f64(bid).unwrap().round_dp(digits);
+    let ask = Decimal::from_f64(ask).unwrap().round_dp(digits);
+    let half_spread = Decimal::from_f64(spread).unwrap().round_dp(digits) / Decimal::TWO;
+    let mid = (bid + ask) / Decimal::TWO;
+
+    (
+        (mid - half_spread)
+            .round_dp_with_strategy(digits, bid_rounding)
+            .to_f64()
+            .unwrap(),
+        (mid + half_spread)
+            .round_dp_with_strategy(digits, ask_rounding)
+            .to_f64()
+            .unwrap(),
+    )
+}
+
+fn apply_max_spread(
+    bid_ask: &mut MicroEngineBidask,
+    max_spread: f64,
+    digits: u32,
+    rounding: RoundingStrategy,
+) {
     let spread = calculate_spread(bid_ask.bid, bid_ask.ask, digits);
     let max_spread = Decimal::from_f64(max_spread).unwrap();
     let factor = i64::pow(10, digits as u32);
     let pip = 1.0 / (factor as f64);
 
     if spread > max_spread {
-        let spread_diff =
-            (spread - max_spread).round_dp_with_strategy(digits, RoundingStrategy::ToZero);
+        let spread_diff = (spread - max_spread).round_dp_with_strategy(digits, rounding);
 
         let spread_rounded = (spread_diff / Decimal::from_f64(2.0).unwrap())
             .round_dp_with_strategy(digits, RoundingStrategy::ToZero);
@@ -244,18 +810,26 @@ fn apply_max_spread(bid_ask: &mut MicroEngineBidask, max_spread: f64, digits: u3
             bid_ask.bid += spread_rounded + pip;
             bid_ask.ask -= spread_rounded;
         }
+
+        // Keep the clamped prices on the instrument's price grid
+        bid_ask.bid = round_float_to_digits(bid_ask.bid, digits as i32);
+        bid_ask.ask = round_float_to_digits(bid_ask.ask, digits as i32);
     }
 }
 
-fn apply_min_spread(bid_ask: &mut MicroEngineBidask, min_spread: f64, digits: u32) {
+fn apply_min_spread(
+    bid_ask: &mut MicroEngineBidask,
+    min_spread: f64,
+    digits: u32,
+    rounding: RoundingStrategy,
+) {
     let spread = calculate_spread(bid_ask.bid, bid_ask.ask, digits);
     let min_spread = Decimal::from_f64(min_spread).unwrap();
     let factor = i64::pow(10, digits as u32);
     let pip = 1.0 / (factor as f64);
 
     if spread < min_spread {
-        let spread_diff =
-            (min_spread - spread).round_dp_with_strategy(digits, RoundingStrategy::ToZero);
+        let spread_diff = (min_spread - spread).round_dp_with_strategy(digits, rounding);
         let spread_rounded = (spread_diff / Decimal::from_f64(2.0).unwrap())
             .round_dp_with_strategy(digits, RoundingStrategy::ToZero);
 
@@ -274,6 +848,10 @@ fn apply_min_spread(bid_ask: &mut MicroEngineBidask, min_spread: f64, digits: u3
             bid_ask.bid -= spread_rounded + pip;
             bid_ask.ask += spread_rounded;
         }
+
+        // Keep the clamped prices on the instrument's price grid
+        bid_ask.bid = round_float_to_digits(bid_ask.bid, digits as i32);
+        bid_ask.ask = round_float_to_digits(bid_ask.ask, digits as i32);
     }
 }
 
@@ -297,9 +875,10 @@ mod tests {
             ask: 1.23434,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.00010, 5);
+        apply_max_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23419");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23429");
@@ -313,9 +892,16 @@ mod tests {
             ask: 1.23434,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_max_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23419");
         assert_eq!(format!("{:.5}", ask), "1.23429");
@@ -329,9 +915,10 @@ mod tests {
             ask: 1.23414,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.00010, 5);
+        apply_max_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23434");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23414");
@@ -345,9 +932,16 @@ mod tests {
             ask: 1.23414,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_max_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23434");
         assert_eq!(format!("{:.5}", ask), "1.23414");
@@ -361,9 +955,10 @@ mod tests {
             ask: 1.23414,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_min_spread(&mut bid_ask, 0.00010, 5);
+        apply_min_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23419");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23429");
@@ -377,9 +972,16 @@ mod tests {
             ask: 1.23414,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_min_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_min_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23419");
         assert_eq!(format!("{:.5}", ask), "1.23429");
@@ -393,9 +995,10 @@ mod tests {
             ask: 1.23434,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.00010, 5);
+        apply_max_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23419");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23429");
@@ -409,9 +1012,16 @@ mod tests {
             ask: 1.23434,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_max_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23419");
         assert_eq!(format!("{:.5}", ask), "1.23429");
@@ -425,9 +1035,10 @@ mod tests {
             ask: 1.23435,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_min_spread(&mut bid_ask, 0.00010, 5);
+        apply_min_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23429");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23439");
@@ -441,9 +1052,16 @@ mod tests {
             ask: 1.23435,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_min_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_min_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23429");
         assert_eq!(format!("{:.5}", ask), "1.23439");
@@ -457,9 +1075,10 @@ mod tests {
             ask: 1.23437,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_min_spread(&mut bid_ask, 0.00010, 5);
+        apply_min_spread(&mut bid_ask, 0.00010, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23430");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23440");
@@ -473,9 +1092,16 @@ mod tests {
             ask: 1.23437,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_min_spread(bid_ask.bid, bid_ask.ask, 0.00010, 5);
+        let (bid, ask) = calculate_min_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00010,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.23430");
         assert_eq!(format!("{:.5}", ask), "1.23440");
@@ -489,9 +1115,10 @@ mod tests {
             ask: 1.23436,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.0, 5);
+        apply_max_spread(&mut bid_ask, 0.0, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23435");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23435");
@@ -505,9 +1132,11 @@ mod tests {
             ask: 1.23436,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.0, 5);
+        let (bid, ask) =
+            calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.0, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid), "1.23435");
         assert_eq!(format!("{:.5}", ask), "1.23435");
@@ -521,9 +1150,10 @@ mod tests {
             ask: 1.23437,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.0, 5);
+        apply_max_spread(&mut bid_ask, 0.0, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.23436");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.23436");
@@ -537,9 +1167,11 @@ mod tests {
             ask: 1.23437,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.0, 5);
+        let (bid, ask) =
+            calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.0, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid), "1.23436");
         assert_eq!(format!("{:.5}", ask), "1.23436");
@@ -553,9 +1185,10 @@ mod tests {
             ask: 1.10255,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_max_spread(&mut bid_ask, 0.00013, 5);
+        apply_max_spread(&mut bid_ask, 0.00013, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.10199");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.10212");
@@ -569,9 +1202,16 @@ mod tests {
             ask: 1.10255,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_max_spread(bid_ask.bid, bid_ask.ask, 0.00013, 5);
+        let (bid, ask) = calculate_max_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00013,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.10199");
         assert_eq!(format!("{:.5}", ask), "1.10212");
@@ -585,9 +1225,10 @@ mod tests {
             ask: 1.10156,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        apply_min_spread(&mut bid_ask, 0.00011, 5);
+        apply_min_spread(&mut bid_ask, 0.00011, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.10150");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.10161");
@@ -601,9 +1242,16 @@ mod tests {
             ask: 1.10156,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_min_spread(bid_ask.bid, bid_ask.ask, 0.00011, 5);
+        let (bid, ask) = calculate_min_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00011,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.10150");
         assert_eq!(format!("{:.5}", ask), "1.10161");
@@ -617,8 +1265,9 @@ mod tests {
             ask: 1.10157,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
-        apply_min_spread(&mut bid_ask, 0.00011, 5);
+        apply_min_spread(&mut bid_ask, 0.00011, 5, RoundingStrategy::ToZero);
 
         assert_eq!(format!("{:.5}", bid_ask.bid), "1.10150");
         assert_eq!(format!("{:.5}", bid_ask.ask), "1.10161");
@@ -632,11 +1281,352 @@ mod tests {
             ask: 1.10157,
             base: "".to_string(),
             quote: "".to_string(),
+            date: None,
         };
 
-        let (bid, ask) = calculate_min_spread(bid_ask.bid, bid_ask.ask, 0.00011, 5);
+        let (bid, ask) = calculate_min_spread(
+            bid_ask.bid,
+            bid_ask.ask,
+            0.00011,
+            5,
+            RoundingStrategy::ToZero,
+        );
 
         assert_eq!(format!("{:.5}", bid), "1.10150");
         assert_eq!(format!("{:.5}", ask), "1.10161");
     }
+
+    fn instrument_settings(
+        markup_order: MarkupOrder,
+        spread_clamp_order: SpreadClampOrder,
+        min_spread: Option<f64>,
+        max_spread: Option<f64>,
+    ) -> TradingGroupInstrumentSettings {
+        TradingGroupInstrumentSettings {
+            digits: 5,
+            max_leverage: None,
+            markup_settings: Some(TradingGroupInstrumentMarkupSettings {
+                markup_bid: -0.00005,
+                markup_ask: 0.00005,
+                min_spread,
+                max_spread,
+                markup_order,
+                spread_clamp_order,
+                spread_rounding: Default::default(),
+                volume_markup: None,
+                clamp_preserves_mid: false,
+                on_inverted: Default::default(),
+            }),
+            margin_price_mode: Default::default(),
+            kind: Default::default(),
+            commission_model: Default::default(),
+            lot_step: None,
+            min_lots: None,
+            max_lots: None,
+            trading_hours: None,
+            default_contract_size: None,
+        }
+    }
+
+    #[test]
+    fn test_markup_order_clamp_then_markup() {
+        let bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.23434,
+            ask: 1.23435,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let default_order = instrument_settings(
+            MarkupOrder::MarkupThenClamp,
+            SpreadClampOrder::MaxThenMin,
+            Some(0.00005),
+            None,
+        );
+        let clamp_first = instrument_settings(
+            MarkupOrder::ClampThenMarkup,
+            SpreadClampOrder::MaxThenMin,
+            Some(0.00005),
+            None,
+        );
+
+        // Markup widens the spread to 11 points, so the min spread clamp is a no-op
+        let (bid, ask) = default_order.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.23429");
+        assert_eq!(format!("{:.5}", ask), "1.23440");
+
+        // Clamp widens the raw 1 point spread to 5 points first, then markup adds 10 more
+        let (bid, ask) = clamp_first.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.23427");
+        assert_eq!(format!("{:.5}", ask), "1.23442");
+
+        let mut mutated = bid_ask.clone();
+        clamp_first.mutate_bidask(&mut mutated);
+        assert_eq!(format!("{:.5}", mutated.bid), "1.23427");
+        assert_eq!(format!("{:.5}", mutated.ask), "1.23442");
+    }
+
+    #[test]
+    fn test_volume_markup_after_max_spread() {
+        let bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.23414,
+            ask: 1.23434,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let mut settings = instrument_settings(
+            MarkupOrder::MarkupThenClamp,
+            SpreadClampOrder::MaxThenMin,
+            None,
+            Some(0.00010),
+        );
+        if let Some(markup_settings) = settings.markup_settings.as_mut() {
+            markup_settings.volume_markup = Some(vec![(1.0, 5.0)]);
+        }
+
+        // Below the tier the spread is clamped to 10 points
+        let (bid, ask) = settings.calculate_bidask_for_volume(&bid_ask, 0.5);
+        assert_eq!(format!("{:.5}", bid), "1.23419");
+        assert_eq!(format!("{:.5}", ask), "1.23429");
+
+        // The tier adds 5 points to the clamped spread: 2 below the bid, 3 above the ask
+        let (bid, ask) = settings.calculate_bidask_for_volume(&bid_ask, 1.0);
+        assert_eq!(format!("{:.5}", bid), "1.23417");
+        assert_eq!(format!("{:.5}", ask), "1.23432");
+        assert_eq!(format!("{:.5}", ask - bid), "0.00015");
+    }
+
+    #[test]
+    fn test_spread_clamp_order_min_then_max() {
+        let bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.23434,
+            ask: 1.23435,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let max_then_min = instrument_settings(
+            MarkupOrder::MarkupThenClamp,
+            SpreadClampOrder::MaxThenMin,
+            Some(0.00020),
+            Some(0.00012),
+        );
+        let min_then_max = instrument_settings(
+            MarkupOrder::MarkupThenClamp,
+            SpreadClampOrder::MinThenMax,
+            Some(0.00020),
+            Some(0.00012),
+        );
+
+        // Last applied clamp wins: min spread of 20 points
+        let (bid, ask) = max_then_min.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", ask - bid), "0.00020");
+
+        // Last applied clamp wins: max spread of 12 points
+        let (bid, ask) = min_then_max.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", ask - bid), "0.00012");
+
+        let mut mutated = bid_ask.clone();
+        min_then_max.mutate_bidask(&mut mutated);
+        assert_eq!(format!("{:.5}", mutated.ask - mutated.bid), "0.00012");
+    }
+
+    #[test]
+    fn test_clamp_preserves_mid() {
+        let min_bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.10155,
+            ask: 1.10158,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+        let max_bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.10150,
+            ask: 1.10165,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let mut min_settings = instrument_settings(
+            MarkupOrder::ClampThenMarkup,
+            SpreadClampOrder::MaxThenMin,
+            Some(0.00010),
+            None,
+        );
+        let mut max_settings = instrument_settings(
+            MarkupOrder::ClampThenMarkup,
+            SpreadClampOrder::MaxThenMin,
+            None,
+            Some(0.00010),
+        );
+        for settings in [&mut min_settings, &mut max_settings] {
+            let markup_settings = settings.markup_settings.as_mut().unwrap();
+            markup_settings.markup_bid = 0.0;
+            markup_settings.markup_ask = 0.0;
+        }
+
+        // Pip logic shifts the 1.101565 mid
+        let (bid, ask) = min_settings.calculate_bidask(&min_bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10151");
+        assert_eq!(format!("{:.5}", ask), "1.10161");
+
+        // Pip logic shifts the 1.101575 mid
+        let (bid, ask) = max_settings.calculate_bidask(&max_bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10153");
+        assert_eq!(format!("{:.5}", ask), "1.10163");
+
+        for settings in [&mut min_settings, &mut max_settings] {
+            settings
+                .markup_settings
+                .as_mut()
+                .unwrap()
+                .clamp_preserves_mid = true;
+        }
+
+        // Widened outwards to 11 points around the original mid
+        let (bid, ask) = min_settings.calculate_bidask(&min_bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10151");
+        assert_eq!(format!("{:.5}", ask), "1.10162");
+        assert_eq!(format!("{:.6}", (bid + ask) / 2.0), "1.101565");
+
+        // Narrowed inwards to 9 points around the original mid
+        let (bid, ask) = max_settings.calculate_bidask(&max_bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10153");
+        assert_eq!(format!("{:.5}", ask), "1.10162");
+        assert_eq!(format!("{:.6}", (bid + ask) / 2.0), "1.101575");
+
+        let mut mutated = max_bid_ask.clone();
+        max_settings.mutate_bidask(&mut mutated);
+        assert_eq!(format!("{:.5}", mutated.bid), "1.10153");
+        assert_eq!(format!("{:.5}", mutated.ask), "1.10162");
+    }
+
+    #[test]
+    fn test_inverted_markup_policy() {
+        let bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.10000,
+            ask: 1.10020,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let mut settings = instrument_settings(
+            MarkupOrder::MarkupThenClamp,
+            SpreadClampOrder::MaxThenMin,
+            None,
+            None,
+        );
+        settings.markup_settings.as_mut().unwrap().markup_ask = -0.00100;
+
+        // 1.09995 / 1.09920 is inverted, both sides go to the 1.099575 mid
+        let (bid, ask) = settings.calculate_bidask(&bid_ask);
+        assert!(bid <= ask);
+        assert_eq!(format!("{:.5}", bid), "1.09958");
+        assert_eq!(format!("{:.5}", ask), "1.09958");
+
+        let mut mutated = bid_ask.clone();
+        settings.mutate_bidask(&mut mutated);
+        assert_eq!((mutated.bid, mutated.ask), (bid, ask));
+
+        settings.markup_settings.as_mut().unwrap().on_inverted = OnInverted::KeepRaw;
+
+        let (bid, ask) = settings.calculate_bidask(&bid_ask);
+        assert_eq!((bid, ask), (1.10000, 1.10020));
+
+        let mut mutated = bid_ask.clone();
+        settings.mutate_bidask(&mut mutated);
+        assert_eq!((mutated.bid, mutated.ask), (1.10000, 1.10020));
+    }
+
+    #[test]
+    fn test_group_instruments() {
+        let settings = instrument_settings(
+            MarkupOrder::default(),
+            SpreadClampOrder::default(),
+            None,
+            None,
+        );
+        let group = MicroEngineTradingGroupSettings {
+            id: "tg1".to_string(),
+            hedge_coef: None,
+            instruments: HashMap::from([
+                ("GBPUSD".to_string(), settings.clone()),
+                ("EURUSD".to_string(), settings.clone()),
+                ("USDJPY".to_string(), settings),
+            ]),
+            collaterals: HashMap::new(),
+            negative_balance_protection: false,
+            equity_components: Default::default(),
+            settle_on_close: false,
+            max_leverage: None,
+            account_leverage_policy: Default::default(),
+            missing_conversion_policy: Default::default(),
+            round_account_figures: false,
+            min_margin_level_to_open: None,
+            swaps_reduce_free_margin: false,
+            reserve_swaps_in_margin: false,
+            max_account_notional: None,
+        };
+
+        let mut expected = group.instruments.keys().cloned().collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(group.instrument_ids(), expected);
+
+        let cache = TradingSettingsCache::new_with_mapping(vec![group], HashMap::new());
+        assert_eq!(
+            cache.group_instruments("tg1"),
+            Some(vec!["EURUSD", "GBPUSD", "USDJPY"])
+        );
+        assert_eq!(cache.group_instruments("tg2"), None);
+    }
+
+    #[test]
+    fn test_spread_rounding() {
+        let bid_ask = MicroEngineBidask {
+            id: "EURUSD".to_string(),
+            bid: 1.10150,
+            ask: 1.10160,
+            base: "".to_string(),
+            quote: "".to_string(),
+            date: None,
+        };
+
+        let mut settings = instrument_settings(
+            MarkupOrder::ClampThenMarkup,
+            SpreadClampOrder::MaxThenMin,
+            Some(0.000216),
+            None,
+        );
+        let markup_settings = settings.markup_settings.as_mut().unwrap();
+        markup_settings.markup_bid = 0.0;
+        markup_settings.markup_ask = 0.0;
+
+        // 11.6 points short of the min spread, truncated to 11
+        let (bid, ask) = settings.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10144");
+        assert_eq!(format!("{:.5}", ask), "1.10165");
+
+        settings.markup_settings.as_mut().unwrap().spread_rounding = SpreadRounding::ToNearest;
+
+        // Rounded up to 12
+        let (bid, ask) = settings.calculate_bidask(&bid_ask);
+        assert_eq!(format!("{:.5}", bid), "1.10144");
+        assert_eq!(format!("{:.5}", ask), "1.10166");
+
+        let mut mutated = bid_ask.clone();
+        settings.mutate_bidask(&mut mutated);
+        assert_eq!((mutated.bid, mutated.ask), (bid, ask));
+    }
 }
@@ -0,0 +1,177 @@
+// Allocation counts of the tick recalculation. Kept in its own test binary because it installs
+// a counting global allocator.
+#![cfg(not(feature = "parallel"))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+
+use yft_micro_engine::MicroEngine;
+use yft_micro_engine::accounts::account::MicroEngineAccount;
+use yft_micro_engine::bidask::MicroEngineInstrument;
+use yft_micro_engine::bidask::dto::MicroEngineBidask;
+use yft_micro_engine::positions::position::MicroEnginePosition;
+use yft_micro_engine::settings::{
+    CollateralSettings, MicroEngineTradingGroupSettings, TradingGroupInstrumentSettings,
+};
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts allocations made by the current thread, so parallel tests don't interfere.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|x| x.get())
+}
+
+fn bidask(id: &str, base: &str, quote: &str) -> MicroEngineBidask {
+    MicroEngineBidask {
+        id: id.to_string(),
+        bid: 1.0,
+        ask: 1.1,
+        base: base.to_string(),
+        quote: quote.to_string(),
+        date: None,
+    }
+}
+
+fn settings() -> MicroEngineTradingGroupSettings {
+    MicroEngineTradingGroupSettings {
+        id: "tg1".to_string(),
+        hedge_coef: None,
+        instruments: HashMap::from([(
+            "EURUSD".to_string(),
+            TradingGroupInstrumentSettings {
+                digits: 5,
+                max_leverage: None,
+                markup_settings: None,
+                margin_price_mode: Default::default(),
+                kind: Default::default(),
+                commission_model: Default::default(),
+                lot_step: None,
+                min_lots: None,
+                max_lots: None,
+                trading_hours: None,
+                default_contract_size: None,
+            },
+        )]),
+        collaterals: HashMap::from([("USD".to_string(), CollateralSettings { digits: 2 })]),
+        negative_balance_protection: false,
+        equity_components: Default::default(),
+        settle_on_close: false,
+        max_leverage: None,
+        account_leverage_policy: Default::default(),
+        missing_conversion_policy: Default::default(),
+        round_account_figures: false,
+        min_margin_level_to_open: None,
+        swaps_reduce_free_margin: false,
+        reserve_swaps_in_margin: false,
+        max_account_notional: None,
+    }
+}
+
+fn account() -> MicroEngineAccount {
+    MicroEngineAccount {
+        id: "ACC1".to_string(),
+        trader_id: "TR1".to_string(),
+        trading_group: "tg1".to_string(),
+        balance: 1000.0,
+        credit: 0.0,
+        leverage: 100.0,
+        margin: 0.0,
+        equity: 0.0,
+        free_margin: 0.0,
+        margin_level: 0.0,
+        realized_pl: 0.0,
+        deposit_currency: None,
+        last_calculated: None,
+        raw_spread: false,
+    }
+}
+
+fn position(id: &str) -> MicroEnginePosition {
+    let price = bidask("EURUSD", "EUR", "USD");
+
+    MicroEnginePosition {
+        id: id.to_string(),
+        trader_id: "TR1".to_string(),
+        account_id: "ACC1".to_string(),
+        base: "EUR".to_string(),
+        quote: "USD".to_string(),
+        collateral: "USD".to_string(),
+        asset_pair: "EURUSD".to_string(),
+        lots_amount: 0.1,
+        contract_size: 100000.0,
+        is_buy: true,
+        pl: 0.0,
+        commission: 0.0,
+        open_bidask: price.clone(),
+        active_bidask: price.clone(),
+        margin_bidask: price,
+        profit_bidask: MicroEngineBidask::create_blank(),
+        profit_price_assets_subscriptions: HashSet::new(),
+        swaps_sum: 0.0,
+        realized_pl: 0.0,
+        leverage: None,
+        open_time: None,
+        last_swap_accrual: None,
+        is_pending: false,
+        metadata: None,
+    }
+}
+
+/// Allocations made draining `ticks` prices no position trades or converts through.
+async fn allocations_for_untraded_ticks(ticks: usize) -> usize {
+    let (mut engine, _, _) = MicroEngine::initialize(
+        vec![account()],
+        (0..64).map(|i| position(&format!("P{i:02}"))).collect(),
+        vec![settings()],
+        HashSet::from(["USD".to_string()]),
+        vec![MicroEngineInstrument {
+            id: "EURUSD".to_string(),
+            base: "EUR".to_string(),
+            quote: "USD".to_string(),
+        }],
+        vec![bidask("EURUSD", "EUR", "USD")],
+    )
+    .await;
+
+    let prices = (0..ticks)
+        .map(|i| bidask(&format!("X{i:02}JPY"), &format!("X{i:02}"), "JPY"))
+        .collect::<Vec<_>>();
+    engine.handle_new_price(prices).await;
+
+    let before = allocations();
+    let (accounts, positions) = engine.recalculate_accordint_to_updates().await;
+    let after = allocations();
+
+    assert!(accounts.is_none());
+    assert!(positions.is_none());
+
+    after - before
+}
+
+#[tokio::test]
+async fn test_untraded_prices_are_not_cloned() {
+    let one_tick = allocations_for_untraded_ticks(1).await;
+    let many_ticks = allocations_for_untraded_ticks(32).await;
+
+    // Prices are borrowed from the cache, so ticks without positions cost nothing per price
+    assert_eq!(many_ticks, one_tick);
+}